@@ -0,0 +1,411 @@
+//! Avro encoding of tag samples with a Confluent compatible schema registry.
+//!
+//! With `KAFKA_FORMAT=avro` samples are written in the registry wire format
+//!
+//! ```text
+//! u8 magic byte 0 | u32 schema id (big endian) | Avro binary encoded TagSample
+//! ```
+//!
+//! The [`TAG_SAMPLE_SCHEMA`] is registered under the subject `<topic>-value` at
+//! `SCHEMA_REGISTRY_URL` the first time a topic is written to, and the returned schema id is
+//! cached for the lifetime of the process. A failed registration is remembered for
+//! [`RETRY_AFTER`], so a registry that is down is not asked for every sample. When the
+//! registry cannot be reached, times out or answers with a server error, the samples are
+//! spooled and serialized once it is back; when it refuses the schema they become dead
+//! letters.
+//!
+//! Avro has no type for arbitrary JSON, so `value` holds scalars as `boolean`, `long`, `double`
+//! or `string` and arrays and structures as their JSON text, with `value_type` telling them
+//! apart. Connection events and dead letters stay JSON.
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::sample::TagSample;
+
+/// The Avro schema of the [`TagSample`] envelope, with fields in encoding order.
+pub const TAG_SAMPLE_SCHEMA: &str = r#"{
+  "type": "record",
+  "name": "TagSample",
+  "namespace": "dcs",
+  "fields": [
+    {"name": "schema_version", "type": "int"},
+    {"name": "endpoint", "type": "string"},
+    {"name": "node_id", "type": "string"},
+    {"name": "tag", "type": "string"},
+    {"name": "alias", "type": ["null", "string"], "default": null},
+    {"name": "asset_id", "type": ["null", "string"], "default": null},
+    {"name": "unit", "type": ["null", "string"], "default": null},
+    {"name": "value_type", "type": "string"},
+    {"name": "value", "type": ["null", "boolean", "long", "double", "string"]},
+    {"name": "array_dimensions", "type": ["null", {"type": "array", "items": "long"}], "default": null},
+    {"name": "status", "type": {
+      "type": "record",
+      "name": "SampleStatus",
+      "fields": [
+        {"name": "code", "type": "long"},
+        {"name": "name", "type": "string"}
+      ]
+    }},
+    {"name": "source_timestamp", "type": ["null", "string"], "default": null},
+    {"name": "server_timestamp", "type": ["null", "string"], "default": null},
+    {"name": "backfilled", "type": "boolean", "default": false}
+  ]
+}"#;
+
+const MAGIC_BYTE: u8 = 0;
+const REGISTRY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time before a subject whose registration failed is registered again.
+pub const RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// Why a sample could not be serialized.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SerializeError {
+    /// The schema registry is unavailable for now, the sample can be serialized later.
+    Unavailable(String),
+    /// The sample cannot be serialized, trying again would fail the same way.
+    Failed(String),
+}
+
+impl fmt::Display for SerializeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SerializeError::Unavailable(err) | SerializeError::Failed(err) => f.write_str(err),
+        }
+    }
+}
+
+/// Registers schemas over the registry REST API and caches their ids per subject.
+pub struct SchemaRegistry {
+    url: String,
+    agent: ureq::Agent,
+    schema_ids: HashMap<String, u32>,
+    /// The last failed registration by subject, with its time.
+    failures: HashMap<String, (Instant, SerializeError)>,
+    retry_after: Duration,
+}
+
+#[derive(Deserialize)]
+struct RegisteredSchema {
+    id: u32,
+}
+
+impl SchemaRegistry {
+    pub fn new(url: &str) -> SchemaRegistry {
+        SchemaRegistry::with_retry_after(url, RETRY_AFTER)
+    }
+
+    /// A registry asked again `retry_after` after a failed registration.
+    pub fn with_retry_after(url: &str, retry_after: Duration) -> SchemaRegistry {
+        SchemaRegistry {
+            url: url.trim_end_matches('/').to_string(),
+            agent: ureq::AgentBuilder::new().timeout(REGISTRY_TIMEOUT).build(),
+            schema_ids: HashMap::new(),
+            failures: HashMap::new(),
+            retry_after,
+        }
+    }
+
+    /// The id of `schema` under `subject`, registering it on first use. Registering a schema
+    /// the subject already has returns its existing id.
+    pub fn schema_id(&mut self, subject: &str, schema: &str) -> Result<u32, SerializeError> {
+        if let Some(&id) = self.schema_ids.get(subject) {
+            return Ok(id);
+        }
+        if let Some((failed_at, err)) = self.failures.get(subject)
+            && failed_at.elapsed() < self.retry_after {
+            return Err(err.clone());
+        }
+        match self.register(subject, schema) {
+            Ok(id) => {
+                self.failures.remove(subject);
+                self.schema_ids.insert(subject.to_string(), id);
+                Ok(id)
+            }
+            Err(err) => {
+                println!("Schema registry {}, asking again in {:?}: {}", self.url, self.retry_after, err);
+                self.failures.insert(subject.to_string(), (Instant::now(), err.clone()));
+                Err(err)
+            }
+        }
+    }
+
+    fn register(&self, subject: &str, schema: &str) -> Result<u32, SerializeError> {
+        let url = format!("{}/subjects/{}/versions", self.url, subject);
+        let response = self.agent.post(&url)
+            .set("Content-Type", "application/vnd.schemaregistry.v1+json")
+            .send_json(serde_json::json!({ "schema": schema }))
+            .map_err(|err| {
+                let message = format!("cannot register the schema of subject {} at {}: {}", subject, self.url, err);
+                match err {
+                    // Timeouts, rate limits and server errors pass, other client errors do not
+                    ureq::Error::Status(status, _) if status < 500 && status != 408 && status != 429 => SerializeError::Failed(message),
+                    _ => SerializeError::Unavailable(message),
+                }
+            })?;
+        let registered: RegisteredSchema = response.into_json()
+            .map_err(|err| SerializeError::Failed(format!("invalid schema registry response for subject {}: {}", subject, err)))?;
+        Ok(registered.id)
+    }
+}
+
+/// Serializes samples for a topic in the registry wire format.
+pub struct AvroSerializer {
+    registry: SchemaRegistry,
+}
+
+impl AvroSerializer {
+    pub fn new(registry: SchemaRegistry) -> AvroSerializer {
+        AvroSerializer { registry }
+    }
+
+    pub fn serialize(&mut self, topic: &str, sample: &TagSample) -> Result<Vec<u8>, SerializeError> {
+        let schema_id = self.registry.schema_id(&format!("{}-value", topic), TAG_SAMPLE_SCHEMA)?;
+        let mut buf = Vec::with_capacity(128);
+        buf.push(MAGIC_BYTE);
+        buf.extend_from_slice(&schema_id.to_be_bytes());
+        encode_sample(sample, &mut buf);
+        Ok(buf)
+    }
+}
+
+/// Avro binary encoding of a sample following [`TAG_SAMPLE_SCHEMA`].
+pub fn encode_sample(sample: &TagSample, buf: &mut Vec<u8>) {
+    write_long(buf, sample.schema_version as i64);
+    write_string(buf, &sample.endpoint);
+    write_string(buf, &sample.node_id);
+    write_string(buf, &sample.tag);
+    write_optional_string(buf, sample.alias.as_deref());
+    write_optional_string(buf, sample.asset_id.as_deref());
+    write_optional_string(buf, sample.unit.as_deref());
+    write_string(buf, &sample.value_type);
+    write_value(buf, &sample.value);
+    match sample.array_dimensions {
+        Some(ref dimensions) => {
+            write_long(buf, 1);
+            if !dimensions.is_empty() {
+                write_long(buf, dimensions.len() as i64);
+                dimensions.iter().for_each(|dimension| write_long(buf, *dimension as i64));
+            }
+            write_long(buf, 0);
+        }
+        None => write_long(buf, 0),
+    }
+    write_long(buf, sample.status.code as i64);
+    write_string(buf, &sample.status.name);
+    write_optional_string(buf, sample.source_timestamp.as_deref());
+    write_optional_string(buf, sample.server_timestamp.as_deref());
+    buf.push(sample.backfilled as u8);
+}
+
+/// Writes `value` as the union `["null", "boolean", "long", "double", "string"]`.
+fn write_value(buf: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => write_long(buf, 0),
+        Value::Bool(value) => {
+            write_long(buf, 1);
+            buf.push(*value as u8);
+        }
+        Value::Number(number) => {
+            if let Some(value) = number.as_i64() {
+                write_long(buf, 2);
+                write_long(buf, value);
+            } else if let Some(value) = number.as_u64() {
+                // Beyond the range of long
+                write_long(buf, 4);
+                write_string(buf, &value.to_string());
+            } else {
+                write_long(buf, 3);
+                buf.extend_from_slice(&number.as_f64().unwrap_or(f64::NAN).to_le_bytes());
+            }
+        }
+        Value::String(value) => {
+            write_long(buf, 4);
+            write_string(buf, value);
+        }
+        Value::Array(_) | Value::Object(_) => {
+            write_long(buf, 4);
+            write_string(buf, &value.to_string());
+        }
+    }
+}
+
+/// Zig-zag variable length encoding, used for both `int` and `long`.
+fn write_long(buf: &mut Vec<u8>, value: i64) {
+    let mut value = ((value << 1) ^ (value >> 63)) as u64;
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn write_string(buf: &mut Vec<u8>, value: &str) {
+    write_long(buf, value.len() as i64);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+fn write_optional_string(buf: &mut Vec<u8>, value: Option<&str>) {
+    match value {
+        Some(value) => {
+            write_long(buf, 1);
+            write_string(buf, value);
+        }
+        None => write_long(buf, 0),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use opcua::types::Variant;
+    use crate::sample::{SampleStatus, SCHEMA_VERSION};
+
+    /// The path and body of every registration.
+    pub(crate) type Requests = Arc<Mutex<Vec<(String, Value)>>>;
+
+    /// A registry answering every registration with schema id 42, or with an error while the
+    /// returned status is not 200, recording the requests.
+    pub(crate) fn mock_registry() -> (String, Requests, Arc<Mutex<u16>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        let status = Arc::new(Mutex::new(200));
+        let answered = status.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = header.split_once(':')
+                        && name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0u8; content_length];
+                reader.read_exact(&mut body).unwrap();
+                let path = request_line.split_whitespace().nth(1).unwrap().to_string();
+                recorded.lock().unwrap().push((path, serde_json::from_slice(&body).unwrap()));
+                let status = *answered.lock().unwrap();
+                let response = if status == 200 {
+                    r#"{"id":42}"#.to_string()
+                } else {
+                    format!(r#"{{"error_code":{},"message":"unavailable"}}"#, status)
+                };
+                write!(stream, "HTTP/1.1 {} Status\r\nContent-Type: application/vnd.schemaregistry.v1+json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status, response.len(), response).unwrap();
+            }
+        });
+        (url, requests, status)
+    }
+
+    fn sample() -> TagSample {
+        TagSample {
+            schema_version: SCHEMA_VERSION,
+            endpoint: "opc.tcp://plc1:4840".to_string(),
+            node_id: "ns=2;s=Temp".to_string(),
+            tag: "Temp".to_string(),
+            alias: Some("line1_temp".to_string()),
+            asset_id: None,
+            unit: None,
+            value_type: "Double".to_string(),
+            value: serde_json::json!(21.5),
+            array_dimensions: None,
+            status: SampleStatus { code: 0, name: "Good".to_string() },
+            source_timestamp: None,
+            server_timestamp: None,
+            backfilled: false,
+            variant: Variant::Double(21.5),
+        }
+    }
+
+    #[test]
+    fn registers_schema_once_per_subject() {
+        let (url, requests, _) = mock_registry();
+        let mut serializer = AvroSerializer::new(SchemaRegistry::new(&url));
+        let first = serializer.serialize("plant", &sample()).unwrap();
+        let second = serializer.serialize("plant", &sample()).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(&first[..5], &[0, 0, 0, 0, 42]);
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].0, "/subjects/plant-value/versions");
+        assert_eq!(requests[0].1["schema"], TAG_SAMPLE_SCHEMA);
+    }
+
+    #[test]
+    fn asks_a_failed_registry_again_after_a_while() {
+        let (url, requests, status) = mock_registry();
+        *status.lock().unwrap() = 503;
+        let mut registry = SchemaRegistry::new(&url);
+        let err = registry.schema_id("plant-value", TAG_SAMPLE_SCHEMA).unwrap_err();
+        assert!(matches!(err, SerializeError::Unavailable(_)), "{:?}", err);
+        *status.lock().unwrap() = 200;
+        assert_eq!(registry.schema_id("plant-value", TAG_SAMPLE_SCHEMA), Err(err));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        registry.retry_after = Duration::ZERO;
+        assert_eq!(registry.schema_id("plant-value", TAG_SAMPLE_SCHEMA), Ok(42));
+        assert_eq!(registry.schema_id("plant-value", TAG_SAMPLE_SCHEMA), Ok(42));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn tells_unavailable_registries_from_refusals() {
+        let (url, _, status) = mock_registry();
+        let mut registry = SchemaRegistry::with_retry_after(&url, Duration::ZERO);
+        for (code, retriable) in [(500, true), (503, true), (408, true), (429, true), (409, false), (422, false), (404, false)] {
+            *status.lock().unwrap() = code;
+            let err = registry.schema_id("plant-value", TAG_SAMPLE_SCHEMA).unwrap_err();
+            assert_eq!(matches!(err, SerializeError::Unavailable(_)), retriable, "{}: {}", code, err);
+        }
+        let mut unreachable = SchemaRegistry::new("http://127.0.0.1:1");
+        let err = unreachable.schema_id("plant-value", TAG_SAMPLE_SCHEMA).unwrap_err();
+        assert!(matches!(err, SerializeError::Unavailable(_)), "{}", err);
+    }
+
+    #[test]
+    fn encodes_sample_in_schema_order() {
+        let mut buf = Vec::new();
+        encode_sample(&sample(), &mut buf);
+        let mut expected = vec![2];
+        for value in ["opc.tcp://plc1:4840", "ns=2;s=Temp", "Temp"] {
+            expected.push(value.len() as u8 * 2);
+            expected.extend_from_slice(value.as_bytes());
+        }
+        expected.extend_from_slice(&[2, 20]);
+        expected.extend_from_slice(b"line1_temp");
+        expected.extend_from_slice(&[0, 0, 12]);
+        expected.extend_from_slice(b"Double");
+        expected.push(6);
+        expected.extend_from_slice(&21.5f64.to_le_bytes());
+        expected.extend_from_slice(&[0, 0, 8]);
+        expected.extend_from_slice(b"Good");
+        expected.extend_from_slice(&[0, 0, 0]);
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn zig_zag_longs() {
+        for (value, encoded) in [(0, vec![0]), (-1, vec![1]), (1, vec![2]), (-64, vec![0x7f]), (64, vec![0x80, 0x01])] {
+            let mut buf = Vec::new();
+            write_long(&mut buf, value);
+            assert_eq!(buf, encoded, "{}", value);
+        }
+    }
+}
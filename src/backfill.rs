@@ -0,0 +1,341 @@
+//! Backfill of the values a tag took while the bridge was stopped or disconnected.
+//!
+//! | Variable                     | Default                | Meaning                                         |
+//! |------------------------------|------------------------|-------------------------------------------------|
+//! | `BACKFILL`                   | `false`                | read the gap from the server history on connect |
+//! | `BACKFILL_STATE_FILE`        | `backfill-state.json`  | last published timestamp per tag                |
+//! | `BACKFILL_MAX_GAP_SECS`      | `86400`                | gaps are read back at most this far             |
+//! | `BACKFILL_MAX_VALUES`        | `1000`                 | values per node and HistoryRead request         |
+//! | `BACKFILL_MAX_TOTAL_VALUES`  | `100000`               | values read on one connect at most              |
+//!
+//! Every published sample moves the timestamp of its tag in the state file, which is written
+//! at most every [`SAVE_INTERVAL`] and when the session is lost. On every connect, before the
+//! subscription is created, the raw history of each tag with a recorded timestamp is read from
+//! just after that timestamp up to now and published with `backfilled` set. The history is
+//! published node by node, one HistoryRead response at a time, and the publishing waits for
+//! room in the Kafka, database and file queues rather than dropping values, as the published
+//! values move the timestamps past them. Once `BACKFILL_MAX_TOTAL_VALUES` are read the rest
+//! of the gap is skipped. A tag without a timestamp, such as one monitored for the first time,
+//! is not backfilled. Nodes the server keeps no history for are logged and skipped.
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use chrono::{TimeDelta, Utc};
+use dotenvy::var;
+use opcua::client::prelude::*;
+use opcua::sync::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::client_config::{parse_bool, parse_u64};
+use crate::sample::TagSample;
+use crate::tags::TagMapping;
+
+/// Time between two writes of the state file while samples are published.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct BackfillConfig {
+    pub state_file: PathBuf,
+    pub max_gap: Duration,
+    pub max_values: u32,
+    pub max_total_values: usize,
+}
+
+impl BackfillConfig {
+    /// The backfill settings, `None` unless `BACKFILL` is set.
+    pub fn from_env() -> Result<Option<BackfillConfig>, String> {
+        if !parse_bool("BACKFILL", false)? {
+            return Ok(None);
+        }
+        let max_values = parse_u64("BACKFILL_MAX_VALUES", 1000)?;
+        if max_values == 0 || max_values > u32::MAX as u64 {
+            return Err(format!("BACKFILL_MAX_VALUES must be between 1 and {}", u32::MAX));
+        }
+        Ok(Some(BackfillConfig {
+            state_file: PathBuf::from(var("BACKFILL_STATE_FILE").unwrap_or_else(|_| "backfill-state.json".to_string())),
+            max_gap: Duration::from_secs(parse_u64("BACKFILL_MAX_GAP_SECS", 86_400)?),
+            max_values: max_values as u32,
+            max_total_values: parse_u64("BACKFILL_MAX_TOTAL_VALUES", 100_000)? as usize,
+        }))
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StateFile {
+    /// The last published timestamp by tag name.
+    tags: HashMap<String, chrono::DateTime<Utc>>,
+}
+
+/// The last published timestamp of every tag, kept in `BACKFILL_STATE_FILE`.
+pub struct BackfillState {
+    config: BackfillConfig,
+    state: StateFile,
+    dirty: bool,
+    saved_at: Instant,
+}
+
+impl BackfillState {
+    pub fn load(config: BackfillConfig) -> Result<BackfillState, String> {
+        let state = match fs::read_to_string(&config.state_file) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|err| format!("cannot parse {}: {}", config.state_file.display(), err))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => StateFile::default(),
+            Err(err) => return Err(format!("cannot read {}: {}", config.state_file.display(), err)),
+        };
+        println!("Backfill state {}: {} tags", config.state_file.display(), state.tags.len());
+        Ok(BackfillState { config, state, dirty: false, saved_at: Instant::now() })
+    }
+
+    /// Moves the timestamp of the sample's tag forward, to its source timestamp or else its
+    /// server timestamp.
+    pub fn record(&mut self, sample: &TagSample) {
+        let Some(time) = sample.source_timestamp.as_deref()
+            .or(sample.server_timestamp.as_deref())
+            .and_then(|time| chrono::DateTime::parse_from_rfc3339(time).ok())
+            .map(|time| time.with_timezone(&Utc)) else {
+            return;
+        };
+        match self.state.tags.get_mut(&sample.tag) {
+            Some(last) if *last >= time => {}
+            Some(last) => {
+                *last = time;
+                self.dirty = true;
+            }
+            None => {
+                self.state.tags.insert(sample.tag.clone(), time);
+                self.dirty = true;
+            }
+        }
+    }
+
+    /// Writes the state file when something changed and [`SAVE_INTERVAL`] has passed.
+    pub fn save_if_due(&mut self) {
+        if self.dirty && self.saved_at.elapsed() >= SAVE_INTERVAL {
+            self.save();
+        }
+    }
+
+    /// Writes the state file when something changed. The file is replaced by a rename, so a
+    /// crash leaves either the old or the new state.
+    pub fn save(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        let tmp = self.config.state_file.with_extension("tmp");
+        let result = serde_json::to_string(&self.state)
+            .map_err(|err| err.to_string())
+            .and_then(|contents| fs::write(&tmp, contents).map_err(|err| err.to_string()))
+            .and_then(|_| fs::rename(&tmp, &self.config.state_file).map_err(|err| err.to_string()));
+        match result {
+            Ok(()) => self.dirty = false,
+            Err(err) => println!("Cannot write backfill state {}: {}", self.config.state_file.display(), err),
+        }
+        self.saved_at = Instant::now();
+    }
+
+    /// The tags with a recorded timestamp and the time to read their history from.
+    fn gaps(&self, tags: &TagMapping, now: chrono::DateTime<Utc>) -> Vec<(NodeId, String, chrono::DateTime<Utc>)> {
+        tags.iter()
+            .filter_map(|(node_id, tag)| {
+                let last = self.state.tags.get(&tag.name)?;
+                let start = gap_start(*last, now, self.config.max_gap)?;
+                Some((node_id.clone(), tag.name.clone(), start))
+            })
+            .collect()
+    }
+}
+
+/// Where the history after `last` is read from, at most `max_gap` before `now`. `None` when
+/// there is nothing to read.
+fn gap_start(last: chrono::DateTime<Utc>, now: chrono::DateTime<Utc>, max_gap: Duration) -> Option<chrono::DateTime<Utc>> {
+    let oldest = TimeDelta::from_std(max_gap).ok()
+        .and_then(|max_gap| now.checked_sub_signed(max_gap))
+        .unwrap_or(chrono::DateTime::<Utc>::MIN_UTC);
+    // The last published value itself is not read again. DateTime counts in 100ns ticks, so
+    // that is the smallest step past it
+    let start = (last + TimeDelta::nanoseconds(100)).max(oldest);
+    (start < now).then_some(start)
+}
+
+/// Reads the history of every tag since its last published timestamp and hands it to
+/// `publish` node by node, in time order, one HistoryRead response at a time, with
+/// `backfilled` set. The state is only locked to look the timestamps up, so `publish` can
+/// record the samples. Returns the number of values read.
+pub fn backfill(state: &Mutex<BackfillState>, session: &Session, endpoint: &str, tags: &TagMapping, mut publish: impl FnMut(Vec<(NodeId, TagSample)>)) -> usize {
+    let now = Utc::now();
+    let (gaps, config) = {
+        let state = state.lock();
+        (state.gaps(tags, now), state.config.clone())
+    };
+    let mut total = 0;
+    for (node_id, name, start) in gaps {
+        if total >= config.max_total_values {
+            println!("Backfill stopped after BACKFILL_MAX_TOTAL_VALUES {} values, tag \"{}\" and later ones are not backfilled", total, name);
+            break;
+        }
+        let mut read = 0;
+        let result = read_raw(session, &node_id, start, now, config.max_values, |values| {
+            let remaining = config.max_total_values - total;
+            let samples: Vec<(NodeId, TagSample)> = values.iter().take(remaining).map(|value| {
+                let mut sample = TagSample::from_tag_value(endpoint, &node_id, tags, value);
+                sample.backfilled = true;
+                (node_id.clone(), sample)
+            }).collect();
+            read += samples.len();
+            total += samples.len();
+            publish(samples);
+            total < config.max_total_values
+        });
+        if read > 0 {
+            println!("Tag \"{}\", backfilled {} values since {}", name, read, start.to_rfc3339());
+        }
+        if let Err(err) = result {
+            println!("Tag \"{}\", cannot be backfilled: {}", name, err);
+        }
+    }
+    total
+}
+
+/// Reads the raw values of a node between `start` and `end`, following continuation points,
+/// and hands the values of every response to `page`. Stops, releasing the continuation point,
+/// when `page` returns false.
+fn read_raw(session: &Session, node_id: &NodeId, start: chrono::DateTime<Utc>, end: chrono::DateTime<Utc>, max_values: u32, mut page: impl FnMut(Vec<DataValue>) -> bool) -> Result<(), StatusCode> {
+    let details = ReadRawModifiedDetails {
+        is_read_modified: false,
+        start_time: DateTime::from(start),
+        end_time: DateTime::from(end),
+        num_values_per_node: max_values,
+        return_bounds: false,
+    };
+    let mut continuation_point = ByteString::null();
+    loop {
+        let node = HistoryReadValueId {
+            node_id: node_id.clone(),
+            index_range: UAString::null(),
+            data_encoding: QualifiedName::null(),
+            continuation_point,
+        };
+        let results = session.history_read(HistoryReadAction::ReadRawModifiedDetails(details.clone()), TimestampsToReturn::Both, false, &[node])?;
+        let result = results.into_iter().next().ok_or(StatusCode::BadUnexpectedError)?;
+        if result.status_code.is_bad() {
+            return Err(result.status_code);
+        }
+        let values = if result.history_data.is_null() {
+            Vec::new()
+        } else {
+            result.history_data.decode_inner::<HistoryData>(&DecodingOptions::default())?.data_values.unwrap_or_default()
+        };
+        let more = page(values);
+        if result.continuation_point.value.as_ref().is_none_or(|bytes| bytes.is_empty()) {
+            return Ok(());
+        }
+        if !more {
+            let node = HistoryReadValueId {
+                node_id: node_id.clone(),
+                index_range: UAString::null(),
+                data_encoding: QualifiedName::null(),
+                continuation_point: result.continuation_point,
+            };
+            session.history_read(HistoryReadAction::ReadRawModifiedDetails(details), TimestampsToReturn::Both, true, &[node])?;
+            return Ok(());
+        }
+        continuation_point = result.continuation_point;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn config(dir: &tempfile::TempDir) -> BackfillConfig {
+        BackfillConfig {
+            state_file: dir.path().join("backfill-state.json"),
+            max_gap: Duration::from_secs(3600),
+            max_values: 100,
+            max_total_values: 1000,
+        }
+    }
+
+    fn sample(tag: &str, source_timestamp: Option<&str>, server_timestamp: Option<&str>) -> TagSample {
+        serde_json::from_value(json!({
+            "schema_version": 1,
+            "endpoint": "opc.tcp://plc1:4840",
+            "node_id": format!("ns=2;s={}", tag),
+            "tag": tag,
+            "value_type": "Double",
+            "value": 3.2,
+            "status": { "code": 0, "name": "Good" },
+            "source_timestamp": source_timestamp,
+            "server_timestamp": server_timestamp,
+        })).unwrap()
+    }
+
+    fn time(value: &str) -> chrono::DateTime<Utc> {
+        chrono::DateTime::parse_from_rfc3339(value).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn starts_empty_without_a_state_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = BackfillState::load(config(&dir)).unwrap();
+        assert!(state.state.tags.is_empty());
+        assert!(!state.dirty);
+
+        fs::write(dir.path().join("backfill-state.json"), "{\"tags\": [").unwrap();
+        let err = BackfillState::load(config(&dir)).err().unwrap();
+        assert!(err.starts_with(&format!("cannot parse {}", dir.path().join("backfill-state.json").display())), "{}", err);
+    }
+
+    #[test]
+    fn only_moves_timestamps_forward() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = BackfillState::load(config(&dir)).unwrap();
+        state.record(&sample("Temp", Some("2024-05-01T10:00:02Z"), Some("2024-05-01T10:00:05Z")));
+        state.record(&sample("Temp", Some("2024-05-01T10:00:01+00:00"), None));
+        assert_eq!(state.state.tags["Temp"], time("2024-05-01T10:00:02Z"));
+        state.record(&sample("Temp", Some("2024-05-01T12:00:03+02:00"), None));
+        assert_eq!(state.state.tags["Temp"], time("2024-05-01T10:00:03Z"));
+
+        // The server timestamp stands in for a missing source timestamp
+        state.record(&sample("Count", None, Some("2024-05-01T10:00:04Z")));
+        assert_eq!(state.state.tags["Count"], time("2024-05-01T10:00:04Z"));
+        state.record(&sample("Level", None, None));
+        state.record(&sample("Level", Some("yesterday"), None));
+        assert!(!state.state.tags.contains_key("Level"));
+    }
+
+    #[test]
+    fn saves_by_renaming_and_loads_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir);
+        let mut state = BackfillState::load(config.clone()).unwrap();
+        state.save();
+        assert!(!config.state_file.exists());
+
+        state.record(&sample("Temp", Some("2024-05-01T10:00:02.123456Z"), None));
+        state.save_if_due();
+        assert!(!config.state_file.exists());
+        state.save();
+        assert!(!state.dirty);
+        assert!(!config.state_file.with_extension("tmp").exists());
+        let files: Vec<_> = fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(files, ["backfill-state.json"]);
+
+        let loaded = BackfillState::load(config).unwrap();
+        assert_eq!(loaded.state.tags, HashMap::from([("Temp".to_string(), time("2024-05-01T10:00:02.123456Z"))]));
+    }
+
+    #[test]
+    fn reads_the_gap_up_to_max_gap_back() {
+        let now = time("2024-05-01T12:00:00Z");
+        let hour = Duration::from_secs(3600);
+        assert_eq!(gap_start(time("2024-05-01T11:30:00Z"), now, hour), Some(time("2024-05-01T11:30:00.0000001Z")));
+        assert_eq!(gap_start(time("2024-04-30T12:00:00Z"), now, hour), Some(time("2024-05-01T11:00:00Z")));
+        assert_eq!(gap_start(time("2024-05-01T12:00:00Z"), now, hour), None);
+        assert_eq!(gap_start(time("2024-05-01T12:05:00Z"), now, hour), None);
+        assert_eq!(gap_start(time("2024-05-01T11:59:59.9999999Z"), now, hour), None);
+        assert_eq!(gap_start(time("2024-05-01T11:30:00Z"), now, Duration::MAX), Some(time("2024-05-01T11:30:00.0000001Z")));
+    }
+}
@@ -0,0 +1,408 @@
+//! Walking the server address space to discover tags.
+//!
+//! `dcs browse` prints the nodes below a root, or writes them as a tags file for `TAGS_FILE`:
+//!
+//! ```text
+//! dcs browse --root "nsu=urn:plant:line1;s=Line1" --node-class Variable --data-type Double,Float \
+//!     --name "Temp$" --output tags.toml
+//! ```
+//!
+//! | Option          | Variable                   | Default      | Meaning                                  |
+//! |-----------------|----------------------------|--------------|------------------------------------------|
+//! | `--root`        | `DISCOVER_ROOT`            | `i=85`       | node id to start from, the Objects folder by default |
+//! | `--node-class`  | `DISCOVER_NODE_CLASSES`    | `Variable`   | comma separated node classes to list     |
+//! | `--data-type`   | `DISCOVER_DATA_TYPES`      | any          | comma separated data type names (`Double`) or node ids (`ns=2;i=3002`) |
+//! | `--name`        | `DISCOVER_NAME_PATTERN`    | any          | regular expression the tag name must match |
+//! | `--max-depth`   | `DISCOVER_MAX_DEPTH`       | `10`         | levels of references below the root      |
+//! | `--output`      |                            |              | write a tags file instead of printing    |
+//!
+//! Hierarchical references are followed forward, and each node is visited once. The tag name
+//! of a node is the path of browse names from the root, joined with `.`, e.g.
+//! `Line1.Dryer.Temp`. Data types match exactly, subtypes are not included. Only variables
+//! have a data type, so the data types filter variables and other node classes are listed
+//! regardless.
+//!
+//! With `DISCOVER_AUTO_SUBSCRIBE=true` the service browses with the `DISCOVER_*` settings
+//! after every connect and monitors all matching variables in addition to the configured
+//! tags, which are then optional. A configured tag takes precedence over a discovered node
+//! with the same node id.
+use std::collections::HashSet;
+use std::fmt::Write;
+use dotenvy::var;
+use opcua::client::prelude::*;
+use regex::Regex;
+
+use crate::node_id::{Namespace, NodeIdSpec};
+
+/// Nodes browsed or read in one request at most.
+const REQUEST_CHUNK: usize = 100;
+
+/// The built-in data types by name.
+const DATA_TYPES: [(&str, u32); 29] = [
+    ("Boolean", 1), ("SByte", 2), ("Byte", 3), ("Int16", 4), ("UInt16", 5), ("Int32", 6),
+    ("UInt32", 7), ("Int64", 8), ("UInt64", 9), ("Float", 10), ("Double", 11), ("String", 12),
+    ("DateTime", 13), ("Guid", 14), ("ByteString", 15), ("XmlElement", 16), ("NodeId", 17),
+    ("ExpandedNodeId", 18), ("StatusCode", 19), ("QualifiedName", 20), ("LocalizedText", 21),
+    ("ExtensionObject", 22), ("DataValue", 23), ("Variant", 24), ("DiagnosticInfo", 25),
+    ("Number", 26), ("Integer", 27), ("UInteger", 28), ("Enumeration", 29),
+];
+
+const NODE_CLASSES: [(&str, NodeClass); 8] = [
+    ("Object", NodeClass::Object), ("Variable", NodeClass::Variable), ("Method", NodeClass::Method),
+    ("ObjectType", NodeClass::ObjectType), ("VariableType", NodeClass::VariableType),
+    ("ReferenceType", NodeClass::ReferenceType), ("DataType", NodeClass::DataType), ("View", NodeClass::View),
+];
+
+#[derive(Debug, Clone)]
+pub struct BrowseConfig {
+    pub root: NodeIdSpec,
+    pub node_classes: Vec<NodeClass>,
+    /// Empty for any data type.
+    pub data_types: Vec<NodeIdSpec>,
+    pub name_pattern: Option<Regex>,
+    pub max_depth: usize,
+}
+
+/// A node found below the root.
+#[derive(Debug, Clone)]
+pub struct DiscoveredNode {
+    pub node_id: NodeId,
+    /// The browse names from the root, joined with `.`.
+    pub name: String,
+    pub node_class: NodeClass,
+    /// Only read for variables.
+    pub data_type: Option<NodeId>,
+}
+
+impl DiscoveredNode {
+    /// Whether the node passes the data type filter, which only applies to variables.
+    fn has_data_type(&self, data_types: &[NodeId]) -> bool {
+        data_types.is_empty()
+            || self.node_class != NodeClass::Variable
+            || self.data_type.as_ref().is_some_and(|data_type| data_types.contains(data_type))
+    }
+}
+
+impl BrowseConfig {
+    pub fn from_env() -> Result<BrowseConfig, String> {
+        let mut config = BrowseConfig {
+            root: NodeIdSpec { namespace: Namespace::Index(0), identifier: Identifier::Numeric(ObjectId::ObjectsFolder as u32) },
+            node_classes: vec![NodeClass::Variable],
+            data_types: Vec::new(),
+            name_pattern: None,
+            max_depth: 10,
+        };
+        for name in ["DISCOVER_ROOT", "DISCOVER_NODE_CLASSES", "DISCOVER_DATA_TYPES", "DISCOVER_NAME_PATTERN", "DISCOVER_MAX_DEPTH"] {
+            if let Ok(value) = var(name) {
+                config.set(name, &value)?;
+            }
+        }
+        Ok(config)
+    }
+
+    /// Overrides the settings with the command line options of `dcs browse`.
+    pub fn apply_args(&mut self, args: &mut pico_args::Arguments) -> Result<(), String> {
+        for option in ["--root", "--node-class", "--data-type", "--name", "--max-depth"] {
+            let value: Option<String> = args.opt_value_from_str(option).map_err(|err| err.to_string())?;
+            if let Some(value) = value {
+                self.set(option, &value)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "DISCOVER_ROOT" | "--root" => self.root = NodeIdSpec::parse(value).map_err(|err| format!("{} {}", name, err))?,
+            "DISCOVER_NODE_CLASSES" | "--node-class" => self.node_classes = split(value).map(|class| NODE_CLASSES.iter()
+                .find(|(class_name, _)| class_name.eq_ignore_ascii_case(class))
+                .map(|(_, node_class)| *node_class)
+                .ok_or_else(|| format!("{} \"{}\" is not a node class, expected one of {}", name, class,
+                    NODE_CLASSES.map(|(class_name, _)| class_name).join(", "))))
+                .collect::<Result<Vec<NodeClass>, String>>()?,
+            "DISCOVER_DATA_TYPES" | "--data-type" => self.data_types = split(value).map(|data_type| {
+                match DATA_TYPES.iter().find(|(type_name, _)| type_name.eq_ignore_ascii_case(data_type)) {
+                    Some((_, id)) => Ok(NodeIdSpec { namespace: Namespace::Index(0), identifier: Identifier::Numeric(*id) }),
+                    None => NodeIdSpec::parse(data_type)
+                        .map_err(|_| format!("{} \"{}\" is neither a built-in data type name nor a node id", name, data_type)),
+                }
+            }).collect::<Result<Vec<NodeIdSpec>, String>>()?,
+            "DISCOVER_NAME_PATTERN" | "--name" => self.name_pattern = Some(Regex::new(value)
+                .map_err(|err| format!("{} \"{}\" is not a valid regular expression: {}", name, value, err))?),
+            _ => self.max_depth = value.trim().parse::<usize>()
+                .map_err(|_| format!("{} \"{}\" is not a valid number", name, value))?,
+        }
+        Ok(())
+    }
+}
+
+fn split(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|part| !part.is_empty())
+}
+
+/// Walks the hierarchical references below the root and returns the matching nodes.
+pub fn browse(session: &Session, config: &BrowseConfig, namespaces: &[String]) -> Result<Vec<DiscoveredNode>, String> {
+    let root = config.root.resolve(namespaces).map_err(|err| format!("browse root: {}", err))?;
+    let data_types = config.data_types.iter()
+        .map(|data_type| data_type.resolve(namespaces))
+        .collect::<Result<Vec<NodeId>, String>>()?;
+    let mut visited = HashSet::from([root.clone()]);
+    let mut level = vec![(root, String::new())];
+    let mut found = Vec::new();
+    for _ in 0..config.max_depth {
+        let mut next_level = Vec::new();
+        for chunk in level.chunks(REQUEST_CHUNK) {
+            let references = browse_references(session, chunk.iter().map(|(node_id, _)| node_id))?;
+            for ((_, parent), references) in chunk.iter().zip(references) {
+                for reference in references {
+                    let node_id = reference.node_id.node_id;
+                    if reference.node_id.server_index != 0 || !visited.insert(node_id.clone()) {
+                        continue;
+                    }
+                    let browse_name = reference.browse_name.name.as_ref().to_string();
+                    let name = if parent.is_empty() { browse_name } else { format!("{}.{}", parent, browse_name) };
+                    if config.node_classes.contains(&reference.node_class)
+                        && config.name_pattern.as_ref().is_none_or(|pattern| pattern.is_match(&name)) {
+                        found.push(DiscoveredNode { node_id: node_id.clone(), name: name.clone(), node_class: reference.node_class, data_type: None });
+                    }
+                    next_level.push((node_id, name));
+                }
+            }
+        }
+        if next_level.is_empty() {
+            break;
+        }
+        level = next_level;
+    }
+    read_data_types(session, &mut found)?;
+    found.retain(|node| node.has_data_type(&data_types));
+    Ok(found)
+}
+
+/// The forward hierarchical references of every node, following continuation points.
+fn browse_references<'a>(session: &Session, node_ids: impl Iterator<Item = &'a NodeId>) -> Result<Vec<Vec<ReferenceDescription>>, String> {
+    let descriptions: Vec<BrowseDescription> = node_ids.map(|node_id| BrowseDescription {
+        node_id: node_id.clone(),
+        browse_direction: BrowseDirection::Forward,
+        reference_type_id: ReferenceTypeId::HierarchicalReferences.into(),
+        include_subtypes: true,
+        node_class_mask: 0,
+        result_mask: BrowseResultMask::All as u32,
+    }).collect();
+    let results = session.browse(&descriptions)
+        .map_err(|status| format!("Browse failed: {}", status))?
+        .unwrap_or_default();
+    let mut references = Vec::with_capacity(results.len());
+    for (description, result) in descriptions.iter().zip(results) {
+        if result.status_code.is_bad() {
+            println!("Cannot browse {}: {}", description.node_id, result.status_code);
+            references.push(Vec::new());
+            continue;
+        }
+        let mut node_references = result.references.unwrap_or_default();
+        let mut continuation_point = result.continuation_point;
+        while !continuation_point.is_null_or_empty() {
+            let next = session.browse_next(false, &[continuation_point])
+                .map_err(|status| format!("BrowseNext failed: {}", status))?
+                .and_then(|results| results.into_iter().next());
+            match next {
+                Some(next) if next.status_code.is_good() => {
+                    node_references.extend(next.references.unwrap_or_default());
+                    continuation_point = next.continuation_point;
+                }
+                _ => break,
+            }
+        }
+        references.push(node_references);
+    }
+    Ok(references)
+}
+
+/// Reads the DataType attribute of the discovered variables.
+fn read_data_types(session: &Session, nodes: &mut [DiscoveredNode]) -> Result<(), String> {
+    let mut variables: Vec<&mut DiscoveredNode> = nodes.iter_mut()
+        .filter(|node| node.node_class == NodeClass::Variable)
+        .collect();
+    for chunk in variables.chunks_mut(REQUEST_CHUNK) {
+        let requests: Vec<ReadValueId> = chunk.iter().map(|node| ReadValueId {
+            node_id: node.node_id.clone(),
+            attribute_id: AttributeId::DataType as u32,
+            index_range: UAString::null(),
+            data_encoding: QualifiedName::null(),
+        }).collect();
+        let results = session.read(&requests, TimestampsToReturn::Neither, 0.0)
+            .map_err(|status| format!("cannot read data types: {}", status))?;
+        for (node, result) in chunk.iter_mut().zip(results) {
+            if let Some(Variant::NodeId(data_type)) = result.value {
+                node.data_type = Some(*data_type);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The name of a built-in data type, otherwise the node id.
+fn data_type_name(data_type: &NodeId) -> String {
+    match data_type.identifier {
+        Identifier::Numeric(id) if data_type.namespace == 0 => DATA_TYPES.iter()
+            .find(|(_, type_id)| *type_id == id)
+            .map(|(name, _)| name.to_string())
+            .unwrap_or_else(|| data_type.to_string()),
+        _ => data_type.to_string(),
+    }
+}
+
+/// Prints one line per node: node id, node class, data type and tag name.
+pub fn print_nodes(nodes: &[DiscoveredNode]) {
+    for node in nodes {
+        println!("{}\t{:?}\t{}\t{}", node.node_id, node.node_class,
+            node.data_type.as_ref().map(data_type_name).unwrap_or_else(|| "-".to_string()), node.name);
+    }
+}
+
+/// A tags file with the discovered variables, with namespace URIs so it stays valid when
+/// the server reorders its namespaces. Tag names must be unique, so a variable with the name
+/// of an earlier one is given a `_2`, `_3`, ... suffix. Fails when no variable was found, as
+/// a tags file without tags is not valid.
+pub fn tags_file(nodes: &[DiscoveredNode], namespaces: &[String]) -> Result<String, String> {
+    let mut file = String::from("# Generated by dcs browse\n");
+    let mut names = HashSet::new();
+    for node in nodes.iter().filter(|node| node.node_class == NodeClass::Variable) {
+        let namespace = match namespaces.get(node.node_id.namespace as usize) {
+            Some(uri) if node.node_id.namespace > 0 => Namespace::Uri(uri.clone()),
+            _ => Namespace::Index(node.node_id.namespace),
+        };
+        let node_id = NodeIdSpec { namespace, identifier: node.node_id.identifier.clone() };
+        let name = (1..).map(|suffix| if suffix == 1 { node.name.clone() } else { format!("{}_{}", node.name, suffix) })
+            .find(|name| !names.contains(name))
+            .unwrap();
+        let _ = write!(file, "\n[[tag]]\nname = {}\nnode_id = {}\n",
+            toml::Value::String(name.clone()), toml::Value::String(node_id.to_string()));
+        if name != node.name {
+            let _ = writeln!(file, "# renamed, {} is the name of an earlier variable", node.name);
+        }
+        if let Some(ref data_type) = node.data_type {
+            let _ = writeln!(file, "# data type {}", data_type_name(data_type));
+        }
+        names.insert(name);
+    }
+    if names.is_empty() {
+        return Err("no variable was found, the tags file would not define any [[tag]]".to_string());
+    }
+    Ok(file)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tags::TagList;
+
+    fn node(node_id: NodeId, name: &str, node_class: NodeClass, data_type: Option<DataTypeId>) -> DiscoveredNode {
+        DiscoveredNode { node_id, name: name.to_string(), node_class, data_type: data_type.map(NodeId::from) }
+    }
+
+    fn load(file: &str) -> TagList {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tags.toml");
+        std::fs::write(&path, file).unwrap();
+        TagList::load(path.to_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn filters_only_variables_by_data_type() {
+        let data_types = [NodeId::from(DataTypeId::Double)];
+        assert!(node(NodeId::new(1, "Line1.Dryer"), "Dryer", NodeClass::Object, None).has_data_type(&data_types));
+        assert!(node(NodeId::new(1, "Line1.Dryer.Temp"), "Dryer.Temp", NodeClass::Variable, Some(DataTypeId::Double)).has_data_type(&data_types));
+        assert!(!node(NodeId::new(1, "Line1.Dryer.On"), "Dryer.On", NodeClass::Variable, Some(DataTypeId::Boolean)).has_data_type(&data_types));
+        assert!(!node(NodeId::new(1, "Line1.Dryer.Count"), "Dryer.Count", NodeClass::Variable, None).has_data_type(&data_types));
+        assert!(node(NodeId::new(1, "Line1.Dryer.On"), "Dryer.On", NodeClass::Variable, Some(DataTypeId::Boolean)).has_data_type(&[]));
+    }
+
+    #[test]
+    fn writes_a_loadable_tags_file() {
+        let namespaces = ["http://opcfoundation.org/UA/".to_string(), "urn:plant:line1".to_string()];
+        let nodes = [
+            node(NodeId::new(1, "Line1.Dryer"), "Dryer", NodeClass::Object, None),
+            node(NodeId::new(1, "Line1.Dryer.Temp"), "Dryer.Temp", NodeClass::Variable, Some(DataTypeId::Double)),
+            node(NodeId::new(1, 1001u32), "Dryer.Count \"A\"", NodeClass::Variable, None),
+            node(NodeId::new(0, 2258u32), "Server.CurrentTime", NodeClass::Variable, Some(DataTypeId::UtcTime)),
+            node(NodeId::new(3, "Other"), "Other", NodeClass::Variable, None),
+        ];
+        let file = tags_file(&nodes, &namespaces).unwrap();
+        assert!(file.contains("# data type Double\n"), "{}", file);
+        assert!(file.contains("# data type i=294\n"), "{}", file);
+
+        let tags = load(&file).tags;
+        let tags: Vec<(&str, String)> = tags.iter().map(|tag| (tag.name.as_str(), tag.node.to_string())).collect();
+        assert_eq!(tags, [
+            ("Dryer.Temp", "nsu=urn:plant:line1;s=Line1.Dryer.Temp".to_string()),
+            ("Dryer.Count \"A\"", "nsu=urn:plant:line1;i=1001".to_string()),
+            ("Server.CurrentTime", "i=2258".to_string()),
+            // Not in the namespace array, kept as an index
+            ("Other", "ns=3;s=Other".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn renames_variables_with_a_taken_name() {
+        let nodes = [
+            node(NodeId::new(2, "a"), "Line1.Temp", NodeClass::Variable, None),
+            node(NodeId::new(2, "b"), "Line1.Temp_2", NodeClass::Variable, None),
+            node(NodeId::new(2, "c"), "Line1.Temp", NodeClass::Variable, None),
+            node(NodeId::new(2, "d"), "Line1.Temp", NodeClass::Variable, None),
+        ];
+        let file = tags_file(&nodes, &[]).unwrap();
+        assert!(file.contains("# renamed, Line1.Temp is the name of an earlier variable"), "{}", file);
+        let names: Vec<String> = load(&file).tags.into_iter().map(|tag| tag.name).collect();
+        assert_eq!(names, ["Line1.Temp", "Line1.Temp_2", "Line1.Temp_3", "Line1.Temp_4"]);
+    }
+
+    #[test]
+    fn refuses_a_tags_file_without_variables() {
+        assert!(tags_file(&[], &[]).is_err());
+        let objects = [node(NodeId::new(2, "Line1"), "Line1", NodeClass::Object, None)];
+        let err = tags_file(&objects, &[]).unwrap_err();
+        assert!(err.contains("no variable was found"), "{}", err);
+    }
+
+    fn config() -> BrowseConfig {
+        BrowseConfig {
+            root: NodeIdSpec { namespace: Namespace::Index(0), identifier: Identifier::Numeric(85) },
+            node_classes: vec![NodeClass::Variable],
+            data_types: Vec::new(),
+            name_pattern: None,
+            max_depth: 10,
+        }
+    }
+
+    #[test]
+    fn sets_the_options() {
+        let mut config = config();
+        config.set("--root", "nsu=urn:plant:line1;s=Line1").unwrap();
+        assert_eq!(config.root.to_string(), "nsu=urn:plant:line1;s=Line1");
+        config.set("DISCOVER_NODE_CLASSES", " object, VARIABLE ,,").unwrap();
+        assert_eq!(config.node_classes, [NodeClass::Object, NodeClass::Variable]);
+        config.set("--data-type", "double,ns=2;i=3002").unwrap();
+        let data_types: Vec<String> = config.data_types.iter().map(NodeIdSpec::to_string).collect();
+        assert_eq!(data_types, ["i=11", "ns=2;i=3002"]);
+        config.set("DISCOVER_NAME_PATTERN", "Temp$").unwrap();
+        assert!(config.name_pattern.as_ref().unwrap().is_match("Line1.Temp"));
+        config.set("--max-depth", " 3 ").unwrap();
+        assert_eq!(config.max_depth, 3);
+    }
+
+    #[test]
+    fn names_the_option_of_an_invalid_value() {
+        let mut config = config();
+        for (name, value, expected) in [
+            ("--root", "ns=x;s=Line1", "--root "),
+            ("DISCOVER_NODE_CLASSES", "Variable,Tag", "DISCOVER_NODE_CLASSES \"Tag\" is not a node class, expected one of Object, Variable, Method"),
+            ("--data-type", "Doubel", "--data-type \"Doubel\" is neither a built-in data type name nor a node id"),
+            ("DISCOVER_NAME_PATTERN", "Temp(", "DISCOVER_NAME_PATTERN \"Temp(\" is not a valid regular expression"),
+            ("--max-depth", "-1", "--max-depth \"-1\" is not a valid number"),
+        ] {
+            let err = config.set(name, value).unwrap_err();
+            assert!(err.starts_with(expected), "{}: {}", name, err);
+        }
+    }
+}
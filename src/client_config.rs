@@ -0,0 +1,403 @@
+//! OPC UA client settings read from the environment.
+//!
+//! | Variable                       | Default                | Meaning                                              |
+//! |--------------------------------|------------------------|------------------------------------------------------|
+//! | `OPCUA_SERVER`                 |                        | endpoint url, e.g. `opc.tcp://plc1:4840`             |
+//! | `OPCUA_SECURITY_POLICY`        | any                    | only use `None`, `Basic128Rsa15`, `Basic256`, `Basic256Sha256`, `Aes128Sha256RsaOaep` or `Aes256Sha256RsaPss` |
+//! | `OPCUA_SECURITY_MODE`          | any                    | only use `None`, `Sign` or `SignAndEncrypt`          |
+//! | `OPCUA_MIN_SECURITY_MODE`      | `None`                 | the weakest acceptable mode                          |
+//! | `OPCUA_MIN_SECURITY_LEVEL`     | `0`                    | the lowest acceptable server advertised security level |
+//! | `OPCUA_PKI_DIR`                | `pki`                  | certificate store with `own/`, `private/`, `trusted/` and `rejected/` |
+//! | `OPCUA_CLIENT_CERT`            | `own/cert.der`         | client certificate, relative to the PKI directory    |
+//! | `OPCUA_CLIENT_KEY`             | `private/private.pem`  | client private key, relative to the PKI directory    |
+//! | `OPCUA_APPLICATION_URI`        | `urn:DCSOPCUAClient`   | must match the URI in the client certificate         |
+//! | `OPCUA_CREATE_SAMPLE_KEYPAIR`  | `false`                | create a self signed certificate when none exists    |
+//! | `OPCUA_TRUST_SERVER_CERTS`     | `false`                | trust every server certificate, for testing only     |
+//! | `OPCUA_IDENTITY`               | `anonymous`            | user identity: `anonymous`, `username` or `x509`     |
+//! | `OPCUA_USERNAME`               |                        | user name for `username`                             |
+//! | `OPCUA_PASSWORD_FILE`          |                        | file holding the password for `username`             |
+//! | `OPCUA_PASSWORD`               |                        | the password, when `OPCUA_PASSWORD_FILE` is not set  |
+//! | `OPCUA_USER_CERT`              |                        | user certificate (DER) for `x509`                    |
+//! | `OPCUA_USER_KEY`               |                        | user private key (PEM) for `x509`                    |
+//!
+//! The endpoints of `OPCUA_SERVER` are discovered with GetEndpoints. Among the endpoints that
+//! satisfy the settings above and accept the configured user identity, the one with the highest
+//! security level is used, preferring SignAndEncrypt over Sign over None on equal levels.
+//!
+//! Server certificates are checked against `trusted/` in the PKI directory. An unknown server
+//! certificate is stored in `rejected/` and the connection fails; moving it to `trusted/`
+//! trusts that server.
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use dotenvy::var;
+use opcua::client::prelude::*;
+
+/// A password, kept out of `Debug` output.
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret(***)")
+    }
+}
+
+impl Secret {
+    pub fn new(secret: String) -> Secret {
+        Secret(secret)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+/// The user identity the session is activated with.
+#[derive(Debug, Clone)]
+pub enum UserIdentity {
+    Anonymous,
+    UserName { user: String, password: Secret },
+    X509 { cert: PathBuf, key: PathBuf },
+}
+
+impl UserIdentity {
+    pub fn from_env() -> Result<UserIdentity, String> {
+        match var("OPCUA_IDENTITY").unwrap_or_else(|_| "anonymous".to_string()).trim() {
+            "anonymous" => Ok(UserIdentity::Anonymous),
+            "username" => {
+                let user = var("OPCUA_USERNAME")
+                    .map_err(|_| "OPCUA_USERNAME is required for OPCUA_IDENTITY username".to_string())?;
+                let password = match var("OPCUA_PASSWORD_FILE") {
+                    Ok(path) => fs::read_to_string(&path)
+                        .map(|password| password.trim_end_matches(['\r', '\n']).to_string())
+                        .map_err(|err| format!("cannot read OPCUA_PASSWORD_FILE {}: {}", path, err))?,
+                    Err(_) => var("OPCUA_PASSWORD")
+                        .map_err(|_| "OPCUA_PASSWORD_FILE or OPCUA_PASSWORD is required for OPCUA_IDENTITY username".to_string())?,
+                };
+                Ok(UserIdentity::UserName { user, password: Secret(password) })
+            }
+            "x509" => {
+                let mut paths = Vec::with_capacity(2);
+                for name in ["OPCUA_USER_CERT", "OPCUA_USER_KEY"] {
+                    let path = PathBuf::from(var(name)
+                        .map_err(|_| format!("{} is required for OPCUA_IDENTITY x509", name))?);
+                    if !path.is_file() {
+                        return Err(format!("{} {} does not exist", name, path.display()));
+                    }
+                    paths.push(path);
+                }
+                let key = paths.pop().unwrap();
+                let cert = paths.pop().unwrap();
+                Ok(UserIdentity::X509 { cert, key })
+            }
+            other => Err(format!("OPCUA_IDENTITY \"{}\" must be anonymous, username or x509", other)),
+        }
+    }
+
+    pub fn token_type(&self) -> UserTokenType {
+        match self {
+            UserIdentity::Anonymous => UserTokenType::Anonymous,
+            UserIdentity::UserName { .. } => UserTokenType::UserName,
+            UserIdentity::X509 { .. } => UserTokenType::Certificate,
+        }
+    }
+
+    pub fn identity_token(&self) -> IdentityToken {
+        match self {
+            UserIdentity::Anonymous => IdentityToken::Anonymous,
+            UserIdentity::UserName { user, password } => IdentityToken::UserName(user.clone(), password.0.clone()),
+            UserIdentity::X509 { cert, key } => IdentityToken::X509(cert.clone(), key.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OpcUaConfig {
+    pub url: String,
+    pub security_policy: Option<SecurityPolicy>,
+    pub security_mode: Option<MessageSecurityMode>,
+    pub min_security_mode: MessageSecurityMode,
+    pub min_security_level: u8,
+    pub pki_dir: PathBuf,
+    pub client_cert: PathBuf,
+    pub client_key: PathBuf,
+    pub application_uri: String,
+    pub create_sample_keypair: bool,
+    pub trust_server_certs: bool,
+    pub identity: UserIdentity,
+}
+
+pub fn parse_bool(name: &str, default: bool) -> Result<bool, String> {
+    match var(name) {
+        Ok(value) => match value.trim().to_ascii_lowercase().as_str() {
+            "true" | "1" | "yes" => Ok(true),
+            "false" | "0" | "no" => Ok(false),
+            _ => Err(format!("{} \"{}\" must be true or false", name, value)),
+        },
+        Err(_) => Ok(default),
+    }
+}
+
+pub fn parse_u64(name: &str, default: u64) -> Result<u64, String> {
+    match var(name) {
+        Ok(value) => value.trim().parse::<u64>()
+            .map_err(|_| format!("{} \"{}\" is not a valid number", name, value)),
+        Err(_) => Ok(default),
+    }
+}
+
+/// The name of this instance, `DCS_INSTANCE_ID` or else the host name.
+pub fn instance_id() -> String {
+    var("DCS_INSTANCE_ID").ok()
+        .or_else(|| var("HOSTNAME").ok())
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|instance_id| instance_id.trim().to_string())
+        .filter(|instance_id| !instance_id.is_empty())
+        .unwrap_or_else(|| "dcs".to_string())
+}
+
+fn parse_security_policy(value: &str) -> Result<SecurityPolicy, String> {
+    match value.trim() {
+        "None" => Ok(SecurityPolicy::None),
+        "Basic128Rsa15" => Ok(SecurityPolicy::Basic128Rsa15),
+        "Basic256" => Ok(SecurityPolicy::Basic256),
+        "Basic256Sha256" => Ok(SecurityPolicy::Basic256Sha256),
+        "Aes128Sha256RsaOaep" => Ok(SecurityPolicy::Aes128Sha256RsaOaep),
+        "Aes256Sha256RsaPss" => Ok(SecurityPolicy::Aes256Sha256RsaPss),
+        other => Err(format!("OPCUA_SECURITY_POLICY \"{}\" must be None, Basic128Rsa15, Basic256, Basic256Sha256, Aes128Sha256RsaOaep or Aes256Sha256RsaPss", other)),
+    }
+}
+
+fn parse_security_mode(name: &str, value: &str) -> Result<MessageSecurityMode, String> {
+    match value.trim() {
+        "None" => Ok(MessageSecurityMode::None),
+        "Sign" => Ok(MessageSecurityMode::Sign),
+        "SignAndEncrypt" => Ok(MessageSecurityMode::SignAndEncrypt),
+        other => Err(format!("{} \"{}\" must be None, Sign or SignAndEncrypt", name, other)),
+    }
+}
+
+/// Orders message security modes from weakest to strongest.
+fn security_mode_rank(mode: MessageSecurityMode) -> u8 {
+    match mode {
+        MessageSecurityMode::None => 1,
+        MessageSecurityMode::Sign => 2,
+        MessageSecurityMode::SignAndEncrypt => 3,
+        _ => 0,
+    }
+}
+
+fn describe_endpoint(endpoint: &EndpointDescription) -> String {
+    let token_types: Vec<String> = endpoint.user_identity_tokens.iter().flatten()
+        .map(|policy| format!("{:?}", policy.token_type))
+        .collect();
+    format!("{} policy {} mode {:?} level {} tokens [{}]",
+        endpoint.endpoint_url, endpoint.security_policy_uri, endpoint.security_mode,
+        endpoint.security_level, token_types.join(", "))
+}
+
+impl OpcUaConfig {
+    pub fn from_env() -> Result<OpcUaConfig, String> {
+        let url = var("OPCUA_SERVER").map_err(|_| "OPCUA_SERVER is not set".to_string())?;
+        let security_policy = var("OPCUA_SECURITY_POLICY").ok()
+            .map(|policy| parse_security_policy(&policy)).transpose()?;
+        let security_mode = var("OPCUA_SECURITY_MODE").ok()
+            .map(|mode| parse_security_mode("OPCUA_SECURITY_MODE", &mode)).transpose()?;
+        if let (Some(security_policy), Some(security_mode)) = (security_policy, security_mode) {
+            if (security_policy == SecurityPolicy::None) != (security_mode == MessageSecurityMode::None) {
+                return Err(format!("OPCUA_SECURITY_POLICY {} cannot be used with OPCUA_SECURITY_MODE {:?}, \
+                    security policy None requires mode None and the other policies require Sign or SignAndEncrypt",
+                    security_policy.to_str(), security_mode));
+            }
+        }
+        let min_security_mode = match var("OPCUA_MIN_SECURITY_MODE") {
+            Ok(mode) => parse_security_mode("OPCUA_MIN_SECURITY_MODE", &mode)?,
+            Err(_) => MessageSecurityMode::None,
+        };
+        let min_security_level = match var("OPCUA_MIN_SECURITY_LEVEL") {
+            Ok(level) => level.trim().parse::<u8>()
+                .map_err(|_| format!("OPCUA_MIN_SECURITY_LEVEL \"{}\" must be a number from 0 to 255", level))?,
+            Err(_) => 0,
+        };
+        let config = OpcUaConfig {
+            url,
+            security_policy,
+            security_mode,
+            min_security_mode,
+            min_security_level,
+            pki_dir: PathBuf::from(var("OPCUA_PKI_DIR").unwrap_or_else(|_| "pki".to_string())),
+            client_cert: PathBuf::from(var("OPCUA_CLIENT_CERT").unwrap_or_else(|_| "own/cert.der".to_string())),
+            client_key: PathBuf::from(var("OPCUA_CLIENT_KEY").unwrap_or_else(|_| "private/private.pem".to_string())),
+            application_uri: var("OPCUA_APPLICATION_URI").unwrap_or_else(|_| "urn:DCSOPCUAClient".to_string()),
+            create_sample_keypair: parse_bool("OPCUA_CREATE_SAMPLE_KEYPAIR", false)?,
+            trust_server_certs: parse_bool("OPCUA_TRUST_SERVER_CERTS", false)?,
+            identity: UserIdentity::from_env()?,
+        };
+        Ok(config)
+    }
+
+    fn in_pki_dir(&self, path: &Path) -> PathBuf {
+        if path.is_absolute() { path.to_path_buf() } else { self.pki_dir.join(path) }
+    }
+
+    /// A secure connection needs the client certificate and key unless a sample keypair
+    /// may be created.
+    pub fn check_keypair(&self, security_mode: MessageSecurityMode) -> Result<(), String> {
+        if security_mode == MessageSecurityMode::None || self.create_sample_keypair {
+            return Ok(());
+        }
+        for (name, path) in [("OPCUA_CLIENT_CERT", &self.client_cert), ("OPCUA_CLIENT_KEY", &self.client_key)] {
+            let path = self.in_pki_dir(path);
+            if !path.is_file() {
+                return Err(format!("{} {} does not exist, it is required for security mode {:?}",
+                    name, path.display(), security_mode));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn client_builder(&self) -> ClientBuilder {
+        ClientBuilder::new()
+            .application_name("DCS OPC UA client")
+            .application_uri(self.application_uri.as_str())
+            .pki_dir(self.pki_dir.clone())
+            .certificate_path(self.client_cert.clone())
+            .private_key_path(self.client_key.clone())
+            .create_sample_keypair(self.create_sample_keypair)
+            .trust_server_certs(self.trust_server_certs)
+    }
+
+    /// Picks the most secure of the discovered endpoints that is acceptable to the configuration.
+    pub fn select_endpoint(&self, endpoints: &[EndpointDescription]) -> Result<EndpointDescription, String> {
+        println!("Server {} offers {} endpoints:", self.url, endpoints.len());
+        endpoints.iter().for_each(|endpoint| println!("  {}", describe_endpoint(endpoint)));
+        let token_type = self.identity.token_type();
+        let selected = endpoints.iter()
+            .filter(|endpoint| {
+                let policy = SecurityPolicy::from_uri(endpoint.security_policy_uri.as_ref());
+                policy != SecurityPolicy::Unknown
+                    && self.security_policy.is_none_or(|security_policy| security_policy == policy)
+                    && self.security_mode.is_none_or(|security_mode| security_mode == endpoint.security_mode)
+                    && security_mode_rank(endpoint.security_mode) >= security_mode_rank(self.min_security_mode)
+                    && endpoint.security_level >= self.min_security_level
+                    && endpoint.user_identity_tokens.iter().flatten().any(|policy| policy.token_type == token_type)
+            })
+            .max_by_key(|endpoint| (endpoint.security_level, security_mode_rank(endpoint.security_mode)))
+            .ok_or_else(|| format!("server {} has no acceptable endpoint: required security policy {}, mode {}, \
+                minimum mode {:?}, minimum security level {} and {:?} user tokens",
+                self.url,
+                self.security_policy.map(|policy| policy.to_str().to_string()).unwrap_or_else(|| "any".to_string()),
+                self.security_mode.map(|mode| format!("{:?}", mode)).unwrap_or_else(|| "any".to_string()),
+                self.min_security_mode, self.min_security_level, token_type))?;
+        println!("Selected endpoint {}", describe_endpoint(selected));
+        let mut endpoint = selected.clone();
+        // Servers often advertise a host name the client cannot resolve, so keep the configured url
+        endpoint.endpoint_url = UAString::from(self.url.as_str());
+        Ok(endpoint)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(security_policy: Option<SecurityPolicy>, security_mode: Option<MessageSecurityMode>, min_security_mode: MessageSecurityMode) -> OpcUaConfig {
+        OpcUaConfig {
+            url: "opc.tcp://plc1:4840".to_string(),
+            security_policy,
+            security_mode,
+            min_security_mode,
+            min_security_level: 0,
+            pki_dir: PathBuf::from("pki"),
+            client_cert: PathBuf::from("own/cert.der"),
+            client_key: PathBuf::from("private/private.pem"),
+            application_uri: "urn:DCSOPCUAClient".to_string(),
+            create_sample_keypair: false,
+            trust_server_certs: false,
+            identity: UserIdentity::Anonymous,
+        }
+    }
+
+    fn endpoint(policy: SecurityPolicy, mode: MessageSecurityMode, security_level: u8) -> EndpointDescription {
+        let mut endpoint = EndpointDescription::from(("opc.tcp://plc1.local:4840", policy.to_uri(), mode, UserTokenPolicy::anonymous()));
+        endpoint.security_level = security_level;
+        endpoint
+    }
+
+    fn endpoints() -> Vec<EndpointDescription> {
+        vec![
+            endpoint(SecurityPolicy::None, MessageSecurityMode::None, 0),
+            endpoint(SecurityPolicy::Basic256Sha256, MessageSecurityMode::Sign, 5),
+            endpoint(SecurityPolicy::Basic256Sha256, MessageSecurityMode::SignAndEncrypt, 10),
+            endpoint(SecurityPolicy::Aes256Sha256RsaPss, MessageSecurityMode::SignAndEncrypt, 10),
+        ]
+    }
+
+    fn selected(endpoint: &EndpointDescription) -> (SecurityPolicy, MessageSecurityMode) {
+        (SecurityPolicy::from_uri(endpoint.security_policy_uri.as_ref()), endpoint.security_mode)
+    }
+
+    #[test]
+    fn selects_the_preferred_policy_and_mode() {
+        let config = config(Some(SecurityPolicy::Basic256Sha256), Some(MessageSecurityMode::Sign), MessageSecurityMode::None);
+        let endpoint = config.select_endpoint(&endpoints()).unwrap();
+        assert_eq!(selected(&endpoint), (SecurityPolicy::Basic256Sha256, MessageSecurityMode::Sign));
+        assert_eq!(endpoint.endpoint_url.as_ref(), "opc.tcp://plc1:4840");
+
+        let insecure = self::config(Some(SecurityPolicy::None), None, MessageSecurityMode::None);
+        assert_eq!(selected(&insecure.select_endpoint(&endpoints()).unwrap()), (SecurityPolicy::None, MessageSecurityMode::None));
+    }
+
+    #[test]
+    fn falls_back_by_security_level_then_mode() {
+        let config = config(None, None, MessageSecurityMode::None);
+        let endpoint = config.select_endpoint(&endpoints()).unwrap();
+        assert_eq!(endpoint.security_level, 10);
+        assert_eq!(endpoint.security_mode, MessageSecurityMode::SignAndEncrypt);
+
+        // Without a level the strongest mode wins
+        let endpoints = vec![
+            endpoint(SecurityPolicy::Basic256Sha256, MessageSecurityMode::SignAndEncrypt, 0),
+            endpoint(SecurityPolicy::None, MessageSecurityMode::None, 0),
+            endpoint(SecurityPolicy::Basic256Sha256, MessageSecurityMode::Sign, 0),
+        ];
+        assert_eq!(selected(&config.select_endpoint(&endpoints).unwrap()), (SecurityPolicy::Basic256Sha256, MessageSecurityMode::SignAndEncrypt));
+    }
+
+    #[test]
+    fn rejects_none_when_security_is_required() {
+        let config = config(None, None, MessageSecurityMode::Sign);
+        let only_none = vec![endpoint(SecurityPolicy::None, MessageSecurityMode::None, 0)];
+        assert!(config.select_endpoint(&only_none).is_err());
+
+        let mut endpoints = endpoints();
+        endpoints[0].security_level = 255;
+        assert_eq!(config.select_endpoint(&endpoints).unwrap().security_mode, MessageSecurityMode::SignAndEncrypt);
+    }
+
+    #[test]
+    fn skips_endpoints_without_the_user_token_type() {
+        let mut config = config(None, None, MessageSecurityMode::None);
+        config.identity = UserIdentity::UserName { user: "operator".to_string(), password: Secret::new("secret".to_string()) };
+        let mut endpoints = endpoints();
+        endpoints[1].user_identity_tokens = Some(vec![UserTokenPolicy {
+            policy_id: UAString::from("username"),
+            token_type: UserTokenType::UserName,
+            issued_token_type: UAString::null(),
+            issuer_endpoint_url: UAString::null(),
+            security_policy_uri: UAString::null(),
+        }]);
+        assert_eq!(selected(&config.select_endpoint(&endpoints).unwrap()), (SecurityPolicy::Basic256Sha256, MessageSecurityMode::Sign));
+    }
+
+    #[test]
+    fn describes_the_requirements_when_nothing_matches() {
+        let mut config = config(Some(SecurityPolicy::Aes128Sha256RsaOaep), None, MessageSecurityMode::Sign);
+        config.min_security_level = 20;
+        let err = config.select_endpoint(&endpoints()).unwrap_err();
+        assert!(err.starts_with("server opc.tcp://plc1:4840 has no acceptable endpoint: required security policy Aes128"), "{}", err);
+        assert!(err.ends_with("mode any, minimum mode Sign, minimum security level 20 and Anonymous user tokens"), "{}", err);
+        assert!(config.select_endpoint(&[]).is_err());
+    }
+}
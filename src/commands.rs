@@ -0,0 +1,766 @@
+//! Write-back of values and method calls from a Kafka command topic, e.g. setpoints and
+//! batch starts sent by the MES.
+//!
+//! | Variable              | Default                   | Meaning                                        |
+//! |-----------------------|---------------------------|------------------------------------------------|
+//! | `COMMAND_TOPIC`       |                           | topic the commands are read from, enables write-back |
+//! | `COMMAND_REPLY_TOPIC` | `dcs.command-replies`     | topic the replies are published to             |
+//! | `COMMAND_GROUP`       | `dcs-commands-<instance>` | consumer group, its offsets are kept in Kafka  |
+//! | `COMMANDS_FILE`       |                           | the tags that may be written and the methods that may be called, required |
+//!
+//! The brokers and TLS settings are those of [`crate::kafka_sink`]. Only tags listed in
+//! `COMMANDS_FILE` can be written, each with the data type its values are converted to, and
+//! only methods listed there can be called, with the types of their input arguments:
+//!
+//! ```toml
+//! [[write]]
+//! # Tag name or alias from TAGS_FILE
+//! tag = "line1_setpoint"
+//! data_type = "Double"
+//!
+//! [[write]]
+//! tag = "line1_start_kanban"
+//! data_type = "Boolean"
+//!
+//! [[method]]
+//! name = "StartBatch"
+//! object = "nsu=urn:plant:line1;s=Line1"
+//! method = "nsu=urn:plant:line1;s=Line1.StartBatch"
+//! inputs = [
+//!     { name = "BatchId", data_type = "String" },
+//!     { name = "Quantity", data_type = "UInt32" },
+//! ]
+//! # Names of the output arguments in order
+//! outputs = ["BatchNumber"]
+//! ```
+//!
+//! A `[[write]]` tag that is not in the tag list stops the bridge at startup, or is only
+//! reported when `DISCOVER_AUTO_SUBSCRIBE` may still find it.
+//!
+//! A write names the tag by `tag` (name or alias) or by `node_id` and gives the data type it
+//! expects, which must be the one configured. A call names a configured method and gives
+//! every input argument by name:
+//!
+//! ```json
+//! {
+//!   "correlation_id": "mes-4711",
+//!   "type": "write",
+//!   "tag": "line1_setpoint",
+//!   "value": 72.5,
+//!   "data_type": "Double",
+//!   "deadline": "2024-05-01T10:00:30Z"
+//! }
+//! {
+//!   "correlation_id": "mes-4712",
+//!   "type": "call",
+//!   "method": "StartBatch",
+//!   "arguments": { "BatchId": "B-2024-117", "Quantity": 400 }
+//! }
+//! ```
+//!
+//! `type` is `write` when it is left out. The data types are `Boolean`, `SByte`, `Byte`,
+//! `Int16`, `UInt16`, `Int32`, `UInt32`, `Int64`, `UInt64`, `Float`, `Double`, `String` and
+//! `DateTime` (an RFC 3339 string). Integer values must be JSON integers in the range of the
+//! type. A command past its optional `deadline` is not executed, so commands sent while the
+//! bridge was down are not applied late. Every command is answered on `COMMAND_REPLY_TOPIC`,
+//! keyed by its correlation id:
+//!
+//! ```json
+//! {
+//!   "schema_version": 1,
+//!   "correlation_id": "mes-4711",
+//!   "endpoint": "opc.tcp://plc1:4840",
+//!   "node_id": "ns=2;s=Line1.Setpoint",
+//!   "tag": "line1_setpoint",
+//!   "status": { "code": 0, "name": "Good" },
+//!   "time": "2024-05-01T10:00:00.532Z"
+//! }
+//! {
+//!   "schema_version": 1,
+//!   "correlation_id": "mes-4712",
+//!   "endpoint": "opc.tcp://plc1:4840",
+//!   "node_id": "ns=3;s=Line1.StartBatch",
+//!   "method": "StartBatch",
+//!   "status": { "code": 0, "name": "Good" },
+//!   "outputs": { "BatchNumber": 118 },
+//!   "time": "2024-05-01T10:00:01.207Z"
+//! }
+//! ```
+//!
+//! `status` is the result of the Write or Call, or for a command that was not executed
+//! `BadUserAccessDenied` (tag not writable or method not configured), `BadTypeMismatch`,
+//! `BadOutOfRange`, `BadArgumentsMissing`, `BadInvalidArgument` (unknown argument),
+//! `BadNodeIdUnknown`, `BadDecodingError` (not a valid command), `BadTimeout` (past its
+//! deadline) or `BadNotConnected`, with the reason in `error`. Output arguments without a
+//! configured name are named by their position. Commands are executed one at a time in the
+//! order they are read.
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use chrono::Utc;
+use dotenvy::var;
+use opcua::client::prelude::*;
+use opcua::sync::{Mutex, RwLock};
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{BaseConsumer, CommitMode, Consumer};
+use rdkafka::error::KafkaResult;
+use rdkafka::Message;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::kafka_sink::{client_config, KafkaConfig, PublisherHandle};
+use crate::node_id::{read_namespace_array, NodeIdSpec};
+use crate::sample::{variant_to_json, SampleStatus, SCHEMA_VERSION};
+use crate::tags::{TagConfig, TagList, TagMapping};
+
+const DEFAULT_REPLY_TOPIC: &str = "dcs.command-replies";
+/// How long to wait before creating the consumer again after Kafka failed.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// The data types values can be written as.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum DataType {
+    Boolean,
+    SByte,
+    Byte,
+    Int16,
+    UInt16,
+    Int32,
+    UInt32,
+    Int64,
+    UInt64,
+    Float,
+    Double,
+    String,
+    DateTime,
+}
+
+impl DataType {
+    /// Converts a JSON value to a variant of this type, failing with `BadTypeMismatch` or
+    /// `BadOutOfRange`.
+    pub fn to_variant(self, value: &Value) -> Result<Variant, (StatusCode, String)> {
+        let mismatch = || (StatusCode::BadTypeMismatch, format!("{} is not a {:?}", value, self));
+        let out_of_range = || (StatusCode::BadOutOfRange, format!("{} is out of the range of {:?}", value, self));
+        let integer = || value.as_i64().map(i128::from).or_else(|| value.as_u64().map(i128::from)).ok_or_else(mismatch);
+        Ok(match self {
+            DataType::Boolean => Variant::Boolean(value.as_bool().ok_or_else(mismatch)?),
+            DataType::SByte => Variant::SByte(i8::try_from(integer()?).map_err(|_| out_of_range())?),
+            DataType::Byte => Variant::Byte(u8::try_from(integer()?).map_err(|_| out_of_range())?),
+            DataType::Int16 => Variant::Int16(i16::try_from(integer()?).map_err(|_| out_of_range())?),
+            DataType::UInt16 => Variant::UInt16(u16::try_from(integer()?).map_err(|_| out_of_range())?),
+            DataType::Int32 => Variant::Int32(i32::try_from(integer()?).map_err(|_| out_of_range())?),
+            DataType::UInt32 => Variant::UInt32(u32::try_from(integer()?).map_err(|_| out_of_range())?),
+            DataType::Int64 => Variant::Int64(i64::try_from(integer()?).map_err(|_| out_of_range())?),
+            DataType::UInt64 => Variant::UInt64(u64::try_from(integer()?).map_err(|_| out_of_range())?),
+            DataType::Float => {
+                let float = value.as_f64().ok_or_else(mismatch)?;
+                if float.abs() > f32::MAX as f64 {
+                    return Err(out_of_range());
+                }
+                Variant::Float(float as f32)
+            }
+            DataType::Double => Variant::Double(value.as_f64().ok_or_else(mismatch)?),
+            DataType::String => Variant::String(UAString::from(value.as_str().ok_or_else(mismatch)?)),
+            DataType::DateTime => {
+                let time = value.as_str()
+                    .and_then(|time| chrono::DateTime::parse_from_rfc3339(time).ok())
+                    .ok_or_else(mismatch)?;
+                Variant::from(DateTime::from(time.with_timezone(&Utc)))
+            }
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WriteEntry {
+    tag: String,
+    data_type: DataType,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ArgumentEntry {
+    name: String,
+    data_type: DataType,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MethodEntry {
+    name: String,
+    object: String,
+    method: String,
+    #[serde(default)]
+    inputs: Vec<ArgumentEntry>,
+    #[serde(default)]
+    outputs: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CommandFile {
+    #[serde(default)]
+    write: Vec<WriteEntry>,
+    #[serde(default)]
+    method: Vec<MethodEntry>,
+}
+
+/// A validated `[[method]]`, before its namespaces are resolved.
+#[derive(Debug, Clone)]
+pub struct MethodConfig {
+    pub object: NodeIdSpec,
+    pub method: NodeIdSpec,
+    /// The input arguments in call order, with the type their values are converted to.
+    pub inputs: Vec<(String, DataType)>,
+    /// The names of the output arguments in order.
+    pub outputs: Vec<String>,
+}
+
+impl MethodConfig {
+    /// The input arguments of a call of method `name` in call order, failing with
+    /// `BadInvalidArgument` for an unknown argument, `BadArgumentsMissing` or the conversion
+    /// error of an argument.
+    fn input_arguments(&self, name: &str, arguments: &Map<String, Value>) -> Result<Vec<Variant>, (StatusCode, String)> {
+        if let Some(unknown) = arguments.keys().find(|argument| !self.inputs.iter().any(|(input, _)| input == *argument)) {
+            return Err((StatusCode::BadInvalidArgument, format!("method \"{}\" has no argument \"{}\"", name, unknown)));
+        }
+        let mut input_arguments = Vec::with_capacity(self.inputs.len());
+        for (input, data_type) in &self.inputs {
+            let value = arguments.get(input)
+                .ok_or_else(|| (StatusCode::BadArgumentsMissing, format!("argument \"{}\" is missing", input)))?;
+            input_arguments.push(data_type.to_variant(value)
+                .map_err(|(status, err)| (status, format!("argument \"{}\": {}", input, err)))?);
+        }
+        Ok(input_arguments)
+    }
+
+    /// The output arguments of a call by their configured name, or by position for the
+    /// ones without a name.
+    fn name_outputs(&self, outputs: &[Variant]) -> Map<String, Value> {
+        outputs.iter().enumerate()
+            .map(|(index, output)| (self.outputs.get(index).cloned().unwrap_or_else(|| index.to_string()), variant_to_json(output)))
+            .collect()
+    }
+}
+
+impl MethodEntry {
+    fn validate(self) -> Result<MethodConfig, String> {
+        let mut inputs: Vec<(String, DataType)> = Vec::with_capacity(self.inputs.len());
+        for input in self.inputs {
+            if inputs.iter().any(|(name, _)| *name == input.name) {
+                return Err(format!("has input argument \"{}\" twice", input.name));
+            }
+            inputs.push((input.name, input.data_type));
+        }
+        Ok(MethodConfig {
+            object: NodeIdSpec::parse(&self.object)?,
+            method: NodeIdSpec::parse(&self.method)?,
+            inputs,
+            outputs: self.outputs,
+        })
+    }
+}
+
+/// The allow-list of `COMMANDS_FILE`.
+#[derive(Debug, Clone)]
+pub struct CommandList {
+    /// The data type of every writable tag, by the name or alias it is listed with.
+    writable: HashMap<String, DataType>,
+    /// The methods that can be called, by name.
+    methods: HashMap<String, MethodConfig>,
+}
+
+impl CommandList {
+    pub fn load(path: &str) -> Result<CommandList, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("cannot read commands file {}: {}", path, err))?;
+        Self::parse(&contents, path)
+    }
+
+    fn parse(contents: &str, path: &str) -> Result<CommandList, String> {
+        let file: CommandFile = toml::from_str(contents)
+            .map_err(|err| format!("invalid commands file {}: {}", path, err))?;
+        let mut writable = HashMap::with_capacity(file.write.len());
+        for entry in file.write {
+            if writable.insert(entry.tag.clone(), entry.data_type).is_some() {
+                return Err(format!("commands file {}: tag \"{}\" is listed twice", path, entry.tag));
+            }
+        }
+        let mut methods = HashMap::with_capacity(file.method.len());
+        for (index, entry) in file.method.into_iter().enumerate() {
+            let name = entry.name.clone();
+            let method = entry.validate()
+                .map_err(|err| format!("commands file {}: method {} (\"{}\") {}", path, index + 1, name, err))?;
+            if methods.insert(name.clone(), method).is_some() {
+                return Err(format!("commands file {}: method \"{}\" is listed twice", path, name));
+            }
+        }
+        Ok(CommandList { writable, methods })
+    }
+
+    /// Fails when a `[[write]]` names neither a tag nor an alias of the tag list.
+    pub fn check_tags(&self, tags: &TagList) -> Result<(), String> {
+        for name in self.writable.keys() {
+            if tags.tags.iter().all(|tag| tag.name != *name && tag.alias.as_ref() != Some(name)) {
+                return Err(format!("commands file: tag \"{}\" to write is not in the tag list", name));
+            }
+        }
+        Ok(())
+    }
+
+    /// The data type a tag may be written as, `None` when it is not writable.
+    fn data_type(&self, tag: &TagConfig) -> Option<DataType> {
+        self.writable.get(&tag.name)
+            .or_else(|| tag.alias.as_ref().and_then(|alias| self.writable.get(alias)))
+            .copied()
+    }
+
+    /// The value a write of `tag` sends, when the tag is writable as `expected`.
+    fn write_value(&self, tag: &TagConfig, value: &Value, expected: DataType) -> Result<Variant, (StatusCode, String)> {
+        match self.data_type(tag) {
+            None => Err((StatusCode::BadUserAccessDenied, format!("tag \"{}\" is not writable", tag.name))),
+            Some(data_type) if data_type != expected => Err((StatusCode::BadTypeMismatch,
+                format!("tag \"{}\" is written as {:?}, not {:?}", tag.name, data_type, expected))),
+            Some(data_type) => data_type.to_variant(value),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CommandConfig {
+    pub topic: String,
+    pub reply_topic: String,
+    pub group: String,
+    pub commands: CommandList,
+}
+
+impl CommandConfig {
+    /// The write-back settings, `None` when `COMMAND_TOPIC` is not set.
+    pub fn from_env(instance_id: &str) -> Result<Option<CommandConfig>, String> {
+        let Some(topic) = var("COMMAND_TOPIC").ok().filter(|topic| !topic.trim().is_empty()) else {
+            return Ok(None);
+        };
+        let path = var("COMMANDS_FILE").map_err(|_| "COMMANDS_FILE is required with COMMAND_TOPIC".to_string())?;
+        Ok(Some(CommandConfig {
+            topic,
+            reply_topic: var("COMMAND_REPLY_TOPIC").ok().filter(|topic| !topic.trim().is_empty())
+                .unwrap_or_else(|| DEFAULT_REPLY_TOPIC.to_string()),
+            group: var("COMMAND_GROUP").unwrap_or_else(|_| format!("dcs-commands-{}", instance_id)),
+            commands: CommandList::load(&path)?,
+        }))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Command {
+    correlation_id: String,
+    deadline: Option<chrono::DateTime<Utc>>,
+    #[serde(flatten)]
+    action: Action,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Action {
+    Write {
+        tag: Option<String>,
+        node_id: Option<String>,
+        value: Value,
+        data_type: DataType,
+    },
+    Call {
+        method: String,
+        #[serde(default)]
+        arguments: Map<String, Value>,
+    },
+}
+
+impl Command {
+    fn parse(payload: &[u8]) -> Result<Command, String> {
+        let mut value: Value = serde_json::from_slice(payload).map_err(|err| err.to_string())?;
+        // A command without a type is a write
+        if let Value::Object(ref mut object) = value
+            && !object.contains_key("type") {
+            object.insert("type".to_string(), Value::from("write"));
+        }
+        serde_json::from_value(value).map_err(|err| err.to_string())
+    }
+}
+
+/// The answer to a command, published to `COMMAND_REPLY_TOPIC`.
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandReply {
+    pub schema_version: u32,
+    /// `None` only for messages that are not JSON objects with a correlation id.
+    pub correlation_id: Option<String>,
+    pub endpoint: String,
+    /// The written node, or the called method.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    pub status: SampleStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The output arguments of a method call, by name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outputs: Option<Map<String, Value>>,
+    pub time: String,
+}
+
+impl CommandReply {
+    fn new(correlation_id: Option<String>, endpoint: String, status: StatusCode, error: Option<String>) -> CommandReply {
+        CommandReply {
+            schema_version: SCHEMA_VERSION,
+            correlation_id,
+            endpoint,
+            node_id: None,
+            tag: None,
+            method: None,
+            status: status.into(),
+            error,
+            outputs: None,
+            time: Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        }
+    }
+}
+
+struct Connected {
+    endpoint: String,
+    session: Arc<RwLock<Session>>,
+    tags: TagMapping,
+    namespaces: Vec<String>,
+}
+
+/// The session commands are executed on, set by the main loop while it is connected.
+#[derive(Clone, Default)]
+pub struct CommandTarget {
+    connected: Arc<Mutex<Option<Arc<Connected>>>>,
+}
+
+impl CommandTarget {
+    pub fn connected(&self, session: &Arc<RwLock<Session>>, endpoint: &str, tags: TagMapping) {
+        // Node ids of commands and methods may name their namespace by URI
+        let namespaces = read_namespace_array(&session.read()).unwrap_or_else(|status| {
+            println!("Cannot read the server NamespaceArray, commands must use namespace indexes: {}", status);
+            Vec::new()
+        });
+        *self.connected.lock() = Some(Arc::new(Connected { endpoint: endpoint.to_string(), session: session.clone(), tags, namespaces }));
+    }
+
+    pub fn disconnected(&self) {
+        *self.connected.lock() = None;
+    }
+
+    /// Executes a command message and returns its reply.
+    fn execute(&self, commands: &CommandList, payload: &[u8]) -> CommandReply {
+        // Not locked during the Write or Call, which may block until the request times out
+        let connected = self.connected.lock().clone();
+        let endpoint = connected.as_ref().map(|connected| connected.endpoint.clone()).unwrap_or_default();
+        let command = match Command::parse(payload) {
+            Ok(command) => command,
+            Err(err) => {
+                // Answer under the correlation id when the message has one
+                let correlation_id = serde_json::from_slice::<Value>(payload).ok()
+                    .and_then(|value| value.get("correlation_id")?.as_str().map(str::to_string));
+                return CommandReply::new(correlation_id, endpoint, StatusCode::BadDecodingError, Some(format!("not a valid command: {}", err)));
+            }
+        };
+        let correlation_id = Some(command.correlation_id.clone());
+        if let Some(deadline) = command.deadline
+            && deadline < Utc::now() {
+            return CommandReply::new(correlation_id, endpoint, StatusCode::BadTimeout, Some(format!("deadline {} has passed", deadline.to_rfc3339())));
+        }
+        let Some(connected) = connected else {
+            return CommandReply::new(correlation_id, endpoint, StatusCode::BadNotConnected, Some("the OPC UA session is not connected".to_string()));
+        };
+        let mut reply = CommandReply::new(correlation_id, endpoint, StatusCode::Good, None);
+        let result = match command.action {
+            Action::Write { tag, node_id, value, data_type } => connected.write(commands, &mut reply, tag, node_id, &value, data_type),
+            Action::Call { method, arguments } => connected.call(commands, &mut reply, &method, &arguments),
+        };
+        let status = match result {
+            Ok(status) if status.is_bad() => {
+                reply.error = Some("the server refused the command".to_string());
+                status
+            }
+            Ok(status) => status,
+            Err((status, err)) => {
+                reply.error = Some(err);
+                status
+            }
+        };
+        println!("Command {} on {}: {}", command.correlation_id,
+            reply.method.as_ref().or(reply.tag.as_ref()).map(|target| format!("\"{}\"", target)).unwrap_or_else(|| "unknown target".to_string()),
+            status);
+        reply.status = status.into();
+        reply
+    }
+}
+
+impl Connected {
+    /// The tag a command names by `tag` or `node_id`.
+    fn find_tag(&self, tag: Option<String>, node_id: Option<String>) -> Result<(NodeId, &TagConfig), (StatusCode, String)> {
+        match (tag, node_id) {
+            (Some(name), None) => self.tags.find(&name)
+                .map(|(node_id, tag)| (node_id.clone(), tag))
+                .ok_or_else(|| (StatusCode::BadNodeIdUnknown, format!("there is no tag \"{}\"", name))),
+            (None, Some(node_id)) => {
+                let node_id = NodeIdSpec::parse(&node_id)
+                    .and_then(|spec| spec.resolve(&self.namespaces))
+                    .map_err(|err| (StatusCode::BadNodeIdInvalid, err))?;
+                let tag = self.tags.get(&node_id)
+                    .ok_or_else(|| (StatusCode::BadNodeIdUnknown, format!("node {} is not a tag", node_id)))?;
+                Ok((node_id, tag))
+            }
+            _ => Err((StatusCode::BadNodeIdInvalid, "a write needs either tag or node_id".to_string())),
+        }
+    }
+
+    /// Writes an allowed tag, returning the status of the Write call.
+    fn write(&self, commands: &CommandList, reply: &mut CommandReply, tag: Option<String>, node_id: Option<String>, value: &Value, expected: DataType) -> Result<StatusCode, (StatusCode, String)> {
+        let (node_id, tag) = self.find_tag(tag, node_id)?;
+        reply.node_id = Some(node_id.to_string());
+        reply.tag = Some(tag.name.clone());
+        let value = commands.write_value(tag, value, expected)?;
+        let write_value = WriteValue {
+            node_id,
+            attribute_id: AttributeId::Value as u32,
+            index_range: UAString::null(),
+            value: DataValue::value_only(value),
+        };
+        let results = self.session.read().write(&[write_value]).map_err(|status| (status, "the Write call failed".to_string()))?;
+        Ok(results.first().copied().unwrap_or(StatusCode::BadUnexpectedError))
+    }
+
+    /// Calls a configured method, returning the status of the Call and setting the output
+    /// arguments of the reply.
+    fn call(&self, commands: &CommandList, reply: &mut CommandReply, name: &str, arguments: &Map<String, Value>) -> Result<StatusCode, (StatusCode, String)> {
+        reply.method = Some(name.to_string());
+        let method = commands.methods.get(name)
+            .ok_or_else(|| (StatusCode::BadUserAccessDenied, format!("method \"{}\" is not configured", name)))?;
+        let input_arguments = method.input_arguments(name, arguments)?;
+        let object_id = method.object.resolve(&self.namespaces).map_err(|err| (StatusCode::BadNodeIdInvalid, err))?;
+        let method_id = method.method.resolve(&self.namespaces).map_err(|err| (StatusCode::BadNodeIdInvalid, err))?;
+        reply.node_id = Some(method_id.to_string());
+        let result = self.session.read().call(CallMethodRequest {
+            object_id,
+            method_id,
+            input_arguments: Some(input_arguments),
+        }).map_err(|status| (status, "the Call failed".to_string()))?;
+        // Name the arguments the server refused
+        let refused: Vec<String> = result.input_argument_results.iter().flatten()
+            .zip(&method.inputs)
+            .filter(|(status, _)| status.is_bad())
+            .map(|(status, (input, _))| format!("argument \"{}\": {}", input, status))
+            .collect();
+        if !refused.is_empty() {
+            return Err((result.status_code, refused.join(", ")));
+        }
+        reply.outputs = result.output_arguments.map(|outputs| method.name_outputs(&outputs));
+        Ok(result.status_code)
+    }
+}
+
+/// How long one poll of the command topic waits for a command.
+const POLL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A consumer of `COMMAND_TOPIC` that starts at the end of the topic when its group has no
+/// committed offset yet.
+fn create_consumer(client_config: &ClientConfig, config: &CommandConfig) -> KafkaResult<BaseConsumer> {
+    let consumer: BaseConsumer = client_config.clone()
+        .set("group.id", config.group.as_str())
+        .set("enable.auto.commit", "false")
+        .set("auto.offset.reset", "latest")
+        .create()?;
+    consumer.subscribe(&[config.topic.as_str()])?;
+    Ok(consumer)
+}
+
+/// Starts the thread reading `COMMAND_TOPIC`, which executes every command on the session
+/// of `target` and publishes the replies.
+pub fn spawn_command_consumer(config: CommandConfig, kafka_config: &KafkaConfig, publisher: PublisherHandle, target: CommandTarget) {
+    let client_config = client_config(kafka_config);
+    thread::spawn(move || loop {
+        let consumer = match create_consumer(&client_config, &config) {
+            Ok(consumer) => consumer,
+            Err(err) => {
+                println!("Cannot read commands from {}, retrying in {:?}: {}", config.topic, RETRY_DELAY, err);
+                thread::sleep(RETRY_DELAY);
+                continue;
+            }
+        };
+        println!("Reading commands from {} as group {}", config.topic, config.group);
+        loop {
+            let message = match consumer.poll(POLL_TIMEOUT) {
+                Some(Ok(message)) => message,
+                Some(Err(err)) => {
+                    // librdkafka reconnects by itself
+                    println!("Cannot read commands from {}, retrying in {:?}: {}", config.topic, RETRY_DELAY, err);
+                    thread::sleep(RETRY_DELAY);
+                    continue;
+                }
+                None => continue,
+            };
+            let reply = target.execute(&config.commands, message.payload().unwrap_or_default());
+            let key = reply.correlation_id.clone().unwrap_or_default();
+            if let Err(err) = publisher.publish_event(&config.reply_topic, &reply.endpoint, &key, &reply) {
+                println!("Failed to send the reply to command {} to Kafka topic {}: {}", key, config.reply_topic, err);
+            }
+            if let Err(err) = consumer.commit_message(&message, CommitMode::Sync) {
+                println!("Cannot commit the command offsets of group {}: {}", config.group, err);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn converts_values_to_the_data_type() {
+        assert_eq!(DataType::UInt16.to_variant(&json!(65535)).unwrap(), Variant::UInt16(65535));
+        assert_eq!(DataType::Double.to_variant(&json!(3)).unwrap(), Variant::Double(3.0));
+        assert_eq!(DataType::Boolean.to_variant(&json!(true)).unwrap(), Variant::Boolean(true));
+        assert_eq!(DataType::UInt16.to_variant(&json!(65536)).unwrap_err().0, StatusCode::BadOutOfRange);
+        assert_eq!(DataType::Byte.to_variant(&json!(-1)).unwrap_err().0, StatusCode::BadOutOfRange);
+        assert_eq!(DataType::Int32.to_variant(&json!(1.5)).unwrap_err().0, StatusCode::BadTypeMismatch);
+        assert_eq!(DataType::Boolean.to_variant(&json!("true")).unwrap_err().0, StatusCode::BadTypeMismatch);
+        assert_eq!(DataType::Float.to_variant(&json!(1e300)).unwrap_err().0, StatusCode::BadOutOfRange);
+    }
+
+    #[test]
+    fn parses_writes_and_calls() {
+        let command = Command::parse(br#"{"correlation_id": "1", "tag": "setpoint", "value": 5, "data_type": "UInt16"}"#).unwrap();
+        assert!(matches!(command.action, Action::Write { data_type: DataType::UInt16, .. }));
+        let command = Command::parse(br#"{"correlation_id": "2", "type": "call", "method": "StartBatch", "arguments": {"Quantity": 400}}"#).unwrap();
+        match command.action {
+            Action::Call { method, arguments } => {
+                assert_eq!(method, "StartBatch");
+                assert_eq!(arguments["Quantity"], json!(400));
+            }
+            other => panic!("expected a call, got {:?}", other),
+        }
+        assert!(Command::parse(br#"{"correlation_id": "3", "type": "reset"}"#).is_err());
+    }
+
+    fn commands() -> CommandList {
+        CommandList::parse(r#"
+            [[write]]
+            tag = "line1_setpoint"
+            data_type = "Double"
+
+            [[method]]
+            name = "StartBatch"
+            object = "ns=2;s=Line1"
+            method = "ns=2;s=Line1.StartBatch"
+            inputs = [
+                { name = "BatchId", data_type = "String" },
+                { name = "Quantity", data_type = "UInt32" },
+            ]
+            outputs = ["BatchNumber"]
+        "#, "commands.toml").unwrap()
+    }
+
+    fn tag(name: &str, alias: Option<&str>) -> TagConfig {
+        let mut tag = TagConfig::new(name.to_string(), NodeIdSpec::parse(&format!("ns=2;s={}", name)).unwrap());
+        tag.alias = alias.map(str::to_string);
+        tag
+    }
+
+    #[test]
+    fn writes_only_listed_tags_as_their_data_type() {
+        let commands = commands();
+        let setpoint = tag("Line1.Setpoint", Some("line1_setpoint"));
+        assert_eq!(commands.write_value(&setpoint, &json!(72.5), DataType::Double).unwrap(), Variant::Double(72.5));
+        let (status, err) = commands.write_value(&setpoint, &json!(72), DataType::Int32).unwrap_err();
+        assert_eq!(status, StatusCode::BadTypeMismatch);
+        assert_eq!(err, "tag \"Line1.Setpoint\" is written as Double, not Int32");
+        let (status, err) = commands.write_value(&tag("Line1.Speed", None), &json!(1.0), DataType::Double).unwrap_err();
+        assert_eq!(status, StatusCode::BadUserAccessDenied);
+        assert_eq!(err, "tag \"Line1.Speed\" is not writable");
+    }
+
+    #[test]
+    fn refuses_a_tag_listed_twice() {
+        let err = CommandList::parse(r#"
+            [[write]]
+            tag = "line1_setpoint"
+            data_type = "Double"
+
+            [[write]]
+            tag = "line1_setpoint"
+            data_type = "Float"
+        "#, "commands.toml").unwrap_err();
+        assert_eq!(err, "commands file commands.toml: tag \"line1_setpoint\" is listed twice");
+    }
+
+    #[test]
+    fn checks_the_writes_against_the_tag_list() {
+        let commands = commands();
+        assert!(commands.check_tags(&TagList { tags: vec![tag("Line1.Setpoint", Some("line1_setpoint"))] }).is_ok());
+        assert!(commands.check_tags(&TagList { tags: vec![tag("line1_setpoint", None)] }).is_ok());
+        assert_eq!(commands.check_tags(&TagList { tags: vec![tag("Line1.Setpoint", None)] }).unwrap_err(),
+            "commands file: tag \"line1_setpoint\" to write is not in the tag list");
+    }
+
+    #[test]
+    fn converts_the_input_arguments_in_call_order() {
+        let commands = commands();
+        let method = &commands.methods["StartBatch"];
+        let inputs = method.input_arguments("StartBatch", json!({"Quantity": 400, "BatchId": "B-117"}).as_object().unwrap()).unwrap();
+        assert_eq!(inputs, vec![Variant::String(UAString::from("B-117")), Variant::UInt32(400)]);
+        let (status, err) = method.input_arguments("StartBatch", json!({"BatchId": "B-117", "Quantity": 400, "Line": 1}).as_object().unwrap()).unwrap_err();
+        assert_eq!(status, StatusCode::BadInvalidArgument);
+        assert_eq!(err, "method \"StartBatch\" has no argument \"Line\"");
+        let (status, err) = method.input_arguments("StartBatch", json!({"BatchId": "B-117"}).as_object().unwrap()).unwrap_err();
+        assert_eq!(status, StatusCode::BadArgumentsMissing);
+        assert_eq!(err, "argument \"Quantity\" is missing");
+        let (status, err) = method.input_arguments("StartBatch", json!({"BatchId": "B-117", "Quantity": -1}).as_object().unwrap()).unwrap_err();
+        assert_eq!(status, StatusCode::BadOutOfRange);
+        assert_eq!(err, "argument \"Quantity\": -1 is out of the range of UInt32");
+    }
+
+    #[test]
+    fn refuses_an_input_argument_listed_twice() {
+        let err = CommandList::parse(r#"
+            [[method]]
+            name = "StartBatch"
+            object = "ns=2;s=Line1"
+            method = "ns=2;s=Line1.StartBatch"
+            inputs = [
+                { name = "BatchId", data_type = "String" },
+                { name = "BatchId", data_type = "UInt32" },
+            ]
+        "#, "commands.toml").unwrap_err();
+        assert_eq!(err, "commands file commands.toml: method 1 (\"StartBatch\") has input argument \"BatchId\" twice");
+    }
+
+    #[test]
+    fn names_the_outputs_by_config_then_by_position() {
+        let commands = commands();
+        let outputs = commands.methods["StartBatch"].name_outputs(&[Variant::UInt32(118), Variant::Boolean(true)]);
+        assert_eq!(Value::Object(outputs), json!({"BatchNumber": 118, "1": true}));
+    }
+
+    #[test]
+    fn does_not_execute_late_or_disconnected_commands() {
+        let target = CommandTarget::default();
+        let reply = target.execute(&commands(), br#"{"correlation_id": "1", "tag": "line1_setpoint", "value": 1.0, "data_type": "Double", "deadline": "2020-01-01T00:00:00Z"}"#);
+        assert_eq!(reply.correlation_id.as_deref(), Some("1"));
+        assert_eq!(reply.status.code, StatusCode::BadTimeout.bits());
+        let reply = target.execute(&commands(), br#"{"correlation_id": "2", "tag": "line1_setpoint", "value": 1.0, "data_type": "Double"}"#);
+        assert_eq!(reply.status.code, StatusCode::BadNotConnected.bits());
+        assert_eq!(reply.error.as_deref(), Some("the OPC UA session is not connected"));
+        let reply = target.execute(&commands(), br#"{"correlation_id": "3", "value": }"#);
+        assert_eq!(reply.correlation_id, None);
+        assert_eq!(reply.status.code, StatusCode::BadDecodingError.bits());
+    }
+}
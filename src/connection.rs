@@ -0,0 +1,255 @@
+//! Connecting to the OPC UA server and reconnecting after the connection is lost.
+//!
+//! The session is not reconnected by the OPC UA client itself. When `Session::run` returns,
+//! a new session is connected after an exponential backoff and the subscription and its
+//! monitored items are created again. The backoff is configured with:
+//!
+//! | Variable                          | Default  | Meaning                                      |
+//! |-----------------------------------|----------|----------------------------------------------|
+//! | `OPCUA_RECONNECT_INITIAL_DELAY_MS`| `1000`   | delay before the first reconnect attempt     |
+//! | `OPCUA_RECONNECT_MAX_DELAY_MS`    | `60000`  | upper bound of the doubling delay            |
+//! | `OPCUA_RECONNECT_MAX_ATTEMPTS`    | `0`      | attempts before giving up, 0 for unlimited   |
+//! | `OPCUA_RECONNECT_JITTER_PERCENT`  | `20`     | up to this share is taken off each delay     |
+//!
+//! The jitter keeps several bridges that lost the same server from reconnecting in lockstep.
+//!
+//! Every connect, disconnect and reconnect is logged and, when `KAFKA_STATUS_TOPIC` is set,
+//! published there as a [`ConnectionEvent`] with the tags that were restored and the length
+//! of the data gap.
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+use opcua::client::prelude::*;
+use opcua::sync::*;
+use serde::Serialize;
+
+use crate::browse::{browse, BrowseConfig};
+use crate::client_config::{parse_u64, OpcUaConfig};
+use crate::node_id::read_namespace_array;
+use crate::sample::SCHEMA_VERSION;
+use crate::tags::{TagList, TagMapping};
+
+#[derive(Debug, Clone)]
+pub struct ReconnectConfig {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// `None` retries forever.
+    pub max_attempts: Option<u32>,
+    /// The share of a delay, from 0 to 1, that is randomly taken off.
+    pub jitter: f64,
+}
+
+impl ReconnectConfig {
+    pub fn from_env() -> Result<ReconnectConfig, String> {
+        let initial_delay = Duration::from_millis(parse_u64("OPCUA_RECONNECT_INITIAL_DELAY_MS", 1000)?.max(1));
+        let max_delay = Duration::from_millis(parse_u64("OPCUA_RECONNECT_MAX_DELAY_MS", 60_000)?).max(initial_delay);
+        let max_attempts = match parse_u64("OPCUA_RECONNECT_MAX_ATTEMPTS", 0)? {
+            0 => None,
+            attempts => Some(u32::try_from(attempts).unwrap_or(u32::MAX)),
+        };
+        let jitter_percent = parse_u64("OPCUA_RECONNECT_JITTER_PERCENT", 20)?;
+        if jitter_percent > 100 {
+            return Err(format!("OPCUA_RECONNECT_JITTER_PERCENT {} must be between 0 and 100", jitter_percent));
+        }
+        Ok(ReconnectConfig { initial_delay, max_delay, max_attempts, jitter: jitter_percent as f64 / 100.0 })
+    }
+}
+
+/// Doubling delays between connection attempts, shortened by a random jitter.
+pub struct Backoff {
+    config: ReconnectConfig,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(config: ReconnectConfig) -> Backoff {
+        Backoff { config, attempt: 0 }
+    }
+
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// The delay before the next attempt, or `None` once the attempts are used up.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self.config.max_attempts.is_some_and(|max_attempts| self.attempt >= max_attempts) {
+            return None;
+        }
+        let delay = self.config.initial_delay
+            .checked_mul(2u32.saturating_pow(self.attempt.min(31)))
+            .unwrap_or(self.config.max_delay)
+            .min(self.config.max_delay);
+        self.attempt += 1;
+        Some(delay.mul_f64(1.0 - self.config.jitter * random_fraction()))
+    }
+}
+
+/// A random number from 0 to 1, from the randomly keyed std hasher.
+fn random_fraction() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Why connecting failed. Only `Retry` errors are worth another attempt.
+#[derive(Debug)]
+pub enum ConnectError {
+    Retry(String),
+    Fatal(String),
+}
+
+/// Discovers the endpoints, connects a session and resolves the tags against the server's
+/// namespaces, which may have changed since the last connection. With `discover`, the
+/// variables found by browsing are added to the tags.
+pub fn connect(client: &mut Client, opcua_config: &OpcUaConfig, tag_list: &TagList, discover: Option<&BrowseConfig>) -> Result<(Arc<RwLock<Session>>, TagMapping), ConnectError> {
+    let opcua_host = opcua_config.url.as_str();
+    // Discover the server endpoints and pick the most secure acceptable one
+    let endpoints = client.get_server_endpoints_from_url(opcua_host)
+        .map_err(|status| ConnectError::Retry(format!("GetEndpoints on {} failed: {}", opcua_host, status)))?;
+    let endpoint = opcua_config.select_endpoint(&endpoints).map_err(ConnectError::Fatal)?;
+    opcua_config.check_keypair(endpoint.security_mode).map_err(ConnectError::Fatal)?;
+    let security_policy = SecurityPolicy::from_uri(endpoint.security_policy_uri.as_ref());
+    let security_mode = endpoint.security_mode;
+
+    // Create the session
+    let session = client.connect_to_endpoint(endpoint, opcua_config.identity.identity_token())
+        .map_err(|status| ConnectError::Retry(format!("cannot connect to {} with security policy {}, mode {:?} and {:?} identity: {}. \
+            An untrusted server certificate is stored in {}/rejected and must be moved to {}/trusted",
+            opcua_host, security_policy.to_str(), security_mode, opcua_config.identity.token_type(), status,
+            opcua_config.pki_dir.display(), opcua_config.pki_dir.display())))?;
+
+    // Resolve namespace URIs of the configured tags to the server's namespace indexes
+    let namespaces = if tag_list.has_namespace_uris() || discover.is_some() {
+        read_namespace_array(&session.read())
+            .map_err(|status| ConnectError::Retry(format!("cannot read the server NamespaceArray: {}", status)))?
+    } else {
+        Vec::new()
+    };
+    let mut tag_mapping = tag_list.resolve(&namespaces).map_err(ConnectError::Fatal)?;
+    if let Some(discover) = discover {
+        let nodes = browse(&session.read(), discover, &namespaces).map_err(ConnectError::Retry)?;
+        let variables: Vec<_> = nodes.into_iter().filter(|node| node.node_class == NodeClass::Variable).collect();
+        let found = variables.len();
+        let added = variables.into_iter().filter(|node| tag_mapping.add_discovered(node.node_id.clone(), node.name.clone())).count();
+        println!("Discovered {} variables, {} of them not in the configured tags", found, added);
+    }
+    Ok((session, tag_mapping))
+}
+
+/// A change of the connection to the OPC UA server.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionEvent {
+    pub schema_version: u32,
+    /// `connected`, `disconnected` or `reconnected`.
+    pub event: &'static str,
+    pub endpoint: String,
+    pub timestamp: String,
+    /// Failed connection attempts before this event.
+    pub attempts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disconnected_at: Option<String>,
+    /// Seconds without data from the server.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gap_seconds: Option<f64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub restored_tags: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub failed_tags: Vec<String>,
+}
+
+impl ConnectionEvent {
+    pub fn new(event: &'static str, endpoint: &str, attempts: u32) -> ConnectionEvent {
+        ConnectionEvent {
+            schema_version: SCHEMA_VERSION,
+            event,
+            endpoint: endpoint.to_string(),
+            timestamp: format_time(chrono::Utc::now()),
+            attempts,
+            disconnected_at: None,
+            gap_seconds: None,
+            restored_tags: Vec::new(),
+            failed_tags: Vec::new(),
+        }
+    }
+
+    /// Records the data gap since the connection was lost.
+    pub fn with_gap(mut self, disconnected_at: chrono::DateTime<chrono::Utc>) -> ConnectionEvent {
+        let gap = chrono::Utc::now() - disconnected_at;
+        self.disconnected_at = Some(format_time(disconnected_at));
+        self.gap_seconds = Some(gap.num_milliseconds() as f64 / 1000.0);
+        self
+    }
+}
+
+fn format_time(time: chrono::DateTime<chrono::Utc>) -> String {
+    time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn backoff(max_attempts: Option<u32>, jitter: f64) -> Backoff {
+        Backoff::new(ReconnectConfig {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            max_attempts,
+            jitter,
+        })
+    }
+
+    fn delays(backoff: &mut Backoff, count: usize) -> Vec<u64> {
+        (0..count).map(|_| backoff.next_delay().unwrap().as_millis() as u64).collect()
+    }
+
+    #[test]
+    fn doubles_up_to_the_max_delay() {
+        let mut backoff = backoff(None, 0.0);
+        assert_eq!(delays(&mut backoff, 6), [100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(backoff.attempt(), 6);
+        // Far beyond the shift range of the multiplier
+        backoff.attempt = 1000;
+        assert_eq!(backoff.next_delay(), Some(Duration::from_millis(1000)));
+    }
+
+    #[test]
+    fn jitter_only_shortens_the_delay() {
+        let mut backoff = backoff(None, 0.2);
+        for _ in 0..100 {
+            backoff.reset();
+            for expected in [100, 200, 400, 800, 1000] {
+                let delay = backoff.next_delay().unwrap();
+                assert!(delay <= Duration::from_millis(expected), "{:?} > {}ms", delay, expected);
+                assert!(delay >= Duration::from_millis(expected * 8 / 10), "{:?} < 0.8 * {}ms", delay, expected);
+            }
+        }
+        let delays: Vec<Duration> = (0..20).map(|_| {
+            backoff.reset();
+            backoff.next_delay().unwrap()
+        }).collect();
+        assert!(delays.iter().any(|delay| *delay != delays[0]), "the jitter never changed the delay");
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let mut backoff = backoff(Some(3), 0.0);
+        assert_eq!(delays(&mut backoff, 3), [100, 200, 400]);
+        assert_eq!(backoff.next_delay(), None);
+        assert_eq!(backoff.next_delay(), None);
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut backoff = backoff(Some(2), 0.0);
+        assert_eq!(delays(&mut backoff, 2), [100, 200]);
+        assert_eq!(backoff.next_delay(), None);
+        backoff.reset();
+        assert_eq!(backoff.attempt(), 0);
+        assert_eq!(delays(&mut backoff, 2), [100, 200]);
+    }
+}
@@ -0,0 +1,86 @@
+//! Records that cannot be published, kept for operators to inspect and replay.
+//!
+//! A sample that cannot be serialized, a record larger than `KAFKA_MAX_MESSAGE_BYTES`, or a
+//! record the broker refuses, for example because its topic is invalid, becomes a
+//! [`DeadLetter`] at once, as sending it again would fail the same way. It is published as
+//! JSON to `KAFKA_DEAD_LETTER_TOPIC` or, when that is not set or Kafka cannot be reached,
+//! appended as a JSON line to `DEAD_LETTER_FILE` (`dead-letter.jsonl` by default).
+//!
+//! The original payload is kept as text when it is valid UTF-8, such as a JSON sample, and in
+//! base64 otherwise, so it can be sent to `topic` again unchanged.
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+use opcua::types::ByteString;
+use serde::Serialize;
+
+use crate::sample::SCHEMA_VERSION;
+use crate::spool::SpooledRecord;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeadLetter {
+    pub schema_version: u32,
+    /// The topic the record was meant for.
+    pub topic: String,
+    pub key: String,
+    pub partition: i32,
+    /// The record headers, values that are not UTF-8 are replaced lossily.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_base64: Option<String>,
+    /// Why the record could not be published.
+    pub error: String,
+    /// Sends retried after the first one failed.
+    pub retries: u32,
+    pub failed_at: String,
+}
+
+impl DeadLetter {
+    pub fn new(topic: &str, key: &[u8], payload: &[u8], partition: i32, error: String, retries: u32) -> DeadLetter {
+        let (payload, payload_base64) = match std::str::from_utf8(payload) {
+            Ok(payload) => (Some(payload.to_string()), None),
+            Err(_) => (None, Some(ByteString::from(payload.to_vec()).as_base64())),
+        };
+        DeadLetter {
+            schema_version: SCHEMA_VERSION,
+            topic: topic.to_string(),
+            key: String::from_utf8_lossy(key).into_owned(),
+            partition,
+            headers: BTreeMap::new(),
+            payload,
+            payload_base64,
+            error,
+            retries,
+            failed_at: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        }
+    }
+
+    pub fn from_record(record: &SpooledRecord, error: String, retries: u32) -> DeadLetter {
+        DeadLetter::new(&record.topic, &record.key, &record.value, record.partition, error, retries)
+            .with_headers(&record.headers)
+    }
+
+    pub fn with_headers(mut self, headers: &[(String, Vec<u8>)]) -> DeadLetter {
+        self.headers = headers.iter()
+            .map(|(name, value)| (name.clone(), String::from_utf8_lossy(value).into_owned()))
+            .collect();
+        self
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec(self)
+    }
+}
+
+/// Appends a dead letter as one JSON line to `path`.
+pub fn append_to_file(path: &Path, dead_letter: &DeadLetter) -> io::Result<()> {
+    let mut line = dead_letter.to_json().map_err(io::Error::other)?;
+    line.push(b'\n');
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(&line)?;
+    file.sync_data()
+}
@@ -0,0 +1,580 @@
+//! OPC UA event subscriptions, such as alarms and conditions, loaded from the TOML file named
+//! by `EVENTS_FILE`.
+//!
+//! ```toml
+//! # Call ConditionRefresh after every (re)connect, so the current alarm states are published
+//! condition_refresh = true
+//!
+//! [[event]]
+//! name = "line1-alarms"
+//! # The notifier to monitor, the Server object (i=2253) by default
+//! source = "nsu=urn:plant:line1;s=Line1"
+//! topic = "dcs.alarms"
+//! min_severity = 500
+//! # Only these event types, e.g. AlarmConditionType
+//! event_types = ["i=2915"]
+//! source_names = ["Dryer1", "Dryer2"]
+//! source_nodes = ["ns=2;s=Dryer3"]
+//! # More fields to select, browse paths from BaseEventType, "2:Name" for other namespaces
+//! fields = ["Quality", "LimitState/CurrentState"]
+//! ```
+//!
+//! Every `[[event]]` is its own subscription with one event monitored item. The where clause
+//! is the AND of the filters that are set: `Severity >= min_severity`, the event is of one of
+//! `event_types` (or a subtype), `SourceName` is one of `source_names` and `SourceNode` is one
+//! of `source_nodes`.
+//!
+//! Each event is published as an [`AlarmMessage`] to the Kafka `topic` (`dcs.alarms` by
+//! default), keyed by the condition id, or the source node for events that are not
+//! conditions:
+//!
+//! ```json
+//! {
+//!   "schema_version": 1,
+//!   "endpoint": "opc.tcp://plc1:4840",
+//!   "subscription": "line1-alarms",
+//!   "event_id": "AAECAwQ=",
+//!   "event_type": "i=9341",
+//!   "source_node": "ns=2;s=Dryer1",
+//!   "source_name": "Dryer1",
+//!   "time": "2024-05-01T10:00:00.123Z",
+//!   "receive_time": "2024-05-01T10:00:00.125Z",
+//!   "message": "Dryer1 temperature high",
+//!   "severity": 700,
+//!   "condition": {
+//!     "condition_id": "ns=2;s=Dryer1.HighTemp",
+//!     "condition_name": "HighTemp",
+//!     "retain": true,
+//!     "enabled": true,
+//!     "active": true,
+//!     "acked": false,
+//!     "confirmed": false
+//!   },
+//!   "refresh": false,
+//!   "fields": { "Quality": { "code": 0, "name": "Good" } }
+//! }
+//! ```
+//!
+//! Fields the server does not return are left out. `refresh` is true for the condition states
+//! sent in answer to ConditionRefresh, the RefreshStart and RefreshEnd events themselves are
+//! not published.
+use std::collections::BTreeMap;
+use std::fs;
+use opcua::client::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::node_id::{Namespace, NodeIdSpec};
+use crate::sample::{date_time_to_string, variant_to_json, SCHEMA_VERSION};
+
+const DEFAULT_TOPIC: &str = "dcs.alarms";
+
+/// The fields selected for every event, in the order they are returned.
+const SELECT_CLAUSES: [(ObjectTypeId, &str); 16] = [
+    (ObjectTypeId::BaseEventType, "EventId"),
+    (ObjectTypeId::BaseEventType, "EventType"),
+    (ObjectTypeId::BaseEventType, "SourceNode"),
+    (ObjectTypeId::BaseEventType, "SourceName"),
+    (ObjectTypeId::BaseEventType, "Time"),
+    (ObjectTypeId::BaseEventType, "ReceiveTime"),
+    (ObjectTypeId::BaseEventType, "Message"),
+    (ObjectTypeId::BaseEventType, "Severity"),
+    // The NodeId attribute of the condition itself
+    (ObjectTypeId::ConditionType, ""),
+    (ObjectTypeId::ConditionType, "ConditionName"),
+    (ObjectTypeId::ConditionType, "BranchId"),
+    (ObjectTypeId::ConditionType, "Retain"),
+    (ObjectTypeId::ConditionType, "EnabledState/Id"),
+    (ObjectTypeId::AlarmConditionType, "ActiveState/Id"),
+    (ObjectTypeId::AcknowledgeableConditionType, "AckedState/Id"),
+    (ObjectTypeId::AcknowledgeableConditionType, "ConfirmedState/Id"),
+];
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EventEntry {
+    name: String,
+    source: Option<String>,
+    topic: Option<String>,
+    min_severity: Option<u16>,
+    #[serde(default)]
+    event_types: Vec<String>,
+    #[serde(default)]
+    source_names: Vec<String>,
+    #[serde(default)]
+    source_nodes: Vec<String>,
+    #[serde(default)]
+    fields: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EventFile {
+    #[serde(default)]
+    condition_refresh: bool,
+    #[serde(default)]
+    event: Vec<EventEntry>,
+}
+
+/// A validated `[[event]]`, before its namespaces are resolved.
+#[derive(Debug, Clone)]
+pub struct EventConfig {
+    pub name: String,
+    pub source: NodeIdSpec,
+    pub topic: String,
+    pub min_severity: Option<u16>,
+    pub event_types: Vec<NodeIdSpec>,
+    pub source_names: Vec<String>,
+    pub source_nodes: Vec<NodeIdSpec>,
+    pub fields: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct EventList {
+    pub condition_refresh: bool,
+    pub events: Vec<EventConfig>,
+}
+
+fn parse_node_ids(values: &[String]) -> Result<Vec<NodeIdSpec>, String> {
+    values.iter().map(|value| NodeIdSpec::parse(value)).collect()
+}
+
+impl EventEntry {
+    fn validate(self) -> Result<EventConfig, String> {
+        if self.name.trim().is_empty() {
+            return Err("has an empty name".to_string());
+        }
+        if self.fields.iter().any(|field| field.trim().is_empty()) {
+            return Err("has an empty field".to_string());
+        }
+        Ok(EventConfig {
+            source: match self.source {
+                Some(source) => NodeIdSpec::parse(&source)?,
+                None => NodeIdSpec { namespace: Namespace::Index(0), identifier: Identifier::Numeric(ObjectId::Server as u32) },
+            },
+            topic: self.topic.filter(|topic| !topic.trim().is_empty()).unwrap_or_else(|| DEFAULT_TOPIC.to_string()),
+            min_severity: self.min_severity,
+            event_types: parse_node_ids(&self.event_types)?,
+            source_names: self.source_names,
+            source_nodes: parse_node_ids(&self.source_nodes)?,
+            fields: self.fields,
+            name: self.name,
+        })
+    }
+}
+
+impl EventList {
+    pub fn load(path: &str) -> Result<EventList, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("cannot read events file {}: {}", path, err))?;
+        let file: EventFile = toml::from_str(&contents)
+            .map_err(|err| format!("invalid events file {}: {}", path, err))?;
+        let mut events = Vec::with_capacity(file.event.len());
+        for (index, entry) in file.event.into_iter().enumerate() {
+            let name = entry.name.clone();
+            events.push(entry.validate()
+                .map_err(|err| format!("events file {}: event {} (\"{}\") {}", path, index + 1, name, err))?);
+        }
+        Ok(EventList { condition_refresh: file.condition_refresh, events })
+    }
+
+    pub fn has_namespace_uris(&self) -> bool {
+        self.events.iter().any(|event| event.source.has_namespace_uri()
+            || event.event_types.iter().chain(&event.source_nodes).any(NodeIdSpec::has_namespace_uri))
+    }
+}
+
+fn attribute_operand(type_definition: ObjectTypeId, path: &str, attribute_id: AttributeId) -> SimpleAttributeOperand {
+    let browse_path: Vec<QualifiedName> = path.split('/')
+        .filter(|name| !name.is_empty())
+        .map(|name| match name.split_once(':').and_then(|(namespace, name)| Some((namespace.parse::<u16>().ok()?, name))) {
+            Some((namespace, name)) => QualifiedName::new(namespace, name),
+            None => QualifiedName::new(0, name),
+        })
+        .collect();
+    SimpleAttributeOperand {
+        type_definition_id: type_definition.into(),
+        browse_path: if browse_path.is_empty() { None } else { Some(browse_path) },
+        attribute_id: attribute_id as u32,
+        index_range: UAString::null(),
+    }
+}
+
+fn attribute(type_definition: ObjectTypeId, path: &str) -> ExtensionObject {
+    ExtensionObject::from_encodable(ObjectId::SimpleAttributeOperand_Encoding_DefaultBinary,
+        &attribute_operand(type_definition, path, AttributeId::Value))
+}
+
+fn literal(value: Variant) -> ExtensionObject {
+    ExtensionObject::from_encodable(ObjectId::LiteralOperand_Encoding_DefaultBinary, &LiteralOperand { value })
+}
+
+/// A where clause before it is flattened into content filter elements.
+enum Condition {
+    Element(FilterOperator, Vec<ExtensionObject>),
+    All(Vec<Condition>),
+    Any(Vec<Condition>),
+}
+
+/// Adds the elements of `condition` and returns the index of its root element.
+fn add_condition(elements: &mut Vec<ContentFilterElement>, condition: Condition) -> u32 {
+    let (all, mut conditions) = match condition {
+        Condition::Element(filter_operator, operands) => {
+            elements.push(ContentFilterElement { filter_operator, filter_operands: Some(operands) });
+            return (elements.len() - 1) as u32;
+        }
+        Condition::All(conditions) => (true, conditions),
+        Condition::Any(conditions) => (false, conditions),
+    };
+    if conditions.len() == 1 {
+        return add_condition(elements, conditions.remove(0));
+    }
+    // And and Or take two operands, so longer lists are nested to the right
+    let index = elements.len();
+    let filter_operator = if all { FilterOperator::And } else { FilterOperator::Or };
+    elements.push(ContentFilterElement { filter_operator, filter_operands: None });
+    let first = conditions.remove(0);
+    let left = add_condition(elements, first);
+    let rest = if all { Condition::All(conditions) } else { Condition::Any(conditions) };
+    let right = add_condition(elements, rest);
+    let element = |index| ExtensionObject::from_encodable(ObjectId::ElementOperand_Encoding_DefaultBinary, &ElementOperand { index });
+    elements[index].filter_operands = Some(vec![element(left), element(right)]);
+    index as u32
+}
+
+impl EventConfig {
+    /// The event monitored item on the source, with the select and where clauses.
+    pub fn create_request(&self, namespaces: &[String]) -> Result<MonitoredItemCreateRequest, String> {
+        let mut conditions = Vec::new();
+        if let Some(min_severity) = self.min_severity {
+            conditions.push(Condition::Element(FilterOperator::GreaterThanOrEqual,
+                vec![attribute(ObjectTypeId::BaseEventType, "Severity"), literal(Variant::UInt16(min_severity))]));
+        }
+        if !self.event_types.is_empty() {
+            let mut event_types = Vec::with_capacity(self.event_types.len());
+            for event_type in &self.event_types {
+                let event_type = event_type.resolve(namespaces)?;
+                event_types.push(Condition::Element(FilterOperator::OfType, vec![literal(Variant::from(event_type))]));
+            }
+            conditions.push(Condition::Any(event_types));
+        }
+        if !self.source_names.is_empty() {
+            let mut operands = vec![attribute(ObjectTypeId::BaseEventType, "SourceName")];
+            operands.extend(self.source_names.iter().map(|name| literal(Variant::from(name.as_str()))));
+            conditions.push(Condition::Element(FilterOperator::InList, operands));
+        }
+        if !self.source_nodes.is_empty() {
+            let mut operands = vec![attribute(ObjectTypeId::BaseEventType, "SourceNode")];
+            for source_node in &self.source_nodes {
+                operands.push(literal(Variant::from(source_node.resolve(namespaces)?)));
+            }
+            conditions.push(Condition::Element(FilterOperator::InList, operands));
+        }
+        let mut elements = Vec::new();
+        if !conditions.is_empty() {
+            add_condition(&mut elements, Condition::All(conditions));
+        }
+        let select_clauses = SELECT_CLAUSES.iter()
+            .map(|(type_definition, path)| {
+                let attribute_id = if path.is_empty() { AttributeId::NodeId } else { AttributeId::Value };
+                attribute_operand(*type_definition, path, attribute_id)
+            })
+            .chain(self.fields.iter().map(|field| attribute_operand(ObjectTypeId::BaseEventType, field, AttributeId::Value)))
+            .collect();
+        let filter = EventFilter {
+            select_clauses: Some(select_clauses),
+            where_clause: ContentFilter { elements: if elements.is_empty() { None } else { Some(elements) } },
+        };
+        Ok(MonitoredItemCreateRequest {
+            item_to_monitor: ReadValueId {
+                node_id: self.source.resolve(namespaces)?,
+                attribute_id: AttributeId::EventNotifier as u32,
+                index_range: UAString::null(),
+                data_encoding: QualifiedName::null(),
+            },
+            monitoring_mode: MonitoringMode::Reporting,
+            requested_parameters: MonitoringParameters {
+                client_handle: 0,
+                sampling_interval: 0.0,
+                filter: ExtensionObject::from_encodable(ObjectId::EventFilter_Encoding_DefaultBinary, &filter),
+                queue_size: 1000,
+                discard_oldest: true,
+            },
+        })
+    }
+}
+
+/// An event as published to Kafka.
+#[derive(Debug, Clone, Serialize)]
+pub struct AlarmMessage {
+    pub schema_version: u32,
+    pub endpoint: String,
+    /// The `name` of the `[[event]]`.
+    pub subscription: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_node: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub receive_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub severity: Option<u16>,
+    /// Set for events of conditions.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub condition: Option<ConditionState>,
+    /// Sent in answer to ConditionRefresh.
+    pub refresh: bool,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConditionState {
+    pub condition_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub condition_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub branch_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retain: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub active: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acked: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confirmed: Option<bool>,
+}
+
+/// Whether an event starts (`Some(true)`) or ends (`Some(false)`) a ConditionRefresh.
+pub fn refresh_marker(fields: &[Variant]) -> Option<bool> {
+    let Some(Variant::NodeId(event_type)) = fields.get(1) else {
+        return None;
+    };
+    let start: NodeId = ObjectTypeId::RefreshStartEventType.into();
+    let end: NodeId = ObjectTypeId::RefreshEndEventType.into();
+    if **event_type == start {
+        Some(true)
+    } else if **event_type == end {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+fn text(field: Option<&Variant>) -> Option<String> {
+    match field? {
+        Variant::String(value) => value.value().clone(),
+        Variant::LocalizedText(value) => value.text.value().clone(),
+        Variant::QualifiedName(value) => value.name.value().clone(),
+        Variant::NodeId(value) if !value.is_null() => Some(value.to_string()),
+        Variant::ByteString(value) if !value.is_null() => Some(value.as_base64()),
+        Variant::DateTime(value) => date_time_to_string(value),
+        _ => None,
+    }
+}
+
+fn boolean(field: Option<&Variant>) -> Option<bool> {
+    match field? {
+        Variant::Boolean(value) => Some(*value),
+        _ => None,
+    }
+}
+
+impl AlarmMessage {
+    /// Maps the fields of an event, selected in [`SELECT_CLAUSES`] order followed by the
+    /// configured `fields`.
+    pub fn new(endpoint: &str, config: &EventConfig, fields: &[Variant], refresh: bool) -> AlarmMessage {
+        let field = |index: usize| fields.get(index);
+        let condition = text(field(8)).map(|condition_id| ConditionState {
+            condition_id,
+            condition_name: text(field(9)),
+            branch_id: text(field(10)),
+            retain: boolean(field(11)),
+            enabled: boolean(field(12)),
+            active: boolean(field(13)),
+            acked: boolean(field(14)),
+            confirmed: boolean(field(15)),
+        });
+        AlarmMessage {
+            schema_version: SCHEMA_VERSION,
+            endpoint: endpoint.to_string(),
+            subscription: config.name.clone(),
+            event_id: text(field(0)),
+            event_type: text(field(1)),
+            source_node: text(field(2)),
+            source_name: text(field(3)),
+            time: text(field(4)),
+            receive_time: text(field(5)),
+            message: text(field(6)),
+            severity: match field(7) {
+                Some(Variant::UInt16(severity)) => Some(*severity),
+                _ => None,
+            },
+            condition,
+            refresh,
+            fields: config.fields.iter().enumerate()
+                .filter_map(|(index, name)| match field(SELECT_CLAUSES.len() + index) {
+                    None | Some(Variant::Empty) => None,
+                    Some(value) => Some((name.clone(), variant_to_json(value))),
+                })
+                .collect(),
+        }
+    }
+
+    /// The Kafka key: the condition, else the source node, else the endpoint.
+    pub fn key(&self) -> &str {
+        self.condition.as_ref().map(|condition| condition.condition_id.as_str())
+            .or(self.source_node.as_deref())
+            .unwrap_or(&self.endpoint)
+    }
+}
+
+/// Asks the server to send the current state of every condition to the subscription.
+pub fn condition_refresh(session: &Session, subscription_id: u32) -> Result<(), StatusCode> {
+    let result = session.call(CallMethodRequest {
+        object_id: ObjectTypeId::ConditionType.into(),
+        method_id: MethodId::ConditionType_ConditionRefresh.into(),
+        input_arguments: Some(vec![Variant::UInt32(subscription_id)]),
+    })?;
+    if result.status_code.is_bad() {
+        return Err(result.status_code);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn element_operands(element: &ContentFilterElement) -> Vec<u32> {
+        element.filter_operands.as_ref().unwrap().iter()
+            .map(|operand| operand.decode_inner::<ElementOperand>(&DecodingOptions::default()).unwrap().index)
+            .collect()
+    }
+
+    #[test]
+    fn nests_where_clause_conditions() {
+        let severity = || Condition::Element(FilterOperator::GreaterThanOrEqual, vec![literal(Variant::UInt16(500))]);
+        let of_type = || Condition::Element(FilterOperator::OfType, vec![literal(Variant::from(NodeId::from(ObjectTypeId::AlarmConditionType)))]);
+        let mut elements = Vec::new();
+        let root = add_condition(&mut elements, Condition::All(vec![severity(), Condition::Any(vec![of_type(), of_type()]), severity()]));
+        assert_eq!(root, 0);
+        let operators: Vec<FilterOperator> = elements.iter().map(|element| element.filter_operator).collect();
+        assert_eq!(operators, [FilterOperator::And, FilterOperator::GreaterThanOrEqual, FilterOperator::And,
+            FilterOperator::Or, FilterOperator::OfType, FilterOperator::OfType, FilterOperator::GreaterThanOrEqual]);
+        assert_eq!(element_operands(&elements[0]), [1, 2]);
+        assert_eq!(element_operands(&elements[2]), [3, 6]);
+        assert_eq!(element_operands(&elements[3]), [4, 5]);
+    }
+
+    fn literal_value(operand: &ExtensionObject) -> Variant {
+        operand.decode_inner::<LiteralOperand>(&DecodingOptions::default()).unwrap().value
+    }
+
+    fn entry() -> EventEntry {
+        EventEntry {
+            name: "alarms".to_string(),
+            source: None,
+            topic: None,
+            min_severity: None,
+            event_types: Vec::new(),
+            source_names: Vec::new(),
+            source_nodes: Vec::new(),
+            fields: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn builds_the_event_filter_from_the_config() {
+        let namespaces = ["http://opcfoundation.org/UA/".to_string(), "urn:plant:line1".to_string()];
+        let config = EventEntry {
+            source: Some("nsu=urn:plant:line1;s=Line1".to_string()),
+            min_severity: Some(500),
+            event_types: vec!["i=2915".to_string(), "nsu=urn:plant:line1;i=5000".to_string()],
+            source_names: vec!["Dryer1".to_string(), "Dryer2".to_string()],
+            source_nodes: vec!["nsu=urn:plant:line1;s=Dryer3".to_string()],
+            fields: vec!["Quality".to_string(), "2:Limits/LimitState".to_string()],
+            ..entry()
+        }.validate().unwrap();
+        let request = config.create_request(&namespaces).unwrap();
+        assert_eq!(request.item_to_monitor.node_id, NodeId::new(1, "Line1"));
+        assert_eq!(request.item_to_monitor.attribute_id, AttributeId::EventNotifier as u32);
+        let filter = request.requested_parameters.filter.decode_inner::<EventFilter>(&DecodingOptions::default()).unwrap();
+
+        let select_clauses = filter.select_clauses.unwrap();
+        assert_eq!(select_clauses.len(), SELECT_CLAUSES.len() + 2);
+        assert_eq!(select_clauses[8].attribute_id, AttributeId::NodeId as u32);
+        assert_eq!(select_clauses[8].browse_path, None);
+        assert_eq!(select_clauses[13].type_definition_id, NodeId::from(ObjectTypeId::AlarmConditionType));
+        assert_eq!(select_clauses[13].browse_path, Some(vec![QualifiedName::new(0, "ActiveState"), QualifiedName::new(0, "Id")]));
+        assert_eq!(select_clauses[16].browse_path, Some(vec![QualifiedName::new(0, "Quality")]));
+        assert_eq!(select_clauses[17].browse_path, Some(vec![QualifiedName::new(2, "Limits"), QualifiedName::new(0, "LimitState")]));
+        assert_eq!(select_clauses[17].attribute_id, AttributeId::Value as u32);
+
+        // Severity AND (type 2915 OR type 5000) AND SourceName in list AND SourceNode in list
+        let elements = filter.where_clause.elements.unwrap();
+        let operators: Vec<FilterOperator> = elements.iter().map(|element| element.filter_operator).collect();
+        assert_eq!(operators, [FilterOperator::And, FilterOperator::GreaterThanOrEqual, FilterOperator::And,
+            FilterOperator::Or, FilterOperator::OfType, FilterOperator::OfType, FilterOperator::And,
+            FilterOperator::InList, FilterOperator::InList]);
+        let operands = |index: usize| elements[index].filter_operands.as_ref().unwrap();
+        assert_eq!(literal_value(&operands(1)[1]), Variant::UInt16(500));
+        assert_eq!(literal_value(&operands(4)[0]), Variant::from(NodeId::from(ObjectTypeId::AlarmConditionType)));
+        assert_eq!(literal_value(&operands(5)[0]), Variant::from(NodeId::new(1, 5000u32)));
+        let names: Vec<Variant> = operands(7)[1..].iter().map(literal_value).collect();
+        assert_eq!(names, [Variant::from("Dryer1"), Variant::from("Dryer2")]);
+        assert_eq!(literal_value(&operands(8)[1]), Variant::from(NodeId::new(1, "Dryer3")));
+    }
+
+    #[test]
+    fn leaves_the_where_clause_out_without_filters() {
+        let request = entry().validate().unwrap().create_request(&[]).unwrap();
+        assert_eq!(request.item_to_monitor.node_id, NodeId::from(ObjectId::Server));
+        let filter = request.requested_parameters.filter.decode_inner::<EventFilter>(&DecodingOptions::default()).unwrap();
+        assert_eq!(filter.where_clause.elements, None);
+        assert_eq!(filter.select_clauses.unwrap().len(), SELECT_CLAUSES.len());
+
+        let unknown = EventEntry { source_nodes: vec!["nsu=urn:other;s=Dryer3".to_string()], ..entry() }.validate().unwrap();
+        assert!(unknown.create_request(&["http://opcfoundation.org/UA/".to_string()]).is_err());
+    }
+
+    #[test]
+    fn detects_condition_refresh_markers() {
+        let event = |event_type: ObjectTypeId| vec![Variant::Empty, Variant::from(NodeId::from(event_type))];
+        assert_eq!(refresh_marker(&event(ObjectTypeId::RefreshStartEventType)), Some(true));
+        assert_eq!(refresh_marker(&event(ObjectTypeId::RefreshEndEventType)), Some(false));
+        assert_eq!(refresh_marker(&event(ObjectTypeId::AlarmConditionType)), None);
+        assert_eq!(refresh_marker(&[Variant::Empty]), None);
+    }
+
+    #[test]
+    fn maps_condition_state() {
+        let config = EventEntry { fields: vec!["Quality".to_string()], ..entry() }.validate().unwrap();
+        let mut fields = vec![Variant::Empty; SELECT_CLAUSES.len()];
+        fields[2] = Variant::from(NodeId::new(2, "Dryer1"));
+        fields[6] = Variant::from(LocalizedText::new("", "Dryer1 temperature high"));
+        fields[7] = Variant::UInt16(700);
+        fields[8] = Variant::from(NodeId::new(2, "Dryer1.HighTemp"));
+        fields[13] = Variant::Boolean(true);
+        fields[14] = Variant::Boolean(false);
+        fields.push(Variant::StatusCode(StatusCode::Good));
+        let message = AlarmMessage::new("opc.tcp://plc1:4840", &config, &fields, false);
+        assert_eq!(message.key(), "ns=2;s=Dryer1.HighTemp");
+        assert_eq!(message.message.as_deref(), Some("Dryer1 temperature high"));
+        assert_eq!(message.severity, Some(700));
+        let condition = message.condition.unwrap();
+        assert_eq!((condition.active, condition.acked, condition.confirmed), (Some(true), Some(false), None));
+        assert_eq!(message.fields["Quality"]["name"], "Good");
+        assert_eq!(config.topic, DEFAULT_TOPIC);
+    }
+}
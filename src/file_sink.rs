@@ -0,0 +1,557 @@
+//! Rolling file output, for lines without a network connection to the data center.
+//!
+//! | Variable                     | Default   | Meaning                                          |
+//! |------------------------------|-----------|--------------------------------------------------|
+//! | `FILE_SINK_DIR`              |           | directory of the files, enables the file output  |
+//! | `FILE_SINK_FORMAT`           | `csv`     | `csv` or `parquet`                               |
+//! | `FILE_SINK_MAX_BYTES`        | `67108864` | size after which a new file is started          |
+//! | `FILE_SINK_MAX_AGE_SECS`     | `3600`    | age after which a new file is started            |
+//! | `FILE_SINK_ROW_GROUP_SIZE`   | `10000`   | `parquet`: rows buffered per row group           |
+//! | `FILE_SINK_QUEUE_CAPACITY`   | `10000`   | samples waiting for the writer at most, newer ones are dropped |
+//!
+//! Files are named `<instance id>-<UTC start time>.csv` or `.parquet` and carry a `.partial`
+//! suffix while they are written, so only complete files are collected. A `.partial` CSV file
+//! left by a crash is readable up to its last line, a `.partial` Parquet file is not.
+//!
+//! Both formats have one row per sample with the fields of the [`crate::sample`] envelope:
+//!
+//! | Column             | Parquet type      | Content                                        |
+//! |--------------------|-------------------|------------------------------------------------|
+//! | `schema_version`   | `int32`           |                                                |
+//! | `endpoint`         | `string`          |                                                |
+//! | `node_id`          | `string`          |                                                |
+//! | `tag`              | `string`          |                                                |
+//! | `alias`            | optional `string` |                                                |
+//! | `asset_id`         | optional `string` |                                                |
+//! | `unit`             | optional `string` |                                                |
+//! | `value_type`       | `string`          |                                                |
+//! | `value`            | optional `string` | the JSON text of `value`, empty for `null`     |
+//! | `array_dimensions` | optional `string` | the JSON text of `array_dimensions`            |
+//! | `status_code`      | `int64`           | `status.code`                                  |
+//! | `status_name`      | `string`          | `status.name`                                  |
+//! | `source_timestamp` | optional `string` | RFC 3339                                       |
+//! | `server_timestamp` | optional `string` | RFC 3339                                       |
+//! | `backfilled`       | `boolean`         | `true` when read from the server history       |
+//!
+//! The size of a Parquet file grows with every written row group, so it is checked against
+//! `FILE_SINK_MAX_BYTES` once per row group.
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+use dotenvy::var;
+use parquet::basic::Compression;
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, Int32Type, Int64Type};
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{SerializedColumnWriter, SerializedFileWriter};
+use parquet::schema::parser::parse_message_type;
+use serde_json::Value;
+
+use crate::client_config::parse_u64;
+use crate::sample::TagSample;
+
+const COLUMNS: [&str; 15] = [
+    "schema_version", "endpoint", "node_id", "tag", "alias", "asset_id", "unit", "value_type",
+    "value", "array_dimensions", "status_code", "status_name", "source_timestamp", "server_timestamp",
+    "backfilled",
+];
+
+const PARQUET_SCHEMA: &str = "message tag_sample {
+    required int32 schema_version;
+    required binary endpoint (STRING);
+    required binary node_id (STRING);
+    required binary tag (STRING);
+    optional binary alias (STRING);
+    optional binary asset_id (STRING);
+    optional binary unit (STRING);
+    required binary value_type (STRING);
+    optional binary value (STRING);
+    optional binary array_dimensions (STRING);
+    required int64 status_code;
+    required binary status_name (STRING);
+    optional binary source_timestamp (STRING);
+    optional binary server_timestamp (STRING);
+    required boolean backfilled;
+}";
+
+/// How often written CSV lines are flushed and the file age is checked.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileFormat {
+    Csv,
+    Parquet,
+}
+
+impl FileFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            FileFormat::Csv => "csv",
+            FileFormat::Parquet => "parquet",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileSinkConfig {
+    pub dir: PathBuf,
+    pub format: FileFormat,
+    pub max_bytes: u64,
+    pub max_age: Duration,
+    pub row_group_size: usize,
+    pub queue_capacity: usize,
+    pub instance_id: String,
+}
+
+impl FileSinkConfig {
+    /// The file output settings, `None` when `FILE_SINK_DIR` is not set.
+    pub fn from_env(instance_id: &str) -> Result<Option<FileSinkConfig>, String> {
+        let Ok(dir) = var("FILE_SINK_DIR") else {
+            return Ok(None);
+        };
+        let format = match var("FILE_SINK_FORMAT").as_deref().map(str::trim) {
+            Ok("csv") | Err(_) => FileFormat::Csv,
+            Ok("parquet") => FileFormat::Parquet,
+            Ok(other) => return Err(format!("FILE_SINK_FORMAT \"{}\" must be csv or parquet", other)),
+        };
+        Ok(Some(FileSinkConfig {
+            dir: PathBuf::from(dir),
+            format,
+            max_bytes: parse_u64("FILE_SINK_MAX_BYTES", 64 * 1024 * 1024)?.max(1),
+            max_age: Duration::from_secs(parse_u64("FILE_SINK_MAX_AGE_SECS", 3600)?.max(1)),
+            row_group_size: parse_u64("FILE_SINK_ROW_GROUP_SIZE", 10_000)?.max(1) as usize,
+            queue_capacity: parse_u64("FILE_SINK_QUEUE_CAPACITY", 10_000)?.max(1) as usize,
+            instance_id: instance_id.replace(['/', '\\'], "_"),
+        }))
+    }
+}
+
+/// The sending side of the file output, cheap to clone into callbacks.
+#[derive(Clone)]
+pub struct FileSinkHandle {
+    sender: SyncSender<TagSample>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl FileSinkHandle {
+    /// Queues the samples without waiting for the disk.
+    pub fn publish(&self, samples: &[TagSample]) -> Result<(), String> {
+        let mut dropped = 0;
+        for sample in samples {
+            match self.sender.try_send(sample.clone()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => dropped += 1,
+                Err(TrySendError::Disconnected(_)) => return Err("the file writer has stopped".to_string()),
+            }
+        }
+        if dropped > 0 {
+            self.dropped.fetch_add(dropped, Ordering::Relaxed);
+            return Err(format!("the file queue is full, dropped {} samples", dropped));
+        }
+        Ok(())
+    }
+
+    /// Queues the samples, waiting while the queue is full.
+    pub fn publish_waiting(&self, samples: &[TagSample]) -> Result<(), String> {
+        for sample in samples {
+            self.sender.send(sample.clone()).map_err(|_| "the file writer has stopped".to_string())?;
+        }
+        Ok(())
+    }
+}
+
+/// Creates the directory and starts the thread writing the samples queued on the returned
+/// handle.
+pub fn spawn_file_sink(config: FileSinkConfig) -> Result<FileSinkHandle, String> {
+    fs::create_dir_all(&config.dir)
+        .map_err(|err| format!("cannot create FILE_SINK_DIR {}: {}", config.dir.display(), err))?;
+    let (sender, receiver) = mpsc::sync_channel(config.queue_capacity);
+    let dropped = Arc::new(AtomicU64::new(0));
+    let writer = FileSink { config, file: None };
+    let counter = dropped.clone();
+    thread::spawn(move || writer.run(receiver, counter));
+    Ok(FileSinkHandle { sender, dropped })
+}
+
+struct FileSink {
+    config: FileSinkConfig,
+    file: Option<RollingFile>,
+}
+
+impl FileSink {
+    /// Writes queued samples until every handle is dropped.
+    fn run(mut self, receiver: Receiver<TagSample>, dropped: Arc<AtomicU64>) {
+        let mut last_flush = Instant::now();
+        let mut last_dropped = 0;
+        loop {
+            match receiver.recv_timeout(FLUSH_INTERVAL) {
+                Ok(sample) => self.append(&sample),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.close();
+                    return;
+                }
+            }
+            if self.file.as_ref().is_some_and(|file| file.bytes() >= self.config.max_bytes || file.opened_at.elapsed() >= self.config.max_age) {
+                self.close();
+            }
+            if last_flush.elapsed() >= FLUSH_INTERVAL {
+                if let Some(ref mut file) = self.file
+                    && let Err(err) = file.flush() {
+                    println!("Cannot write {}: {}", file.partial_path.display(), err);
+                    self.file = None;
+                }
+                let dropped = dropped.load(Ordering::Relaxed);
+                if dropped > last_dropped {
+                    println!("File output: {} samples dropped while the queue was full", dropped - last_dropped);
+                    last_dropped = dropped;
+                }
+                last_flush = Instant::now();
+            }
+        }
+    }
+
+    fn append(&mut self, sample: &TagSample) {
+        if self.file.is_none() {
+            match RollingFile::create(&self.config) {
+                Ok(file) => self.file = Some(file),
+                Err(err) => {
+                    println!("{}", err);
+                    return;
+                }
+            }
+        }
+        if let Some(ref mut file) = self.file
+            && let Err(err) = file.append(sample, self.config.row_group_size) {
+            // The file is left as .partial and the next sample starts a new one
+            println!("Cannot write {}: {}", file.partial_path.display(), err);
+            self.file = None;
+        }
+    }
+
+    /// Completes the current file.
+    fn close(&mut self) {
+        if let Some(file) = self.file.take() {
+            let partial_path = file.partial_path.clone();
+            match file.finish() {
+                Ok(path) => println!("File output: completed {}", path.display()),
+                Err(err) => println!("Cannot complete {}: {}", partial_path.display(), err),
+            }
+        }
+    }
+}
+
+/// Counts the bytes written through it.
+struct Counter<W> {
+    inner: W,
+    bytes: u64,
+}
+
+impl<W: Write> Write for Counter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.bytes += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+enum OpenFile {
+    Csv(csv::Writer<Counter<BufWriter<File>>>),
+    Parquet {
+        writer: SerializedFileWriter<BufWriter<File>>,
+        /// Rows of the next row group.
+        rows: Vec<TagSample>,
+    },
+}
+
+struct RollingFile {
+    file: OpenFile,
+    partial_path: PathBuf,
+    opened_at: Instant,
+}
+
+impl RollingFile {
+    fn create(config: &FileSinkConfig) -> Result<RollingFile, String> {
+        let name = format!("{}-{}.{}.partial", config.instance_id,
+            chrono::Utc::now().format("%Y%m%dT%H%M%S%3fZ"), config.format.extension());
+        let partial_path = config.dir.join(name);
+        let out = BufWriter::new(File::create(&partial_path)
+            .map_err(|err| format!("cannot create {}: {}", partial_path.display(), err))?);
+        let file = match config.format {
+            FileFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(Counter { inner: out, bytes: 0 });
+                writer.write_record(COLUMNS)
+                    .map_err(|err| format!("cannot write {}: {}", partial_path.display(), err))?;
+                OpenFile::Csv(writer)
+            }
+            FileFormat::Parquet => {
+                let schema = Arc::new(parse_message_type(PARQUET_SCHEMA).unwrap());
+                let properties = Arc::new(WriterProperties::builder().set_compression(Compression::SNAPPY).build());
+                let writer = SerializedFileWriter::new(out, schema, properties)
+                    .map_err(|err| format!("cannot write {}: {}", partial_path.display(), err))?;
+                OpenFile::Parquet { writer, rows: Vec::with_capacity(config.row_group_size) }
+            }
+        };
+        Ok(RollingFile { file, partial_path, opened_at: Instant::now() })
+    }
+
+    fn bytes(&self) -> u64 {
+        match self.file {
+            OpenFile::Csv(ref writer) => writer.get_ref().bytes,
+            OpenFile::Parquet { ref writer, .. } => writer.bytes_written() as u64,
+        }
+    }
+
+    fn append(&mut self, sample: &TagSample, row_group_size: usize) -> Result<(), String> {
+        match self.file {
+            OpenFile::Csv(ref mut writer) => writer.write_record(columns(sample).map(Option::unwrap_or_default))
+                .map_err(|err| err.to_string()),
+            OpenFile::Parquet { ref mut writer, ref mut rows } => {
+                rows.push(sample.clone());
+                if rows.len() >= row_group_size {
+                    write_row_group(writer, rows).map_err(|err| err.to_string())?;
+                    rows.clear();
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Makes the written CSV lines readable. Parquet rows are written per row group.
+    fn flush(&mut self) -> Result<(), String> {
+        match self.file {
+            OpenFile::Csv(ref mut writer) => writer.flush().map_err(|err| err.to_string()),
+            OpenFile::Parquet { .. } => Ok(()),
+        }
+    }
+
+    /// Writes the remaining rows and renames the file to its final name.
+    fn finish(self) -> Result<PathBuf, String> {
+        match self.file {
+            OpenFile::Csv(writer) => {
+                let mut out = writer.into_inner().map_err(|err| err.to_string())?.inner;
+                out.flush().map_err(|err| err.to_string())?;
+            }
+            OpenFile::Parquet { mut writer, rows } => {
+                if !rows.is_empty() {
+                    write_row_group(&mut writer, &rows).map_err(|err| err.to_string())?;
+                }
+                writer.close().map_err(|err| err.to_string())?;
+            }
+        }
+        let path = self.partial_path.with_extension("");
+        fs::rename(&self.partial_path, &path).map_err(|err| err.to_string())?;
+        Ok(path)
+    }
+}
+
+/// The columns of a sample in [`COLUMNS`] order.
+fn columns(sample: &TagSample) -> [Option<String>; 15] {
+    [
+        Some(sample.schema_version.to_string()),
+        Some(sample.endpoint.clone()),
+        Some(sample.node_id.clone()),
+        Some(sample.tag.clone()),
+        sample.alias.clone(),
+        sample.asset_id.clone(),
+        sample.unit.clone(),
+        Some(sample.value_type.clone()),
+        match sample.value {
+            Value::Null => None,
+            ref value => Some(value.to_string()),
+        },
+        sample.array_dimensions.as_ref().map(|dimensions| Value::from(dimensions.clone()).to_string()),
+        Some(sample.status.code.to_string()),
+        Some(sample.status.name.clone()),
+        sample.source_timestamp.clone(),
+        sample.server_timestamp.clone(),
+        Some(sample.backfilled.to_string()),
+    ]
+}
+
+fn write_row_group(writer: &mut SerializedFileWriter<BufWriter<File>>, rows: &[TagSample]) -> Result<(), ParquetError> {
+    let table: Vec<[Option<String>; 15]> = rows.iter().map(columns).collect();
+    let mut row_group = writer.next_row_group()?;
+    let mut index = 0;
+    while let Some(mut column) = row_group.next_column()? {
+        match index {
+            0 => {
+                let values: Vec<i32> = rows.iter().map(|sample| sample.schema_version as i32).collect();
+                column.typed::<Int32Type>().write_batch(&values, None, None)?;
+            }
+            10 => {
+                let values: Vec<i64> = rows.iter().map(|sample| sample.status.code as i64).collect();
+                column.typed::<Int64Type>().write_batch(&values, None, None)?;
+            }
+            14 => {
+                let values: Vec<bool> = rows.iter().map(|sample| sample.backfilled).collect();
+                column.typed::<BoolType>().write_batch(&values, None, None)?;
+            }
+            _ => write_strings(&mut column, table.iter().map(|row| row[index].clone()))?,
+        }
+        column.close()?;
+        index += 1;
+    }
+    row_group.close()?;
+    Ok(())
+}
+
+/// Writes a string column, with a definition level per row for optional columns.
+fn write_strings(column: &mut SerializedColumnWriter<'_>, values: impl Iterator<Item = Option<String>>) -> Result<(), ParquetError> {
+    let mut definition_levels = Vec::new();
+    let mut present = Vec::new();
+    for value in values {
+        definition_levels.push(value.is_some() as i16);
+        present.extend(value.map(|value| ByteArray::from(value.into_bytes())));
+    }
+    column.typed::<ByteArrayType>().write_batch(&present, Some(&definition_levels), None)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::Field;
+    use serde_json::json;
+
+    fn config(dir: &tempfile::TempDir, format: FileFormat) -> FileSinkConfig {
+        FileSinkConfig {
+            dir: dir.path().to_path_buf(),
+            format,
+            max_bytes: 1 << 20,
+            max_age: Duration::from_secs(3600),
+            row_group_size: 2,
+            queue_capacity: 10,
+            instance_id: "line1".to_string(),
+        }
+    }
+
+    fn samples() -> Vec<TagSample> {
+        let full = serde_json::from_value(json!({
+            "schema_version": 1,
+            "endpoint": "opc.tcp://plc1:4840",
+            "node_id": "ns=2;s=Line1.Temp",
+            "tag": "Line1.Temp",
+            "alias": "line1_temperature",
+            "asset_id": "L1-TT-101",
+            "unit": "degC",
+            "value_type": "Int32[]",
+            "value": [1, 2, 3, 4],
+            "array_dimensions": [2, 2],
+            "status": { "code": 2_150_891_520u32, "name": "BadNoCommunication" },
+            "source_timestamp": "2024-05-01T10:00:00.123Z",
+            "server_timestamp": "2024-05-01T10:00:00.456Z",
+            "backfilled": true,
+        })).unwrap();
+        let bare = serde_json::from_value(json!({
+            "schema_version": 1,
+            "endpoint": "opc.tcp://plc1:4840",
+            "node_id": "ns=2;s=Line1.Count",
+            "tag": "Line1.Count",
+            "value_type": "Empty",
+            "value": null,
+            "status": { "code": 0, "name": "Good" },
+            "source_timestamp": null,
+            "server_timestamp": null,
+        })).unwrap();
+        let mut text: TagSample = serde_json::from_value(json!({
+            "schema_version": 1,
+            "endpoint": "opc.tcp://plc1:4840",
+            "node_id": "ns=2;s=Line1.State",
+            "tag": "Line1.State",
+            "value_type": "String",
+            "value": "running, \"fast\"",
+            "status": { "code": 0, "name": "Good" },
+            "source_timestamp": null,
+            "server_timestamp": "2024-05-01T10:00:01Z",
+        })).unwrap();
+        text.unit = Some(String::new());
+        vec![full, bare, text]
+    }
+
+    /// Writes the samples and completes the file, checking it is `.partial` until then.
+    fn write(config: &FileSinkConfig) -> PathBuf {
+        let mut sink = FileSink { config: config.clone(), file: None };
+        for sample in samples() {
+            sink.append(&sample);
+        }
+        let partial_path = sink.file.as_ref().unwrap().partial_path.clone();
+        let name = partial_path.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("line1-") && name.ends_with(&format!(".{}.partial", config.format.extension())), "{}", name);
+        sink.close();
+        assert!(sink.file.is_none());
+        assert!(!partial_path.exists());
+        let files: Vec<PathBuf> = fs::read_dir(&config.dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+        assert_eq!(files, [partial_path.with_extension("")]);
+        files[0].clone()
+    }
+
+    #[test]
+    fn writes_a_csv_row_per_sample() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write(&config(&dir, FileFormat::Csv));
+        let mut reader = csv::Reader::from_path(path).unwrap();
+        assert_eq!(reader.headers().unwrap(), &csv::StringRecord::from(COLUMNS.to_vec()));
+        let rows: Vec<Vec<String>> = reader.records()
+            .map(|row| row.unwrap().iter().map(str::to_string).collect())
+            .collect();
+        assert_eq!(rows, [
+            vec!["1", "opc.tcp://plc1:4840", "ns=2;s=Line1.Temp", "Line1.Temp", "line1_temperature", "L1-TT-101", "degC",
+                "Int32[]", "[1,2,3,4]", "[2,2]", "2150891520", "BadNoCommunication", "2024-05-01T10:00:00.123Z",
+                "2024-05-01T10:00:00.456Z", "true"],
+            vec!["1", "opc.tcp://plc1:4840", "ns=2;s=Line1.Count", "Line1.Count", "", "", "", "Empty", "", "", "0", "Good",
+                "", "", "false"],
+            vec!["1", "opc.tcp://plc1:4840", "ns=2;s=Line1.State", "Line1.State", "", "", "", "String",
+                "\"running, \\\"fast\\\"\"", "", "0", "Good", "", "2024-05-01T10:00:01Z", "false"],
+        ]);
+    }
+
+    #[test]
+    fn keeps_a_flushed_partial_csv_file_readable() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file = RollingFile::create(&config(&dir, FileFormat::Csv)).unwrap();
+        file.append(&samples()[0], 2).unwrap();
+        file.flush().unwrap();
+        let mut reader = csv::Reader::from_path(&file.partial_path).unwrap();
+        assert_eq!(reader.records().count(), 1);
+        assert_eq!(file.bytes(), fs::metadata(&file.partial_path).unwrap().len());
+    }
+
+    #[test]
+    fn writes_parquet_row_groups_with_nulls() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write(&config(&dir, FileFormat::Parquet));
+        let reader = SerializedFileReader::new(File::open(path).unwrap()).unwrap();
+        // Two rows per row group
+        assert_eq!(reader.metadata().num_row_groups(), 2);
+        let names: Vec<&str> = reader.metadata().file_metadata().schema_descr().columns().iter().map(|column| column.name()).collect();
+        assert_eq!(names, COLUMNS);
+
+        let rows: Vec<Vec<Field>> = reader.get_row_iter(None).unwrap()
+            .map(|row| row.unwrap().get_column_iter().map(|(_, field)| field.clone()).collect())
+            .collect();
+        let string = |value: &str| Field::Str(value.to_string());
+        assert_eq!(rows, [
+            vec![Field::Int(1), string("opc.tcp://plc1:4840"), string("ns=2;s=Line1.Temp"), string("Line1.Temp"),
+                string("line1_temperature"), string("L1-TT-101"), string("degC"), string("Int32[]"), string("[1,2,3,4]"),
+                string("[2,2]"), Field::Long(2_150_891_520), string("BadNoCommunication"), string("2024-05-01T10:00:00.123Z"),
+                string("2024-05-01T10:00:00.456Z"), Field::Bool(true)],
+            vec![Field::Int(1), string("opc.tcp://plc1:4840"), string("ns=2;s=Line1.Count"), string("Line1.Count"),
+                Field::Null, Field::Null, Field::Null, string("Empty"), Field::Null, Field::Null, Field::Long(0), string("Good"),
+                Field::Null, Field::Null, Field::Bool(false)],
+            vec![Field::Int(1), string("opc.tcp://plc1:4840"), string("ns=2;s=Line1.State"), string("Line1.State"),
+                Field::Null, Field::Null, string(""), string("String"), string("\"running, \\\"fast\\\"\""), Field::Null,
+                Field::Long(0), string("Good"), Field::Null, string("2024-05-01T10:00:01Z"), Field::Bool(false)],
+        ]);
+    }
+}
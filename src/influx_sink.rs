@@ -0,0 +1,174 @@
+//! InfluxDB output, writing samples as line protocol over HTTP.
+//!
+//! | Variable           | Meaning                                                            |
+//! |--------------------|--------------------------------------------------------------------|
+//! | `INFLUX_URL`       | base url of the server, e.g. `http://influx:8086`, enables the sink |
+//! | `INFLUX_BUCKET`    | InfluxDB 2 bucket, written through `/api/v2/write`                 |
+//! | `INFLUX_ORG`       | InfluxDB 2 organization of `INFLUX_BUCKET`                         |
+//! | `INFLUX_DATABASE`  | InfluxDB 1 database, written through `/write` when no bucket is set |
+//! | `INFLUX_TOKEN`     | API token, sent as `Authorization: Token <token>`                  |
+//!
+//! Batching and retries are set with the `INFLUX_` variables of [`crate::timeseries`].
+//! A sample becomes one line such as
+//!
+//! ```text
+//! temperature,line=1,tag=line1_temperature,unit=degC celsius=21.5,status=0i 1714557600123000000
+//! ```
+use std::time::Duration;
+use dotenvy::var;
+
+use crate::client_config::Secret;
+use crate::timeseries::{BatchConfig, FieldValue, Point, PointWriter};
+
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct InfluxConfig {
+    /// The write endpoint with its query parameters.
+    pub write_url: String,
+    pub token: Option<Secret>,
+    pub batch: BatchConfig,
+}
+
+impl InfluxConfig {
+    /// The InfluxDB settings, `None` when `INFLUX_URL` is not set.
+    pub fn from_env() -> Result<Option<InfluxConfig>, String> {
+        let Ok(url) = var("INFLUX_URL") else {
+            return Ok(None);
+        };
+        let url = url.trim().trim_end_matches('/');
+        let write_url = match (var("INFLUX_BUCKET"), var("INFLUX_DATABASE")) {
+            (Ok(bucket), _) => {
+                let org = var("INFLUX_ORG").map_err(|_| "INFLUX_ORG is required with INFLUX_BUCKET".to_string())?;
+                format!("{}/api/v2/write?org={}&bucket={}&precision=ns", url, encode(&org), encode(&bucket))
+            }
+            (Err(_), Ok(database)) => format!("{}/write?db={}&precision=ns", url, encode(&database)),
+            (Err(_), Err(_)) => return Err("INFLUX_URL is set without INFLUX_BUCKET or INFLUX_DATABASE".to_string()),
+        };
+        Ok(Some(InfluxConfig {
+            write_url,
+            token: var("INFLUX_TOKEN").ok().map(Secret::new),
+            batch: BatchConfig::from_env("INFLUX")?,
+        }))
+    }
+}
+
+/// Percent-encodes a query parameter value.
+fn encode(value: &str) -> String {
+    value.bytes().map(|byte| match byte {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+        _ => format!("%{:02X}", byte),
+    }).collect()
+}
+
+pub struct InfluxWriter {
+    agent: ureq::Agent,
+    write_url: String,
+    token: Option<Secret>,
+}
+
+impl InfluxWriter {
+    pub fn new(config: &InfluxConfig) -> InfluxWriter {
+        InfluxWriter {
+            agent: ureq::AgentBuilder::new().timeout(WRITE_TIMEOUT).build(),
+            write_url: config.write_url.clone(),
+            token: config.token.clone(),
+        }
+    }
+}
+
+impl PointWriter for InfluxWriter {
+    fn write(&mut self, points: &[Point]) -> Result<(), String> {
+        let body = points.iter().map(line).collect::<Vec<String>>().join("\n");
+        let mut request = self.agent.post(&self.write_url).set("Content-Type", "text/plain; charset=utf-8");
+        if let Some(ref token) = self.token {
+            request = request.set("Authorization", &format!("Token {}", token.expose()));
+        }
+        match request.send_string(&body) {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::Status(status, response)) => Err(format!("InfluxDB answered {}: {}", status,
+                response.into_string().unwrap_or_default().trim())),
+            Err(err) => Err(format!("cannot reach InfluxDB: {}", err)),
+        }
+    }
+}
+
+/// The line protocol line of a point.
+pub fn line(point: &Point) -> String {
+    let mut line = escape(&point.measurement, &[',', ' ']);
+    for (name, value) in &point.tags {
+        // Empty tag values are not allowed
+        if !value.is_empty() {
+            line.push(',');
+            line.push_str(&escape(name, &[',', '=', ' ']));
+            line.push('=');
+            line.push_str(&escape(value, &[',', '=', ' ']));
+        }
+    }
+    line.push(' ');
+    if let Some(ref value) = point.value {
+        line.push_str(&escape(&point.field, &[',', '=', ' ']));
+        line.push('=');
+        match value {
+            FieldValue::Float(value) => line.push_str(&format!("{:?}", value)),
+            FieldValue::Boolean(value) => line.push_str(if *value { "true" } else { "false" }),
+            FieldValue::Text(value) => line.push_str(&format!("\"{}\"", escape(value, &['"']))),
+        }
+        line.push(',');
+    }
+    line.push_str(&format!("status={}i", point.status));
+    if let Some(nanos) = point.time.timestamp_nanos_opt() {
+        line.push_str(&format!(" {}", nanos));
+    }
+    line
+}
+
+/// Escapes backslashes and `special` characters with a backslash.
+fn escape(value: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\n' => escaped.push_str("\\n"),
+            '\\' => escaped.push_str("\\\\"),
+            c if special.contains(&c) => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn escapes_line_protocol() {
+        let point = Point {
+            measurement: "plant temp,1".to_string(),
+            tags: BTreeMap::from([("tag".to_string(), "Line 1=a".to_string()), ("unit".to_string(), String::new())]),
+            field: "value".to_string(),
+            value: Some(FieldValue::Text("say \"hi\"".to_string())),
+            status: 0,
+            time: Utc.timestamp_millis_opt(1_714_557_600_123).unwrap(),
+        };
+        assert_eq!(line(&point), r#"plant\ temp\,1,tag=Line\ 1\=a value="say \"hi\"",status=0i 1714557600123000000"#);
+    }
+
+    #[test]
+    fn writes_floats_with_a_decimal_point() {
+        let point = Point {
+            measurement: "opcua".to_string(),
+            tags: BTreeMap::new(),
+            field: "value".to_string(),
+            value: Some(FieldValue::Float(1.0)),
+            status: 2_150_891_520,
+            time: Utc.timestamp_millis_opt(0).unwrap(),
+        };
+        assert_eq!(line(&point), "opcua value=1.0,status=2150891520i 0");
+    }
+}
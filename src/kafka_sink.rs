@@ -0,0 +1,1232 @@
+//! Kafka output for monitored item changes.
+//!
+//! The subscription callback hands samples to a [`PublisherHandle`], which puts them on a
+//! bounded channel and never waits for Kafka. A dedicated thread owns the single long-lived
+//! librdkafka producer, collects records for up to `KAFKA_LINGER_MS` or `KAFKA_BATCH_SIZE`
+//! records, hands them to the producer together and waits for their delivery reports, at most
+//! `KAFKA_DELIVERY_TIMEOUT_MS`. librdkafka groups them per topic and partition. When the channel is
+//! full new records are dropped and counted. While Kafka cannot be reached, records are kept
+//! in the [`Spool`] and replayed in order once it is back.
+//!
+//! The brokers are reached over TLS when `KAFKA_TLS` is true or any `KAFKA_TLS_*` file is
+//! set, see [`KafkaTls`]. Records that cannot be published become dead letters, see
+//! [`crate::dead_letter`].
+//!
+//! Samples are written as JSON, or with `KAFKA_FORMAT=avro` as Avro registered at
+//! `SCHEMA_REGISTRY_URL` (see [`crate::avro`]) or with `KAFKA_FORMAT=protobuf` as Protobuf
+//! (see [`crate::protobuf`]). `KAFKA_TOPIC_FORMATS` overrides the format per topic, e.g.
+//! `dcs.counters=protobuf,dcs.alarms=avro`. Avro samples arriving while the schema registry
+//! is unavailable are spooled as JSON with a `dcs-pending-avro` header and serialized once it
+//! is back, the records after them wait in the spool to keep the order.
+//!
+//! Every record is given provenance headers, so consumers can route and de-duplicate without
+//! parsing the payload:
+//!
+//! | Header               | Value                                                      |
+//! |----------------------|------------------------------------------------------------|
+//! | `dcs-endpoint`       | the OPC UA endpoint url                                    |
+//! | `dcs-node-id`        | the node id of the tag, not set on events                  |
+//! | `dcs-status`         | the OPC UA status code name of the value, not set on events |
+//! | `dcs-schema-version` | the envelope schema version                                |
+//! | `dcs-instance-id`    | `DCS_INSTANCE_ID`, by default the host name                |
+//! | `dcs-sequence`       | a number increasing with every record of this instance     |
+//!
+//! The sequence starts at the process start time in microseconds, so it keeps increasing over
+//! restarts. The headers are kept with spooled records and dead letters.
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use dotenvy::var;
+
+use opcua::sync::Mutex;
+use openssl::ssl::{SslConnector, SslFiletype, SslMethod};
+use rdkafka::config::ClientConfig;
+use rdkafka::error::{KafkaError, KafkaResult, RDKafkaErrorCode};
+use rdkafka::message::{Header, OwnedHeaders};
+use rdkafka::producer::{BaseProducer, BaseRecord, DeliveryResult, Producer as _, ProducerContext};
+use rdkafka::ClientContext;
+use serde::Serialize;
+
+use crate::avro::{AvroSerializer, SchemaRegistry, SerializeError};
+use crate::client_config::{instance_id, parse_bool, parse_u64};
+use crate::protobuf;
+use crate::dead_letter::{append_to_file, DeadLetter};
+use crate::sample::{TagSample, SCHEMA_VERSION};
+use crate::spool::{Spool, SpooledRecord};
+
+/// Kafka settings read from the environment.
+#[derive(Debug, Clone)]
+pub struct KafkaConfig {
+    /// `KAFKA_BROKERS`, a comma separated list of `host:port` entries.
+    pub brokers: Vec<String>,
+    /// `KAFKA_TOPIC`, the topic for samples that no route matches.
+    pub topic: String,
+    /// `KAFKA_PARTITION`, the partition to write to. When unset (-1) the
+    /// producer picks the partition from the record key.
+    pub partition: i32,
+    /// `KAFKA_KEY`, how the record key is built for each tag.
+    pub key: KeyStrategy,
+    /// `KAFKA_STATUS_TOPIC`, the optional topic for connection events.
+    pub status_topic: Option<String>,
+    /// `KAFKA_LINGER_MS`, how long a record may wait for more records to share its request.
+    pub linger: Duration,
+    /// `KAFKA_BATCH_SIZE`, the records sent in one request at most.
+    pub batch_size: usize,
+    /// `KAFKA_COMPRESSION`, `none`, `gzip` or `snappy`, as librdkafka `compression.type`.
+    pub compression: String,
+    /// `KAFKA_ACKS`, `none`, `one` or `all` brokers that must acknowledge a request, as
+    /// librdkafka `acks`.
+    pub acks: String,
+    /// `KAFKA_ACK_TIMEOUT_MS`, how long the brokers may take to acknowledge a request.
+    pub ack_timeout: Duration,
+    /// `KAFKA_DELIVERY_TIMEOUT_MS`, how long librdkafka may take to deliver a record, resends
+    /// included, before it is spooled.
+    pub delivery_timeout: Duration,
+    /// `KAFKA_QUEUE_CAPACITY`, the records waiting for the producer thread at most.
+    pub queue_capacity: usize,
+    /// TLS settings, `None` for plaintext connections.
+    pub tls: Option<KafkaTls>,
+    /// `KAFKA_SEND_RETRIES`, how often librdkafka sends a record again after a failure that
+    /// may pass, such as a broker being away, before the record is spooled. A record the
+    /// broker refuses becomes a dead letter at once, with this count as its retries, as
+    /// librdkafka may have made them before the refusal.
+    pub send_retries: u32,
+    /// `KAFKA_MAX_MESSAGE_BYTES`, the largest record, key and headers included. Larger records
+    /// become dead letters without being sent.
+    pub max_message_bytes: usize,
+    /// `KAFKA_DEAD_LETTER_TOPIC`, the optional topic for dead letters.
+    pub dead_letter_topic: Option<String>,
+    /// `DEAD_LETTER_FILE`, where dead letters go without a dead-letter topic or while Kafka
+    /// cannot be reached.
+    pub dead_letter_file: PathBuf,
+    /// `DCS_INSTANCE_ID`, the `dcs-instance-id` header, by default the host name.
+    pub instance_id: String,
+    /// `KAFKA_FORMAT`, how samples are serialized.
+    pub format: PayloadFormat,
+    /// `KAFKA_TOPIC_FORMATS`, comma separated `topic=format` overrides of `format`.
+    pub topic_formats: HashMap<String, PayloadFormat>,
+    /// `SCHEMA_REGISTRY_URL`, the schema registry for `avro`.
+    pub schema_registry_url: Option<String>,
+}
+
+/// The serialization of samples, `json` (the default), `avro` or `protobuf`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PayloadFormat {
+    Json,
+    Avro,
+    Protobuf,
+}
+
+impl PayloadFormat {
+    pub fn parse(value: &str) -> Result<PayloadFormat, String> {
+        match value.trim() {
+            "json" => Ok(PayloadFormat::Json),
+            "avro" => Ok(PayloadFormat::Avro),
+            "protobuf" => Ok(PayloadFormat::Protobuf),
+            other => Err(format!("payload format \"{}\" must be json, avro or protobuf", other)),
+        }
+    }
+}
+
+/// TLS settings of the broker connections, loaded from PEM files that are checked at startup:
+///
+/// * `KAFKA_TLS_CA_FILE`, the CA bundle the broker certificates are checked against. Without
+///   it the system trust store is used.
+/// * `KAFKA_TLS_CERT_FILE` and `KAFKA_TLS_KEY_FILE`, the client certificate chain and its
+///   private key, for brokers requiring client authentication.
+#[derive(Debug, Clone)]
+pub struct KafkaTls {
+    /// `KAFKA_TLS_VERIFY_HOSTNAME`, whether the broker certificate must match the broker host
+    /// name. Defaults to true.
+    pub verify_hostname: bool,
+    ca_file: Option<PathBuf>,
+    cert_file: Option<PathBuf>,
+    key_file: Option<PathBuf>,
+}
+
+impl KafkaTls {
+    pub fn from_env() -> Result<Option<KafkaTls>, String> {
+        let file = |name: &str| -> Result<Option<PathBuf>, String> {
+            match var(name) {
+                Ok(path) if !path.trim().is_empty() => {
+                    let path = PathBuf::from(path.trim());
+                    if !path.is_file() {
+                        return Err(format!("{} {} does not exist", name, path.display()));
+                    }
+                    Ok(Some(path))
+                }
+                _ => Ok(None),
+            }
+        };
+        let ca_file = file("KAFKA_TLS_CA_FILE")?;
+        let cert_file = file("KAFKA_TLS_CERT_FILE")?;
+        let key_file = file("KAFKA_TLS_KEY_FILE")?;
+        let any_file = ca_file.is_some() || cert_file.is_some() || key_file.is_some();
+        if !parse_bool("KAFKA_TLS", any_file)? {
+            if any_file {
+                return Err("KAFKA_TLS is false but KAFKA_TLS_* files are set".to_string());
+            }
+            return Ok(None);
+        }
+        let verify_hostname = parse_bool("KAFKA_TLS_VERIFY_HOSTNAME", true)?;
+        // librdkafka reads the files itself, but only reports problems once it connects
+        check_pem_files(ca_file.as_deref(), cert_file.as_deref(), key_file.as_deref())?;
+        Ok(Some(KafkaTls { verify_hostname, ca_file, cert_file, key_file }))
+    }
+
+    /// Sets the librdkafka TLS properties.
+    pub fn configure(&self, client_config: &mut ClientConfig) {
+        client_config.set("security.protocol", "ssl");
+        match self.ca_file {
+            Some(ref ca_file) => client_config.set("ssl.ca.location", ca_file.to_string_lossy()),
+            None => client_config.set("ssl.ca.location", "probe"),
+        };
+        if let (Some(cert_file), Some(key_file)) = (&self.cert_file, &self.key_file) {
+            client_config.set("ssl.certificate.location", cert_file.to_string_lossy());
+            client_config.set("ssl.key.location", key_file.to_string_lossy());
+        }
+        client_config.set("ssl.endpoint.identification.algorithm", if self.verify_hostname { "https" } else { "none" });
+    }
+}
+
+fn check_pem_files(ca_file: Option<&Path>, cert_file: Option<&Path>, key_file: Option<&Path>) -> Result<(), String> {
+    let mut builder = SslConnector::builder(SslMethod::tls())
+        .map_err(|err| format!("cannot initialize TLS for Kafka: {}", err))?;
+    if let Some(ca_file) = ca_file {
+        builder.set_ca_file(ca_file)
+            .map_err(|err| format!("KAFKA_TLS_CA_FILE {} is not a valid PEM CA bundle: {}", ca_file.display(), err))?;
+    }
+    match (cert_file, key_file) {
+        (Some(cert_file), Some(key_file)) => {
+            builder.set_certificate_chain_file(cert_file)
+                .map_err(|err| format!("KAFKA_TLS_CERT_FILE {} is not a valid PEM certificate: {}", cert_file.display(), err))?;
+            builder.set_private_key_file(key_file, SslFiletype::PEM)
+                .map_err(|err| format!("KAFKA_TLS_KEY_FILE {} is not a valid PEM private key: {}", key_file.display(), err))?;
+            builder.check_private_key()
+                .map_err(|err| format!("KAFKA_TLS_KEY_FILE {} does not match KAFKA_TLS_CERT_FILE {}: {}", key_file.display(), cert_file.display(), err))?;
+        }
+        (None, None) => {}
+        _ => return Err("KAFKA_TLS_CERT_FILE and KAFKA_TLS_KEY_FILE must be set together".to_string()),
+    }
+    Ok(())
+}
+
+/// How the record key of a sample is built. Keying by tag keeps the samples of a tag
+/// on one partition, in order, and lets log compaction keep the latest value per tag.
+///
+/// `KAFKA_KEY` is one of `node_id` (the default), `alias`, `asset_id`, or a template
+/// such as `{asset_id}/{alias}` using the placeholders `{node_id}`, `{tag}`, `{alias}`,
+/// `{asset_id}` and `{endpoint}`. A missing alias falls back to the tag name and a
+/// missing asset id falls back to the node id, so every tag still gets its own key.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyStrategy {
+    NodeId,
+    Alias,
+    AssetId,
+    Template(String),
+}
+
+const KEY_PLACEHOLDERS: [&str; 5] = ["node_id", "tag", "alias", "asset_id", "endpoint"];
+
+impl KeyStrategy {
+    pub fn parse(value: &str) -> Result<KeyStrategy, String> {
+        match value.trim() {
+            "node_id" => Ok(KeyStrategy::NodeId),
+            "alias" => Ok(KeyStrategy::Alias),
+            "asset_id" => Ok(KeyStrategy::AssetId),
+            template if template.contains('{') => {
+                let mut rest = template;
+                while let Some(start) = rest.find('{') {
+                    let end = rest[start..].find('}')
+                        .ok_or_else(|| format!("KAFKA_KEY template \"{}\" has an unclosed placeholder", template))?;
+                    let placeholder = &rest[start + 1..start + end];
+                    if !KEY_PLACEHOLDERS.contains(&placeholder) {
+                        return Err(format!("KAFKA_KEY template \"{}\" uses unknown placeholder {{{}}}, expected one of {:?}",
+                            template, placeholder, KEY_PLACEHOLDERS));
+                    }
+                    rest = &rest[start + end + 1..];
+                }
+                Ok(KeyStrategy::Template(template.to_string()))
+            }
+            other => Err(format!("KAFKA_KEY \"{}\" must be node_id, alias, asset_id or a template with placeholders", other)),
+        }
+    }
+
+    pub fn key(&self, sample: &TagSample) -> String {
+        let alias = || sample.alias.clone().unwrap_or_else(|| sample.tag.clone());
+        let asset_id = || sample.asset_id.clone().unwrap_or_else(|| sample.node_id.clone());
+        match self {
+            KeyStrategy::NodeId => sample.node_id.clone(),
+            KeyStrategy::Alias => alias(),
+            KeyStrategy::AssetId => asset_id(),
+            KeyStrategy::Template(template) => template
+                .replace("{node_id}", &sample.node_id)
+                .replace("{tag}", &sample.tag)
+                .replace("{alias}", &alias())
+                .replace("{asset_id}", &asset_id())
+                .replace("{endpoint}", &sample.endpoint),
+        }
+    }
+}
+
+impl KafkaConfig {
+    pub fn from_env() -> Result<KafkaConfig, String> {
+        let brokers: Vec<String> = var("KAFKA_BROKERS")
+            .map_err(|_| "KAFKA_BROKERS is not set".to_string())?
+            .split(',')
+            .map(|broker| broker.trim().to_string())
+            .filter(|broker| !broker.is_empty())
+            .collect();
+        if brokers.is_empty() {
+            return Err("KAFKA_BROKERS does not contain any broker".to_string());
+        }
+        let topic = var("KAFKA_TOPIC").map_err(|_| "KAFKA_TOPIC is not set".to_string())?;
+        let partition = match var("KAFKA_PARTITION") {
+            Ok(partition) => partition.trim().parse::<i32>()
+                .map_err(|_| format!("KAFKA_PARTITION \"{}\" is not a valid partition number", partition))?,
+            Err(_) => -1,
+        };
+        let key = match var("KAFKA_KEY") {
+            Ok(key) => KeyStrategy::parse(&key)?,
+            Err(_) => KeyStrategy::NodeId,
+        };
+        let status_topic = var("KAFKA_STATUS_TOPIC").ok().filter(|topic| !topic.trim().is_empty());
+        let format = match var("KAFKA_FORMAT") {
+            Ok(format) => PayloadFormat::parse(&format).map_err(|err| format!("KAFKA_FORMAT {}", err))?,
+            Err(_) => PayloadFormat::Json,
+        };
+        let mut topic_formats = HashMap::new();
+        if let Ok(overrides) = var("KAFKA_TOPIC_FORMATS") {
+            for entry in overrides.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+                let (topic, topic_format) = entry.split_once('=')
+                    .ok_or_else(|| format!("KAFKA_TOPIC_FORMATS entry \"{}\" must be topic=format", entry))?;
+                let topic_format = PayloadFormat::parse(topic_format)
+                    .map_err(|err| format!("KAFKA_TOPIC_FORMATS entry \"{}\": {}", entry, err))?;
+                topic_formats.insert(topic.trim().to_string(), topic_format);
+            }
+        }
+        let schema_registry_url = var("SCHEMA_REGISTRY_URL").ok().filter(|url| !url.trim().is_empty());
+        let uses_avro = format == PayloadFormat::Avro || topic_formats.values().any(|format| *format == PayloadFormat::Avro);
+        if uses_avro && schema_registry_url.is_none() {
+            return Err("SCHEMA_REGISTRY_URL is required for the avro format".to_string());
+        }
+        let compression = match var("KAFKA_COMPRESSION").as_deref().map(str::trim) {
+            Ok("none") | Err(_) => "none",
+            Ok("gzip") => "gzip",
+            Ok("snappy") => "snappy",
+            Ok(other) => return Err(format!("KAFKA_COMPRESSION \"{}\" must be none, gzip or snappy", other)),
+        };
+        let acks = match var("KAFKA_ACKS").as_deref().map(str::trim) {
+            Ok("one") | Ok("1") | Err(_) => "1",
+            Ok("none") | Ok("0") => "0",
+            Ok("all") | Ok("-1") => "all",
+            Ok(other) => return Err(format!("KAFKA_ACKS \"{}\" must be none, one or all", other)),
+        };
+        let ack_timeout = Duration::from_millis(parse_u64("KAFKA_ACK_TIMEOUT_MS", 1000)?.max(1));
+        let delivery_timeout = Duration::from_millis(parse_u64("KAFKA_DELIVERY_TIMEOUT_MS", 30_000)?).max(ack_timeout);
+        let max_message_bytes = parse_u64("KAFKA_MAX_MESSAGE_BYTES", 1_000_000)?;
+        if !(1000..=1_000_000_000).contains(&max_message_bytes) {
+            return Err(format!("KAFKA_MAX_MESSAGE_BYTES {} must be between 1000 and 1000000000", max_message_bytes));
+        }
+        let max_message_bytes = max_message_bytes as usize;
+        Ok(KafkaConfig {
+            brokers,
+            topic,
+            partition,
+            key,
+            status_topic,
+            linger: Duration::from_millis(parse_u64("KAFKA_LINGER_MS", 100)?),
+            batch_size: parse_u64("KAFKA_BATCH_SIZE", 500)?.max(1) as usize,
+            compression: compression.to_string(),
+            acks: acks.to_string(),
+            ack_timeout,
+            delivery_timeout,
+            queue_capacity: parse_u64("KAFKA_QUEUE_CAPACITY", 10_000)?.max(1) as usize,
+            tls: KafkaTls::from_env()?,
+            send_retries: u32::try_from(parse_u64("KAFKA_SEND_RETRIES", 2)?).unwrap_or(u32::MAX),
+            max_message_bytes,
+            dead_letter_topic: var("KAFKA_DEAD_LETTER_TOPIC").ok().filter(|topic| !topic.trim().is_empty()),
+            dead_letter_file: PathBuf::from(var("DEAD_LETTER_FILE").unwrap_or_else(|_| "dead-letter.jsonl".to_string())),
+            instance_id: instance_id(),
+            format,
+            topic_formats,
+            schema_registry_url,
+        })
+    }
+}
+
+/// The librdkafka settings shared by the producer and the command consumer: the brokers and TLS.
+pub fn client_config(config: &KafkaConfig) -> ClientConfig {
+    let mut client_config = ClientConfig::new();
+    client_config.set("bootstrap.servers", config.brokers.join(","));
+    if let Some(ref tls) = config.tls {
+        tls.configure(&mut client_config);
+    }
+    client_config
+}
+
+/// Collects the delivery reports of the records in flight, by their index in the batch.
+#[derive(Default)]
+pub struct DeliveryReports {
+    reports: Mutex<Vec<(usize, KafkaResult<()>)>>,
+}
+
+impl ClientContext for DeliveryReports {}
+
+impl ProducerContext for DeliveryReports {
+    type DeliveryOpaque = usize;
+
+    fn delivery(&self, result: &DeliveryResult<'_>, index: usize) {
+        let result = match result {
+            Ok(_) => Ok(()),
+            Err((err, _)) => Err(err.clone()),
+        };
+        self.reports.lock().push((index, result));
+    }
+}
+
+pub type KafkaProducer = BaseProducer<DeliveryReports>;
+
+/// Creates the producer and checks that the brokers can be reached, as librdkafka only
+/// connects once records are sent.
+pub fn create_producer(config: &KafkaConfig) -> KafkaResult<KafkaProducer> {
+    let producer: KafkaProducer = client_config(config)
+        .set("acks", config.acks.as_str())
+        .set("compression.type", config.compression.as_str())
+        .set("request.timeout.ms", config.ack_timeout.as_millis().to_string())
+        .set("message.timeout.ms", config.delivery_timeout.as_millis().to_string())
+        .set("message.send.max.retries", config.send_retries.to_string())
+        .set("message.max.bytes", config.max_message_bytes.to_string())
+        .create_with_context(DeliveryReports::default())?;
+    producer.client().fetch_metadata(None, config.ack_timeout)?;
+    Ok(producer)
+}
+
+/// How long to wait for delivery reports at a time.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Produces `records` and waits for their delivery reports, at most `timeout`. Returns the
+/// result of every record; a record without a report in time has timed out.
+fn deliver(producer: &KafkaProducer, records: &[&SpooledRecord], timeout: Duration) -> Vec<KafkaResult<()>> {
+    let deadline = Instant::now() + timeout;
+    let mut results: Vec<Option<KafkaResult<()>>> = vec![None; records.len()];
+    producer.context().reports.lock().clear();
+    for (index, record) in records.iter().enumerate() {
+        let mut base_record = base_record(record, index);
+        loop {
+            match producer.send(base_record) {
+                Ok(()) => break,
+                // The local queue is full until some records are delivered
+                Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), returned)) if Instant::now() < deadline => {
+                    producer.poll(POLL_INTERVAL);
+                    base_record = returned;
+                }
+                Err((err, _)) => {
+                    results[index] = Some(Err(err));
+                    break;
+                }
+            }
+        }
+    }
+    while results.iter().any(Option::is_none) && Instant::now() < deadline {
+        producer.poll(POLL_INTERVAL);
+        for (index, result) in producer.context().reports.lock().drain(..) {
+            if let Some(slot) = results.get_mut(index) {
+                *slot = Some(result);
+            }
+        }
+    }
+    results.into_iter()
+        .map(|result| result.unwrap_or(Err(KafkaError::MessageProduction(RDKafkaErrorCode::MessageTimedOut))))
+        .collect()
+}
+
+/// Marks a sample spooled as JSON until the schema registry takes its schema. It is removed
+/// before the record is sent.
+const PENDING_AVRO_HEADER: &str = "dcs-pending-avro";
+
+/// Hands batches of records to Kafka. The publisher only sees this, so it can be tested
+/// without brokers.
+trait RecordSender {
+    /// Sends `records` together and returns the result of every record.
+    fn send_batch(&mut self, records: &[&SpooledRecord]) -> Vec<KafkaResult<()>>;
+}
+
+/// The producer and how long to wait for its delivery reports.
+struct ProducerSender {
+    producer: KafkaProducer,
+    timeout: Duration,
+}
+
+impl RecordSender for ProducerSender {
+    fn send_batch(&mut self, records: &[&SpooledRecord]) -> Vec<KafkaResult<()>> {
+        deliver(&self.producer, records, self.timeout)
+    }
+}
+
+/// Creates a sender, called again after the previous one failed.
+type Connect<S> = Box<dyn FnMut() -> Result<S, String> + Send>;
+
+/// The record to produce, with its index in the batch as delivery opaque.
+fn base_record(record: &SpooledRecord, index: usize) -> BaseRecord<'_, [u8], [u8], usize> {
+    let base_record = BaseRecord::with_opaque_to(&record.topic, index)
+        .key(record.key.as_slice())
+        .payload(record.value.as_slice())
+        .headers(owned_headers(&record.headers));
+    if record.partition >= 0 { base_record.partition(record.partition) } else { base_record }
+}
+
+/// The bytes of a record that count against `KAFKA_MAX_MESSAGE_BYTES`.
+fn record_size(record: &SpooledRecord) -> usize {
+    record.key.len() + record.value.len()
+        + record.headers.iter().map(|(name, value)| name.len() + value.len()).sum::<usize>()
+}
+
+fn owned_headers(headers: &[(String, Vec<u8>)]) -> OwnedHeaders {
+    headers.iter().fold(OwnedHeaders::new_with_capacity(headers.len()), |owned, (name, value)| {
+        owned.insert(Header { key: name, value: Some(value) })
+    })
+}
+
+#[derive(Debug)]
+pub enum SendError {
+    Serialize(serde_json::Error),
+    /// The producer thread is behind by `KAFKA_QUEUE_CAPACITY` records.
+    QueueFull,
+    /// The producer thread has stopped.
+    Stopped,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Serialize(err) => write!(f, "cannot serialize message: {}", err),
+            SendError::QueueFull => write!(f, "the Kafka queue is full, dropping the record"),
+            SendError::Stopped => write!(f, "the Kafka producer thread has stopped"),
+        }
+    }
+}
+
+/// Whether a failed send may succeed later, as opposed to a record the broker refuses. A
+/// topic or partition that does not exist counts as refused, or its records would stay at the
+/// head of the spool for good.
+fn is_retriable(err: &KafkaError) -> bool {
+    !matches!(err.rdkafka_error_code(), Some(
+        RDKafkaErrorCode::InvalidMessage
+        | RDKafkaErrorCode::InvalidMessageSize
+        | RDKafkaErrorCode::MessageSizeTooLarge
+        | RDKafkaErrorCode::InvalidTopic
+        | RDKafkaErrorCode::UnknownTopicOrPartition
+        | RDKafkaErrorCode::UnknownTopic
+        | RDKafkaErrorCode::UnknownPartition
+        | RDKafkaErrorCode::MessageBatchTooLarge
+        | RDKafkaErrorCode::TopicAuthorizationFailed
+        | RDKafkaErrorCode::InvalidTimestamp
+        | RDKafkaErrorCode::InvalidRecord
+    ))
+}
+
+/// A record on its way to the producer thread.
+enum Outbound {
+    Sample { topic: String, sample: TagSample },
+    Event { topic: String, endpoint: String, key: String, value: Vec<u8> },
+}
+
+/// The sending side of the producer thread, cheap to clone into callbacks.
+#[derive(Clone)]
+pub struct PublisherHandle {
+    sender: SyncSender<Outbound>,
+    status_topic: Option<String>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl PublisherHandle {
+    pub fn status_topic(&self) -> Option<&str> {
+        self.status_topic.as_deref()
+    }
+
+    /// Queues a sample for the producer thread without blocking.
+    pub fn publish(&self, topic: &str, sample: TagSample) -> Result<(), SendError> {
+        self.queue(Outbound::Sample { topic: topic.to_string(), sample })
+    }
+
+    /// Queues a sample, waiting while the queue is full.
+    pub fn publish_waiting(&self, topic: &str, sample: TagSample) -> Result<(), SendError> {
+        self.sender.send(Outbound::Sample { topic: topic.to_string(), sample }).map_err(|_| SendError::Stopped)
+    }
+
+    /// Queues a JSON event of `endpoint`, such as a connection event or an alarm, with the
+    /// given key.
+    pub fn publish_event<T: Serialize>(&self, topic: &str, endpoint: &str, key: &str, event: &T) -> Result<(), SendError> {
+        let value = serde_json::to_vec(event).map_err(SendError::Serialize)?;
+        self.queue(Outbound::Event { topic: topic.to_string(), endpoint: endpoint.to_string(), key: key.to_string(), value })
+    }
+
+    fn queue(&self, outbound: Outbound) -> Result<(), SendError> {
+        self.sender.try_send(outbound).map_err(|err| match err {
+            TrySendError::Full(_) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                SendError::QueueFull
+            }
+            TrySendError::Disconnected(_) => SendError::Stopped,
+        })
+    }
+}
+
+/// How often the producer is created again and the spool replayed while Kafka is away.
+const REPLAY_INTERVAL: Duration = Duration::from_secs(1);
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Starts the producer thread, which owns the producer and the spool.
+pub fn spawn_publisher(config: KafkaConfig, spool: Spool) -> PublisherHandle {
+    let (sender, receiver) = mpsc::sync_channel(config.queue_capacity);
+    let handle = PublisherHandle {
+        sender,
+        status_topic: config.status_topic.clone(),
+        dropped: Arc::new(AtomicU64::new(0)),
+    };
+    let dropped = handle.dropped.clone();
+    let producer_config = config.clone();
+    let connect = Box::new(move || create_producer(&producer_config)
+        .map(|producer| ProducerSender {
+            producer,
+            // librdkafka times records out itself, the extra wait covers its last request
+            timeout: producer_config.delivery_timeout + producer_config.ack_timeout,
+        })
+        .map_err(|err| err.to_string()));
+    thread::spawn(move || KafkaPublisher::new(config, spool, connect).run(receiver, dropped));
+    handle
+}
+
+/// Sends records to Kafka, or to the spool while Kafka is unreachable or the spool still
+/// holds older records, so records reach Kafka in the order they were published.
+struct KafkaPublisher<S> {
+    config: KafkaConfig,
+    connect: Connect<S>,
+    producer: Option<S>,
+    spool: Spool,
+    /// The `dcs-sequence` header of the next record.
+    sequence: u64,
+    avro: Option<AvroSerializer>,
+}
+
+impl<S: RecordSender> KafkaPublisher<S> {
+    fn new(config: KafkaConfig, spool: Spool, mut connect: Connect<S>) -> KafkaPublisher<S> {
+        let producer = match connect() {
+            Ok(producer) => Some(producer),
+            Err(err) => {
+                println!("Kafka brokers {:?} are not reachable, spooling until they are: {}", config.brokers, err);
+                None
+            }
+        };
+        let sequence = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_micros() as u64).unwrap_or(0);
+        let avro = config.schema_registry_url.as_deref().map(|url| AvroSerializer::new(SchemaRegistry::new(url)));
+        KafkaPublisher { config, connect, producer, spool, sequence, avro }
+    }
+
+    /// Collects queued records into batches until every handle is dropped.
+    fn run(mut self, receiver: Receiver<Outbound>, dropped: Arc<AtomicU64>) {
+        let mut batch = Vec::with_capacity(self.config.batch_size);
+        let mut batch_started = Instant::now();
+        let mut last_replay = Instant::now();
+        let mut last_stats = Instant::now();
+        let mut last_dropped = 0;
+        let mut replayed = 0;
+        loop {
+            // Drain a backlog as fast as Kafka takes it, otherwise only retry now and then
+            let draining = replayed > 0 && self.producer.is_some() && !self.spool.is_empty();
+            let timeout = if draining {
+                Duration::ZERO
+            } else if batch.is_empty() {
+                REPLAY_INTERVAL.saturating_sub(last_replay.elapsed())
+            } else {
+                self.config.linger.saturating_sub(batch_started.elapsed())
+            };
+            match receiver.recv_timeout(timeout) {
+                Ok(outbound) => {
+                    if batch.is_empty() {
+                        batch_started = Instant::now();
+                    }
+                    match self.record(outbound) {
+                        Ok(record) => batch.push(record),
+                        Err(dead_letter) => self.send_dead_letters(vec![dead_letter]),
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.flush(&mut batch);
+                    if let Err(err) = self.spool.sync() {
+                        println!("Cannot sync the spool: {}", err);
+                    }
+                    return;
+                }
+            }
+            if batch.len() >= self.config.batch_size || (!batch.is_empty() && batch_started.elapsed() >= self.config.linger) {
+                self.flush(&mut batch);
+            }
+            if draining || last_replay.elapsed() >= REPLAY_INTERVAL {
+                replayed = self.replay(self.config.batch_size);
+                last_replay = Instant::now();
+            }
+            if last_stats.elapsed() >= STATS_INTERVAL {
+                let dropped = dropped.load(Ordering::Relaxed);
+                self.log_stats(dropped - last_dropped);
+                last_dropped = dropped;
+                last_stats = Instant::now();
+            }
+        }
+    }
+
+    /// Encodes a queued record, or returns it as a dead letter when it cannot be encoded or
+    /// is larger than `KAFKA_MAX_MESSAGE_BYTES`.
+    fn record(&mut self, outbound: Outbound) -> Result<SpooledRecord, DeadLetter> {
+        let record = self.encode(outbound)?;
+        let size = record_size(&record);
+        if size > self.config.max_message_bytes {
+            let err = format!("the record of {} bytes is larger than KAFKA_MAX_MESSAGE_BYTES {}", size, self.config.max_message_bytes);
+            return Err(DeadLetter::from_record(&record, err, 0));
+        }
+        Ok(record)
+    }
+
+    fn encode(&mut self, outbound: Outbound) -> Result<SpooledRecord, DeadLetter> {
+        match outbound {
+            Outbound::Sample { topic, sample } => {
+                let key = self.config.key.key(&sample);
+                let mut headers = self.headers(&sample.endpoint, Some((sample.node_id.as_str(), sample.status.name.as_str())));
+                let value = match self.serialize(&topic, &sample) {
+                    Ok(value) => Ok(value),
+                    Err(SerializeError::Unavailable(err)) => {
+                        // Serialized when the registry is back
+                        headers.push((PENDING_AVRO_HEADER.to_string(), Vec::new()));
+                        sample.to_json().map_err(|json_err| format!("{}, and cannot serialize the sample as JSON: {}", err, json_err))
+                    }
+                    Err(SerializeError::Failed(err)) => Err(err),
+                };
+                match value {
+                    Ok(value) => Ok(SpooledRecord::new(&topic, key.as_bytes(), &value, self.config.partition).with_headers(headers)),
+                    Err(err) => {
+                        // Keep the sample readable for the operators, as JSON when possible
+                        let payload = sample.to_json().unwrap_or_else(|_| format!("{:?}", sample).into_bytes());
+                        Err(DeadLetter::new(&topic, key.as_bytes(), &payload, self.config.partition, err, 0).with_headers(&headers))
+                    }
+                }
+            }
+            Outbound::Event { topic, endpoint, key, value } => {
+                let headers = self.headers(&endpoint, None);
+                Ok(SpooledRecord::new(&topic, key.as_bytes(), &value, -1).with_headers(headers))
+            }
+        }
+    }
+
+    fn serialize(&mut self, topic: &str, sample: &TagSample) -> Result<Vec<u8>, SerializeError> {
+        let format = self.config.topic_formats.get(topic).copied().unwrap_or(self.config.format);
+        match (format, self.avro.as_mut()) {
+            (PayloadFormat::Avro, Some(avro)) => avro.serialize(topic, sample),
+            (PayloadFormat::Protobuf, _) => Ok(protobuf::encode_sample(sample)),
+            _ => sample.to_json().map_err(|err| SerializeError::Failed(format!("cannot serialize sample: {}", err))),
+        }
+    }
+
+    /// Serializes a sample spooled while the schema registry was unavailable. Other records
+    /// are left as they are.
+    fn serialize_pending(&mut self, record: &mut SpooledRecord) -> Result<(), SerializeError> {
+        let Some(position) = record.headers.iter().position(|(name, _)| name == PENDING_AVRO_HEADER) else {
+            return Ok(());
+        };
+        let sample: TagSample = serde_json::from_slice(&record.value)
+            .map_err(|err| SerializeError::Failed(format!("cannot parse the spooled sample: {}", err)))?;
+        let avro = self.avro.as_mut()
+            .ok_or_else(|| SerializeError::Failed("SCHEMA_REGISTRY_URL is no longer set".to_string()))?;
+        record.value = avro.serialize(&record.topic, &sample)?;
+        record.headers.remove(position);
+        Ok(())
+    }
+
+    /// The provenance headers of the next record, with the node id and status of a sample.
+    fn headers(&mut self, endpoint: &str, tag: Option<(&str, &str)>) -> Vec<(String, Vec<u8>)> {
+        let mut headers = vec![("dcs-endpoint".to_string(), endpoint.as_bytes().to_vec())];
+        if let Some((node_id, status)) = tag {
+            headers.push(("dcs-node-id".to_string(), node_id.as_bytes().to_vec()));
+            headers.push(("dcs-status".to_string(), status.as_bytes().to_vec()));
+        }
+        headers.push(("dcs-schema-version".to_string(), SCHEMA_VERSION.to_string().into_bytes()));
+        headers.push(("dcs-instance-id".to_string(), self.config.instance_id.as_bytes().to_vec()));
+        headers.push(("dcs-sequence".to_string(), self.sequence.to_string().into_bytes()));
+        self.sequence += 1;
+        headers
+    }
+
+    /// Sends a batch, spooling what cannot be sent now.
+    fn flush(&mut self, batch: &mut Vec<SpooledRecord>) {
+        if batch.is_empty() {
+            return;
+        }
+        let mut records = std::mem::take(batch);
+        let sent = if self.spool.is_empty() { self.send_records(&mut records) } else { 0 };
+        let mut refused = 0;
+        for record in &records[sent..] {
+            match self.spool.push(record) {
+                Ok(true) => {}
+                Ok(false) => refused += 1,
+                Err(err) => {
+                    println!("Cannot write to the spool, dropping a record for topic {}: {}", record.topic, err);
+                }
+            }
+        }
+        if refused > 0 {
+            println!("The spool is full, {} records were dropped", refused);
+        }
+    }
+
+    /// Sends `records` together, up to the first sample the schema registry is still
+    /// unavailable for. A record the broker refuses or a sample the registry refuses becomes a
+    /// dead letter. Returns how many leading records are done with. When the rest could not be
+    /// delivered the producer is dropped; they may still include delivered records, which then
+    /// reach Kafka twice.
+    fn send_records(&mut self, records: &mut [SpooledRecord]) -> usize {
+        if self.producer.is_none() {
+            return 0;
+        }
+        let mut dead_letters = Vec::new();
+        // The index of every record to send
+        let mut sending = Vec::with_capacity(records.len());
+        let mut ready = records.len();
+        for (index, record) in records.iter_mut().enumerate() {
+            match self.serialize_pending(record) {
+                Ok(()) => sending.push(index),
+                Err(SerializeError::Failed(err)) => dead_letters.push((index, DeadLetter::from_record(record, err, 0))),
+                Err(SerializeError::Unavailable(_)) => {
+                    ready = index;
+                    break;
+                }
+            }
+        }
+        let batch: Vec<&SpooledRecord> = sending.iter().map(|&index| &records[index]).collect();
+        let results = match self.producer.as_mut() {
+            Some(producer) if !batch.is_empty() => producer.send_batch(&batch),
+            _ => Vec::new(),
+        };
+        for (&index, result) in sending.iter().zip(results) {
+            match result {
+                Ok(()) => {}
+                Err(err) if !is_retriable(&err) => {
+                    dead_letters.push((index, DeadLetter::from_record(&records[index], err.to_string(), self.config.send_retries)));
+                }
+                Err(err) => {
+                    println!("Kafka send to topic {} failed, spooling until Kafka is back: {}", records[index].topic, err);
+                    self.producer = None;
+                    // The records from here on are sent again later
+                    dead_letters.retain(|(dead, _)| *dead < index);
+                    ready = index;
+                    break;
+                }
+            }
+        }
+        self.send_dead_letters(dead_letters.into_iter().map(|(_, dead_letter)| dead_letter).collect());
+        ready
+    }
+
+    /// Publishes dead letters to the dead-letter topic, or appends them to the dead-letter
+    /// file without a topic or a producer.
+    fn send_dead_letters(&mut self, dead_letters: Vec<DeadLetter>) {
+        for dead_letter in dead_letters {
+            println!("Record for topic {} is a dead letter: {}", dead_letter.topic, dead_letter.error);
+            if let (Some(topic), Some(producer)) = (self.config.dead_letter_topic.as_deref(), self.producer.as_mut()) {
+                let result = dead_letter.to_json().map_err(|err| err.to_string()).and_then(|value| {
+                    let record = SpooledRecord::new(topic, dead_letter.key.as_bytes(), &value, -1);
+                    producer.send_batch(&[&record]).remove(0).map_err(|err| err.to_string())
+                });
+                match result {
+                    Ok(()) => continue,
+                    Err(err) => println!("Cannot send the dead letter to topic {}, writing it to {}: {}", topic, self.config.dead_letter_file.display(), err),
+                }
+            }
+            if let Err(err) = append_to_file(&self.config.dead_letter_file, &dead_letter) {
+                println!("Cannot write the dead letter to {}, dropping it: {}", self.config.dead_letter_file.display(), err);
+            }
+        }
+    }
+
+    /// Sends up to `max_records` spooled records, oldest first, creating the producer again
+    /// if needed. Returns the number of records that left the spool.
+    fn replay(&mut self, max_records: usize) -> usize {
+        if self.spool.is_empty() {
+            return 0;
+        }
+        if self.producer.is_none() {
+            match (self.connect)() {
+                Ok(producer) => {
+                    println!("Kafka brokers {:?} are reachable again", self.config.brokers);
+                    self.producer = Some(producer);
+                }
+                Err(_) => return 0,
+            }
+        }
+        let mut records = match self.spool.front_batch(max_records) {
+            Ok(records) => records,
+            Err(err) => {
+                println!("Cannot read the spool: {}", err);
+                return 0;
+            }
+        };
+        let replayed = self.send_records(&mut records);
+        if let Err(err) = self.spool.pop_batch(replayed) {
+            println!("Cannot update the spool: {}", err);
+        }
+        replayed
+    }
+
+    /// Logs the spool backlog and the records dropped because the queue was full.
+    fn log_stats(&self, dropped: u64) {
+        let stats = self.spool.stats();
+        if dropped > 0 {
+            println!("Kafka queue was full, {} records were dropped", dropped);
+        }
+        if stats.records > 0 {
+            let oldest = stats.oldest_ms
+                .and_then(|oldest_ms| chrono::DateTime::from_timestamp_millis(oldest_ms as i64))
+                .map(|oldest| format!(", oldest from {}", oldest.to_rfc3339()))
+                .unwrap_or_default();
+            println!("Spool backlog: {} records, {} bytes in {} segments{}, {} evicted, {} refused",
+                stats.records, stats.bytes, stats.segments, oldest, stats.evicted, stats.refused);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashSet;
+    use rdkafka::message::Headers;
+    use serde_json::json;
+
+    use crate::spool::{Eviction, SpoolConfig};
+
+    fn sample(alias: Option<&str>, asset_id: Option<&str>) -> TagSample {
+        serde_json::from_value(json!({
+            "schema_version": 1,
+            "endpoint": "opc.tcp://plc1:4840",
+            "node_id": "ns=2;s=Line1.Temp",
+            "tag": "Line1.Temp",
+            "alias": alias,
+            "asset_id": asset_id,
+            "value_type": "Double",
+            "value": 3.2,
+            "status": { "code": 0, "name": "Good" },
+            "source_timestamp": null,
+            "server_timestamp": null,
+        })).unwrap()
+    }
+
+    #[test]
+    fn keys_by_each_strategy() {
+        let tagged = sample(Some("line1_temperature"), Some("L1-TT-101"));
+        assert_eq!(KeyStrategy::parse("node_id").unwrap().key(&tagged), "ns=2;s=Line1.Temp");
+        assert_eq!(KeyStrategy::parse(" alias ").unwrap().key(&tagged), "line1_temperature");
+        assert_eq!(KeyStrategy::parse("asset_id").unwrap().key(&tagged), "L1-TT-101");
+
+        let untagged = sample(None, None);
+        assert_eq!(KeyStrategy::Alias.key(&untagged), "Line1.Temp");
+        assert_eq!(KeyStrategy::AssetId.key(&untagged), "ns=2;s=Line1.Temp");
+    }
+
+    #[test]
+    fn fills_every_template_placeholder() {
+        let template = KeyStrategy::parse("{endpoint}|{node_id}|{tag}|{alias}|{asset_id}").unwrap();
+        assert_eq!(template.key(&sample(Some("line1_temperature"), Some("L1-TT-101"))),
+            "opc.tcp://plc1:4840|ns=2;s=Line1.Temp|Line1.Temp|line1_temperature|L1-TT-101");
+        assert_eq!(template.key(&sample(None, None)),
+            "opc.tcp://plc1:4840|ns=2;s=Line1.Temp|Line1.Temp|Line1.Temp|ns=2;s=Line1.Temp");
+    }
+
+    #[test]
+    fn rejects_unknown_placeholders() {
+        let err = KeyStrategy::parse("{asset_id}/{unit}").unwrap_err();
+        assert!(err.contains("unknown placeholder {unit}"), "{}", err);
+        let err = KeyStrategy::parse("{asset_id").unwrap_err();
+        assert!(err.contains("unclosed placeholder"), "{}", err);
+        assert!(KeyStrategy::parse("tag").is_err());
+    }
+
+    #[test]
+    fn sends_the_headers_with_the_record() {
+        let headers = vec![
+            ("dcs-endpoint".to_string(), b"opc.tcp://plc1:4840".to_vec()),
+            ("dcs-sequence".to_string(), b"42".to_vec()),
+        ];
+        let record = SpooledRecord::new("dcs.pv", b"ns=2;s=Line1.Temp", b"{}", 2).with_headers(headers.clone());
+        let produced = base_record(&record, 7);
+        assert_eq!(produced.topic, "dcs.pv");
+        assert_eq!(produced.partition, Some(2));
+        assert_eq!(produced.key, Some(&b"ns=2;s=Line1.Temp"[..]));
+        assert_eq!(produced.payload, Some(&b"{}"[..]));
+        assert_eq!(produced.delivery_opaque, 7);
+        let encoded = produced.headers.as_ref().unwrap();
+        let encoded: Vec<(String, Vec<u8>)> = (0..encoded.count())
+            .map(|index| encoded.get(index))
+            .map(|header| (header.key.to_string(), header.value.unwrap().to_vec()))
+            .collect();
+        assert_eq!(encoded, headers);
+
+        let unpartitioned = SpooledRecord::new("dcs.pv", b"", b"", -1);
+        assert_eq!(base_record(&unpartitioned, 0).partition, None);
+    }
+
+    /// What the fake brokers did.
+    #[derive(Default)]
+    struct FakeKafka {
+        reachable: bool,
+        /// Keys of the records that time out once.
+        time_out_once: HashSet<String>,
+        /// Keys of the records the broker refuses.
+        refused: HashSet<String>,
+        /// Keys of every batch sent.
+        batches: Vec<Vec<String>>,
+        /// Keys of the delivered records.
+        delivered: Vec<String>,
+        records: Vec<SpooledRecord>,
+    }
+
+    struct FakeSender(Arc<Mutex<FakeKafka>>);
+
+    impl RecordSender for FakeSender {
+        fn send_batch(&mut self, records: &[&SpooledRecord]) -> Vec<KafkaResult<()>> {
+            let mut kafka = self.0.lock();
+            let keys: Vec<String> = records.iter().map(|record| String::from_utf8(record.key.clone()).unwrap()).collect();
+            kafka.batches.push(keys.clone());
+            keys.into_iter().zip(records).map(|(key, record)| {
+                if !kafka.reachable || kafka.time_out_once.remove(&key) {
+                    Err(KafkaError::MessageProduction(RDKafkaErrorCode::MessageTimedOut))
+                } else if kafka.refused.contains(&key) {
+                    Err(KafkaError::MessageProduction(RDKafkaErrorCode::MessageSizeTooLarge))
+                } else {
+                    kafka.delivered.push(key);
+                    kafka.records.push((*record).clone());
+                    Ok(())
+                }
+            }).collect()
+        }
+    }
+
+    fn config(dir: &tempfile::TempDir) -> KafkaConfig {
+        KafkaConfig {
+            brokers: vec!["kafka:9092".to_string()],
+            topic: "dcs.pv".to_string(),
+            partition: -1,
+            key: KeyStrategy::NodeId,
+            status_topic: None,
+            linger: Duration::from_secs(60),
+            batch_size: 2,
+            compression: "none".to_string(),
+            acks: "1".to_string(),
+            ack_timeout: Duration::from_secs(1),
+            delivery_timeout: Duration::from_secs(30),
+            queue_capacity: 100,
+            tls: None,
+            send_retries: 2,
+            max_message_bytes: 1000,
+            dead_letter_topic: None,
+            dead_letter_file: dir.path().join("dead-letter.jsonl"),
+            instance_id: "test".to_string(),
+            format: PayloadFormat::Json,
+            topic_formats: HashMap::new(),
+            schema_registry_url: None,
+        }
+    }
+
+    fn publisher(dir: &tempfile::TempDir, kafka: &Arc<Mutex<FakeKafka>>) -> KafkaPublisher<FakeSender> {
+        let spool = Spool::open(SpoolConfig {
+            dir: dir.path().join("spool"),
+            segment_bytes: 1 << 20,
+            max_bytes: 1 << 20,
+            max_age: None,
+            eviction: Eviction::DropOldest,
+        }).unwrap();
+        let kafka = kafka.clone();
+        let connect = Box::new(move || if kafka.lock().reachable {
+            Ok(FakeSender(kafka.clone()))
+        } else {
+            Err("no broker is reachable".to_string())
+        });
+        KafkaPublisher::new(config(dir), spool, connect)
+    }
+
+    fn event(key: &str) -> Outbound {
+        Outbound::Event { topic: "dcs.events".to_string(), endpoint: "opc.tcp://plc1:4840".to_string(), key: key.to_string(), value: b"{}".to_vec() }
+    }
+
+    fn records(publisher: &mut KafkaPublisher<FakeSender>, keys: &[&str]) -> Vec<SpooledRecord> {
+        keys.iter().map(|key| publisher.record(event(key)).unwrap()).collect()
+    }
+
+    #[test]
+    fn sends_full_batches_and_the_rest_when_stopped() {
+        let dir = tempfile::tempdir().unwrap();
+        let kafka = Arc::new(Mutex::new(FakeKafka { reachable: true, ..FakeKafka::default() }));
+        let publisher = publisher(&dir, &kafka);
+        let (sender, receiver) = mpsc::sync_channel(10);
+        for key in ["a", "b", "c", "d", "e"] {
+            sender.send(event(key)).unwrap();
+        }
+        drop(sender);
+        publisher.run(receiver, Arc::new(AtomicU64::new(0)));
+        assert_eq!(kafka.lock().batches, [vec!["a", "b"], vec!["c", "d"], vec!["e"]]);
+    }
+
+    #[test]
+    fn sends_a_partial_batch_after_the_linger() {
+        let dir = tempfile::tempdir().unwrap();
+        let kafka = Arc::new(Mutex::new(FakeKafka { reachable: true, ..FakeKafka::default() }));
+        let mut publisher = publisher(&dir, &kafka);
+        publisher.config.linger = Duration::from_millis(20);
+        let (sender, receiver) = mpsc::sync_channel(10);
+        let thread = thread::spawn(move || publisher.run(receiver, Arc::new(AtomicU64::new(0))));
+        sender.send(event("a")).unwrap();
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(kafka.lock().delivered, ["a"]);
+        drop(sender);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn spools_while_kafka_is_away_and_replays_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let kafka = Arc::new(Mutex::new(FakeKafka::default()));
+        let mut publisher = publisher(&dir, &kafka);
+        let mut batch = records(&mut publisher, &["a", "b"]);
+        publisher.flush(&mut batch);
+        assert!(kafka.lock().batches.is_empty());
+        assert_eq!(publisher.replay(10), 0);
+
+        // Newer records wait behind the spooled ones
+        kafka.lock().reachable = true;
+        let mut batch = records(&mut publisher, &["c"]);
+        publisher.flush(&mut batch);
+        assert_eq!(publisher.spool.stats().records, 3);
+        assert!(kafka.lock().batches.is_empty());
+
+        assert_eq!(publisher.replay(10), 3);
+        assert!(publisher.spool.is_empty());
+        let mut batch = records(&mut publisher, &["d"]);
+        publisher.flush(&mut batch);
+        assert_eq!(kafka.lock().delivered, ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn spools_the_rest_of_a_batch_after_a_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let kafka = Arc::new(Mutex::new(FakeKafka { reachable: true, ..FakeKafka::default() }));
+        kafka.lock().time_out_once.insert("b".to_string());
+        let mut publisher = publisher(&dir, &kafka);
+        let mut batch = records(&mut publisher, &["a", "b", "c"]);
+        publisher.flush(&mut batch);
+        assert!(publisher.producer.is_none());
+        assert_eq!(publisher.spool.stats().records, 2);
+
+        assert_eq!(publisher.replay(10), 2);
+        let kafka = kafka.lock();
+        assert_eq!(kafka.batches, [vec!["a", "b", "c"], vec!["b", "c"]]);
+        // c reached Kafka twice
+        assert_eq!(kafka.delivered, ["a", "c", "b", "c"]);
+    }
+
+    #[test]
+    fn dead_letters_refused_records_at_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let kafka = Arc::new(Mutex::new(FakeKafka { reachable: true, ..FakeKafka::default() }));
+        kafka.lock().refused.insert("b".to_string());
+        let mut publisher = publisher(&dir, &kafka);
+        let mut batch = records(&mut publisher, &["a", "b", "c"]);
+        publisher.flush(&mut batch);
+        assert!(publisher.spool.is_empty());
+        assert!(publisher.producer.is_some());
+        assert_eq!(kafka.lock().batches.len(), 1);
+        assert_eq!(kafka.lock().delivered, ["a", "c"]);
+
+        let dead_letters = std::fs::read_to_string(dir.path().join("dead-letter.jsonl")).unwrap();
+        let dead_letter: serde_json::Value = serde_json::from_str(dead_letters.trim()).unwrap();
+        assert_eq!(dead_letter["key"], "b");
+        assert_eq!(dead_letter["topic"], "dcs.events");
+        assert_eq!(dead_letter["retries"], 2);
+    }
+
+    #[test]
+    fn refuses_records_for_missing_topics() {
+        let missing = [
+            RDKafkaErrorCode::UnknownTopicOrPartition,
+            RDKafkaErrorCode::UnknownTopic,
+            RDKafkaErrorCode::UnknownPartition,
+            RDKafkaErrorCode::InvalidTopic,
+        ];
+        for code in missing {
+            assert!(!is_retriable(&KafkaError::MessageProduction(code)), "{:?}", code);
+        }
+        assert!(is_retriable(&KafkaError::MessageProduction(RDKafkaErrorCode::MessageTimedOut)));
+        assert!(is_retriable(&KafkaError::MessageProduction(RDKafkaErrorCode::BrokerTransportFailure)));
+    }
+
+    #[test]
+    fn dead_letters_records_over_the_size_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let kafka = Arc::new(Mutex::new(FakeKafka { reachable: true, ..FakeKafka::default() }));
+        let mut publisher = publisher(&dir, &kafka);
+        let oversize = Outbound::Event {
+            topic: "dcs.events".to_string(),
+            endpoint: "opc.tcp://plc1:4840".to_string(),
+            key: "a".to_string(),
+            value: vec![b'x'; 1000],
+        };
+        let dead_letter = publisher.record(oversize).unwrap_err();
+        assert!(dead_letter.error.contains("larger than KAFKA_MAX_MESSAGE_BYTES 1000"), "{}", dead_letter.error);
+        assert!(publisher.record(event("b")).is_ok());
+    }
+
+    #[test]
+    fn spools_avro_samples_until_the_registry_is_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let kafka = Arc::new(Mutex::new(FakeKafka { reachable: true, ..FakeKafka::default() }));
+        let (url, _, status) = crate::avro::test::mock_registry();
+        *status.lock().unwrap() = 503;
+        let mut publisher = publisher(&dir, &kafka);
+        publisher.config.format = PayloadFormat::Avro;
+        publisher.avro = Some(AvroSerializer::new(SchemaRegistry::with_retry_after(&url, Duration::ZERO)));
+
+        let mut batch = records(&mut publisher, &["a"]);
+        batch.push(publisher.record(Outbound::Sample { topic: "dcs.pv".to_string(), sample: sample(None, None) }).unwrap());
+        batch.extend(records(&mut publisher, &["b"]));
+        publisher.flush(&mut batch);
+        // The sample and the records after it wait for the registry, Kafka is fine
+        assert!(publisher.producer.is_some());
+        assert_eq!(kafka.lock().delivered, ["a"]);
+        assert_eq!(publisher.spool.stats().records, 2);
+        assert_eq!(publisher.replay(10), 0);
+
+        *status.lock().unwrap() = 200;
+        assert_eq!(publisher.replay(10), 2);
+        let kafka = kafka.lock();
+        assert_eq!(kafka.delivered, ["a", "ns=2;s=Line1.Temp", "b"]);
+        let sample = &kafka.records[1];
+        assert_eq!(&sample.value[..5], &[0, 0, 0, 0, 42]);
+        assert!(sample.headers.iter().all(|(name, _)| name != PENDING_AVRO_HEADER));
+    }
+
+    #[test]
+    fn dead_letters_samples_the_registry_refuses() {
+        let dir = tempfile::tempdir().unwrap();
+        let kafka = Arc::new(Mutex::new(FakeKafka { reachable: true, ..FakeKafka::default() }));
+        let (url, _, status) = crate::avro::test::mock_registry();
+        *status.lock().unwrap() = 422;
+        let mut publisher = publisher(&dir, &kafka);
+        publisher.config.format = PayloadFormat::Avro;
+        publisher.avro = Some(AvroSerializer::new(SchemaRegistry::new(&url)));
+        let dead_letter = publisher.record(Outbound::Sample { topic: "dcs.pv".to_string(), sample: sample(None, None) }).unwrap_err();
+        assert!(dead_letter.error.contains("cannot register the schema of subject dcs.pv-value"), "{}", dead_letter.error);
+        assert_eq!(dead_letter.payload.map(|payload| payload.contains("\"tag\":\"Line1.Temp\"")), Some(true));
+    }
+}
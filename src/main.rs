@@ -5,59 +5,416 @@
 //! This simple OPC UA client will do the following:
 //!
 //! 1. Create a client configuration
-//! 2. Connect to an endpoint specified by the url with security None
-//! 3. Subscribe to values and loop forever printing out their valrap();
-use std::{sync::Arc, vec};
-use std::fmt::Write;
-use std::time::Duration;
+//! 2. Discover the endpoints of the url and connect to the most secure acceptable one
+//! 3. Subscribe to values and loop forever forwarding every change to Kafka, MQTT,
+//!    time-series databases and rolling files, after publishing the values missed while
+//!    disconnected when `BACKFILL` is set, see [`backfill`]
+//! 4. Subscribe to the events of `EVENTS_FILE` and forward them to Kafka as alarm messages
+//! 5. Write the values and call the methods sent to `COMMAND_TOPIC` that `COMMANDS_FILE`
+//!    allows, see [`commands`]
+//!
+//! `dcs browse` instead lists the nodes of the server address space, see [`browse`].
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use dotenvy::{dotenv_override, var};
 use opcua::client::prelude::*;
 use opcua::sync::*;
 
-use kafka::producer::{Producer, Record, RequiredAcks};
+mod avro;
+mod backfill;
+mod browse;
+mod client_config;
+mod commands;
+mod connection;
+mod dead_letter;
+mod events;
+mod file_sink;
+mod influx_sink;
+mod kafka_sink;
+mod mqtt_sink;
+mod node_id;
+mod protobuf;
+mod routing;
+mod sample;
+mod sparkplug;
+mod spool;
+mod tags;
+mod timescale_sink;
+mod timeseries;
+
+use crate::backfill::{backfill, BackfillConfig, BackfillState};
+use crate::browse::{browse, print_nodes, tags_file, BrowseConfig};
+use crate::client_config::{instance_id, parse_bool, OpcUaConfig};
+use crate::commands::{spawn_command_consumer, CommandConfig, CommandTarget};
+use crate::connection::{connect, Backoff, ConnectError, ConnectionEvent, ReconnectConfig};
+use crate::events::{condition_refresh, refresh_marker, AlarmMessage, EventConfig, EventList};
+use crate::file_sink::{spawn_file_sink, FileSinkConfig, FileSinkHandle};
+use crate::influx_sink::{InfluxConfig, InfluxWriter};
+use crate::kafka_sink::{spawn_publisher, KafkaConfig, PublisherHandle};
+use crate::mqtt_sink::{spawn_mqtt, MqttConfig, MqttHandle};
+use crate::node_id::read_namespace_array;
+use crate::routing::Router;
+use crate::sample::TagSample;
+use crate::spool::{Spool, SpoolConfig};
+use crate::tags::{TagList, TagMapping};
+use crate::timescale_sink::{TimescaleConfig, TimescaleWriter};
+use crate::timeseries::{spawn_writer, SeriesMapping, TimeSeriesHandle};
 
 fn main() {
     dotenv_override().ok();
-    let opcua_host: &str = &var("OPCUA_SERVER").unwrap();
-    let monitored_tags  = var("MONITORED_TAGS").unwrap();
-    println!("OPC UA tags: {:?}", monitored_tags);
-    let mut client = ClientBuilder::new()
-        .application_name("DCS OPC UA client")
-        .application_uri("urn:DCSOPCUAClient")
-        .create_sample_keypair(true)
-        .trust_server_certs(true)
-        .session_retry_limit(3)
+    let mut args = pico_args::Arguments::from_env();
+    let command = exit_on_error(args.subcommand().map_err(|err| err.to_string()));
+    let opcua_config = exit_on_error(OpcUaConfig::from_env());
+    match command.as_deref() {
+        None => {}
+        Some("browse") => return browse_command(args, &opcua_config),
+        Some(other) => exit_on_error(Err(format!("unknown command \"{}\", expected browse", other))),
+    }
+    let opcua_host: &str = &opcua_config.url;
+    let discover = if exit_on_error(parse_bool("DISCOVER_AUTO_SUBSCRIBE", false)) {
+        Some(exit_on_error(BrowseConfig::from_env()))
+    } else {
+        None
+    };
+    let tag_list = exit_on_error(match var("TAGS_FILE") {
+        Ok(path) => TagList::load(&path),
+        Err(_) => match var("MONITORED_TAGS") {
+            Ok(monitored_tags) => TagList::from_monitored_tags(&monitored_tags),
+            // Every tag is discovered
+            Err(_) if discover.is_some() => Ok(TagList { tags: Vec::new() }),
+            Err(_) => Err("neither TAGS_FILE nor MONITORED_TAGS is set".to_string()),
+        },
+    });
+    println!("OPC UA tags: {}", tag_list.tags.len());
+    let event_list = var("EVENTS_FILE").ok().map(|path| exit_on_error(EventList::load(&path)));
+    let instance_id = instance_id();
+    let mqtt = exit_on_error(MqttConfig::from_env(&instance_id)).map(spawn_mqtt);
+    let files = exit_on_error(FileSinkConfig::from_env(&instance_id)).map(|config| exit_on_error(spawn_file_sink(config)));
+    let series_mapping = Arc::new(SeriesMapping::new(&tag_list));
+    let mut timeseries = Vec::new();
+    if let Some(config) = exit_on_error(InfluxConfig::from_env()) {
+        timeseries.push(spawn_writer("InfluxDB", config.batch.clone(), series_mapping.clone(), InfluxWriter::new(&config)));
+    }
+    if let Some(config) = exit_on_error(TimescaleConfig::from_env()) {
+        timeseries.push(spawn_writer("TimescaleDB", config.batch.clone(), series_mapping.clone(), TimescaleWriter::new(config)));
+    }
+    let command_config = exit_on_error(CommandConfig::from_env(&instance_id));
+    if let Some(config) = &command_config
+        && let Err(err) = config.commands.check_tags(&tag_list) {
+        // Discovered tags are only known once connected
+        if discover.is_some() {
+            println!("{}, unless it is discovered", err);
+        } else {
+            exit_on_error(Err(err))
+        }
+    }
+    let command_target = CommandTarget::default();
+    // Sites writing only to MQTT, a database or files leave KAFKA_BROKERS unset
+    let kafka = match var("KAFKA_BROKERS") {
+        Err(_) if command_config.is_none() && (mqtt.is_some() || files.is_some() || !timeseries.is_empty()) => None,
+        _ => {
+            let kafka_config = exit_on_error(KafkaConfig::from_env());
+            let router = exit_on_error(match var("ROUTING_FILE") {
+                Ok(path) => Router::load(&path, &kafka_config.topic),
+                Err(_) => Ok(Router::new(&kafka_config.topic)),
+            });
+            let spool_config = exit_on_error(SpoolConfig::from_env());
+            let spool = exit_on_error(Spool::open(spool_config.clone())
+                .map_err(|err| format!("cannot open the spool in {}: {}", spool_config.dir.display(), err)));
+            // One producer thread for the lifetime of the process, fed by the subscription callback
+            let publisher = spawn_publisher(kafka_config.clone(), spool);
+            if let Some(command_config) = command_config {
+                spawn_command_consumer(command_config, &kafka_config, publisher.clone(), command_target.clone());
+            }
+            Some((publisher, router))
+        }
+    };
+    let backfill = exit_on_error(BackfillConfig::from_env())
+        .map(|config| Arc::new(Mutex::new(exit_on_error(BackfillState::load(config)))));
+    let outputs = Outputs { kafka, mqtt, timeseries, files, backfill };
+    let mut backoff = Backoff::new(exit_on_error(ReconnectConfig::from_env()));
+    // Reconnects are done below, so the client itself never retries a lost session
+    let mut client = opcua_config.client_builder()
+        .session_retry_limit(0)
         .client().unwrap();
 
-    // Create an endpoint. The EndpointDescription can be made from a tuple consisting of
-    // the endpoint url, security policy, message security mode and user token policy.
-    let endpoint: EndpointDescription = (opcua_host, "None", MessageSecurityMode::None, UserTokenPolicy::anonymous()).into();
+    let mut disconnected_at = None;
+    loop {
+        match connect(&mut client, &opcua_config, &tag_list, discover.as_ref()) {
+            Ok((session, tag_mapping)) => {
+                // The history since the last published values goes out before live values
+                if let Some(ref state) = outputs.backfill {
+                    let read = backfill(state, &session.read(), opcua_host, &tag_mapping,
+                        |samples| outputs.publish_backfill(&tag_mapping, samples));
+                    if read > 0 {
+                        println!("Backfilled {} values from {}", read, opcua_host);
+                    }
+                }
+                // Create a subscription and monitored items
+                match subscribe_to_values(session.clone(), opcua_host, tag_mapping.clone(), outputs.clone()) {
+                    Ok((restored_tags, failed_tags)) => {
+                        if let Some(ref event_list) = event_list {
+                            subscribe_to_events(&session, opcua_host, event_list, &outputs);
+                        }
+                        let mut event = match disconnected_at.take() {
+                            Some(disconnected_at) => ConnectionEvent::new("reconnected", opcua_host, backoff.attempt()).with_gap(disconnected_at),
+                            None => ConnectionEvent::new("connected", opcua_host, backoff.attempt()),
+                        };
+                        println!("Session {} to {} after {} failed attempts, {} tags monitored, {} failed{}",
+                            event.event, opcua_host, event.attempts, restored_tags.len(), failed_tags.len(),
+                            event.gap_seconds.map(|gap| format!(", data gap of {:.1}s since {}", gap, event.disconnected_at.as_deref().unwrap_or_default())).unwrap_or_default());
+                        event.restored_tags = restored_tags;
+                        event.failed_tags = failed_tags;
+                        report_connection_event(&outputs, &event);
+                        backoff.reset();
+                        command_target.connected(&session, opcua_host, tag_mapping);
+                        Session::run(session.clone());
+                        command_target.disconnected();
+                        session.write().disconnect();
+                        disconnected_at = Some(chrono::Utc::now());
+                        if let Some(ref backfill) = outputs.backfill {
+                            backfill.lock().save();
+                        }
+                        println!("Session to {} lost", opcua_host);
+                        report_connection_event(&outputs, &ConnectionEvent::new("disconnected", opcua_host, 0));
+                    }
+                    Err(status) => {
+                        println!("Error creating subscription: {}", status);
+                        session.write().disconnect();
+                    }
+                }
+            }
+            Err(ConnectError::Fatal(err)) => exit_on_error(Err(err)),
+            Err(ConnectError::Retry(err)) => println!("{}", err),
+        }
+        match backoff.next_delay() {
+            Some(delay) => {
+                println!("Reconnecting to {} in {:?} (attempt {})", opcua_host, delay, backoff.attempt());
+                std::thread::sleep(delay);
+            }
+            None => exit_on_error(Err(format!("giving up on {} after {} reconnect attempts", opcua_host, backoff.attempt()))),
+        }
+    }
+}
+
+/// The configured outputs, at least one of them is set.
+#[derive(Clone)]
+struct Outputs {
+    kafka: Option<(PublisherHandle, Router)>,
+    mqtt: Option<MqttHandle>,
+    timeseries: Vec<TimeSeriesHandle>,
+    files: Option<FileSinkHandle>,
+    backfill: Option<Arc<Mutex<BackfillState>>>,
+}
 
-    // Create the session
-    let session = client.connect_to_endpoint(endpoint, IdentityToken::Anonymous).unwrap();
+impl Outputs {
+    /// Publishes the samples of the given nodes to every output.
+    fn publish(&self, tag_mapping: &TagMapping, samples: Vec<(NodeId, TagSample)>) {
+        self.send(tag_mapping, samples, false);
+    }
 
-    // Create a subscription and monitored items
-    if subscribe_to_values(session.clone(), monitored_tags).is_ok() {
-        Session::run(session);
-    } else {
-        println!("Error creating subscription");
+    /// Publishes values read from the server history, waiting for room in the queues of
+    /// Kafka, the databases and the files instead of dropping them.
+    fn publish_backfill(&self, tag_mapping: &TagMapping, samples: Vec<(NodeId, TagSample)>) {
+        self.send(tag_mapping, samples, true);
+    }
+
+    fn send(&self, tag_mapping: &TagMapping, samples: Vec<(NodeId, TagSample)>, wait: bool) {
+        let (node_ids, samples): (Vec<NodeId>, Vec<TagSample>) = samples.into_iter().unzip();
+        if let Some(ref mqtt) = self.mqtt
+            && let Err(err) = mqtt.publish_samples(&samples) {
+            println!("Failed to send {} samples to MQTT: {}", samples.len(), err);
+        }
+        for sink in &self.timeseries {
+            let result = if wait { sink.publish_waiting(&samples) } else { sink.publish(&samples) };
+            if let Err(err) = result {
+                println!("Failed to write samples: {}", err);
+            }
+        }
+        if let Some(ref files) = self.files {
+            let result = if wait { files.publish_waiting(&samples) } else { files.publish(&samples) };
+            if let Err(err) = result {
+                println!("Failed to write samples to file: {}", err);
+            }
+        }
+        if let Some(ref backfill) = self.backfill {
+            let mut backfill = backfill.lock();
+            samples.iter().for_each(|sample| backfill.record(sample));
+            backfill.save_if_due();
+        }
+        if let Some((ref publisher, ref router)) = self.kafka {
+            node_ids.iter().zip(samples).for_each(|(node_id, sample)| {
+                // A topic set on the tag takes precedence over the routing table
+                let topic = tag_mapping.get(node_id)
+                    .and_then(|tag| tag.topic.as_deref())
+                    .unwrap_or_else(|| router.route(&sample))
+                    .to_string();
+                let result = if wait { publisher.publish_waiting(&topic, sample) } else { publisher.publish(&topic, sample) };
+                if let Err(err) = result {
+                    println!("Item \"{}\", failed to send to Kafka topic {}: {}", node_id, topic, err);
+                }
+            });
+        }
+    }
+}
+
+/// Connects once and lists the nodes found by browsing, or writes them to `--output` as a
+/// tags file.
+fn browse_command(mut args: pico_args::Arguments, opcua_config: &OpcUaConfig) {
+    let mut config = exit_on_error(BrowseConfig::from_env());
+    exit_on_error(config.apply_args(&mut args));
+    let output: Option<PathBuf> = exit_on_error(args.opt_value_from_str("--output").map_err(|err| err.to_string()));
+    let unexpected = args.finish();
+    if !unexpected.is_empty() {
+        exit_on_error(Err(format!("unexpected arguments {:?}, expected --root, --node-class, --data-type, --name, --max-depth or --output", unexpected)));
+    }
+    let mut client = opcua_config.client_builder()
+        .session_retry_limit(0)
+        .client().unwrap();
+    let (session, _) = match connect(&mut client, opcua_config, &TagList { tags: Vec::new() }, None) {
+        Ok(connected) => connected,
+        Err(ConnectError::Fatal(err) | ConnectError::Retry(err)) => exit_on_error(Err(err)),
+    };
+    let result = {
+        let session = session.read();
+        read_namespace_array(&session)
+            .map_err(|status| format!("cannot read the server NamespaceArray: {}", status))
+            .and_then(|namespaces| browse(&session, &config, &namespaces).map(|nodes| (nodes, namespaces)))
+    };
+    session.write().disconnect();
+    let (nodes, namespaces) = exit_on_error(result);
+    match output {
+        Some(path) => {
+            let file = exit_on_error(tags_file(&nodes, &namespaces)
+                .map_err(|err| format!("{}, {} is not written", err, path.display())));
+            exit_on_error(fs::write(&path, file)
+                .map_err(|err| format!("cannot write {}: {}", path.display(), err)));
+            println!("Wrote {} nodes to {}", nodes.len(), path.display());
+        }
+        None => print_nodes(&nodes),
     }
 }
 
-fn subscribe_to_values(session: Arc<RwLock<Session>>, monitored_tags: String) -> Result<(), StatusCode> {
+/// Publishes a connection event to `KAFKA_STATUS_TOPIC` when that is set, and to MQTT.
+fn report_connection_event(outputs: &Outputs, event: &ConnectionEvent) {
+    if let Some((ref publisher, _)) = outputs.kafka
+        && let Some(status_topic) = publisher.status_topic()
+        && let Err(err) = publisher.publish_event(status_topic, &event.endpoint, &event.endpoint, event) {
+        println!("Failed to send {} event to Kafka topic {}: {}", event.event, status_topic, err);
+    }
+    if let Some(ref mqtt) = outputs.mqtt
+        && let Err(err) = mqtt.connection_event(event) {
+        println!("Failed to send {} event to MQTT: {}", event.event, err);
+    }
+}
+
+/// Creates the subscription and its monitored items, returning the names of the tags that
+/// are monitored and of those the server refused.
+fn subscribe_to_values(session: Arc<RwLock<Session>>, endpoint_url: &str, tag_mapping: TagMapping, outputs: Outputs) -> Result<(Vec<String>, Vec<String>), StatusCode> {
     let session = session.write();
+    let endpoint_url = endpoint_url.to_string();
+    let items_to_create = tag_mapping.create_requests();
+    let item_names: Vec<String> = items_to_create.iter()
+        .map(|request| tag_mapping.get(&request.item_to_monitor.node_id).map(|tag| tag.name.clone())
+            .unwrap_or_else(|| request.item_to_monitor.node_id.to_string()))
+        .collect();
     // Create a subscription polling every 2s with a callback
-    let subscription_id = session.create_subscription(0.0, 3, 0, 0, 0, true, DataChangeCallback::new(|changed_monitored_items| {
+    let subscription_id = session.create_subscription(0.0, 3, 0, 0, 0, true, DataChangeCallback::new(move |changed_monitored_items| {
         println!("Data change from server:");
-        changed_monitored_items.iter().for_each(|item| print_value(item));
+        let samples: Vec<(NodeId, TagSample)> = changed_monitored_items.iter()
+            .map(|item| {
+                print_value(item);
+                (item.item_to_monitor().node_id.clone(), TagSample::from_monitored_item(&endpoint_url, item, &tag_mapping))
+            })
+            .collect();
+        outputs.publish(&tag_mapping, samples);
     }))?;
     // Create some monitored items   
-    let monitored_tags_list: Vec<String> = monitored_tags.split(',').map(|tags|tags.trim().to_string()).collect();
-    println!("Monitored tags: {:?}", monitored_tags_list);
-    let items_to_create: Vec<MonitoredItemCreateRequest> = monitored_tags_list.iter()
-        .map(|v| NodeId::new(2, v.clone()).into()).collect();
-    let _ = session.create_monitored_items(subscription_id, TimestampsToReturn::Both, &items_to_create)?;
-    Ok(())
+    let results = session.create_monitored_items(subscription_id, TimestampsToReturn::Both, &items_to_create)?;
+    let mut monitored_tags = Vec::with_capacity(results.len());
+    let mut failed_tags = Vec::new();
+    for ((request, result), name) in items_to_create.iter().zip(results.iter()).zip(item_names) {
+        if result.status_code.is_bad() {
+            println!("Item \"{}\", cannot be monitored: {}", request.item_to_monitor.node_id, result.status_code);
+            failed_tags.push(name);
+        } else {
+            println!("Item \"{}\", monitored as tag \"{}\"", request.item_to_monitor.node_id, name);
+            monitored_tags.push(name);
+        }
+    }
+    Ok((monitored_tags, failed_tags))
+}
+
+/// Creates one subscription per configured event source and calls ConditionRefresh on it
+/// when configured. Failures are logged, the value subscription keeps running without them.
+fn subscribe_to_events(session: &Arc<RwLock<Session>>, endpoint_url: &str, event_list: &EventList, outputs: &Outputs) {
+    let session = session.write();
+    let namespaces = if event_list.has_namespace_uris() {
+        match read_namespace_array(&session) {
+            Ok(namespaces) => namespaces,
+            Err(status) => {
+                println!("Cannot read the server NamespaceArray, no event subscriptions: {}", status);
+                return;
+            }
+        }
+    } else {
+        Vec::new()
+    };
+    for config in &event_list.events {
+        let result = config.create_request(&namespaces)
+            .and_then(|request| create_event_subscription(&session, endpoint_url, config, request, outputs)
+                .map_err(|status| status.to_string()));
+        match result {
+            Ok(subscription_id) => {
+                println!("Events \"{}\", subscribed", config.name);
+                if event_list.condition_refresh && let Err(status) = condition_refresh(&session, subscription_id) {
+                    println!("Events \"{}\", ConditionRefresh failed: {}", config.name, status);
+                }
+            }
+            Err(err) => println!("Events \"{}\", cannot be subscribed: {}", config.name, err),
+        }
+    }
+}
+
+fn create_event_subscription(session: &Session, endpoint_url: &str, config: &EventConfig, request: MonitoredItemCreateRequest, outputs: &Outputs) -> Result<u32, StatusCode> {
+    let endpoint_url = endpoint_url.to_string();
+    let event_config = config.clone();
+    let publisher = outputs.kafka.as_ref().map(|(publisher, _)| publisher.clone());
+    // Set between the RefreshStart and RefreshEnd events of a ConditionRefresh
+    let refreshing = AtomicBool::new(false);
+    let subscription_id = session.create_subscription(0.0, 3, 0, 0, 0, true, EventCallback::new(move |notification| {
+        for event in notification.events.iter().flatten() {
+            let fields = event.event_fields.as_deref().unwrap_or_default();
+            if let Some(refresh) = refresh_marker(fields) {
+                refreshing.store(refresh, Ordering::Relaxed);
+                continue;
+            }
+            let alarm = AlarmMessage::new(&endpoint_url, &event_config, fields, refreshing.load(Ordering::Relaxed));
+            println!("Event \"{}\" from {}: {}", event_config.name, alarm.source_name.as_deref().unwrap_or("?"), alarm.message.as_deref().unwrap_or_default());
+            if let Some(ref publisher) = publisher
+                && let Err(err) = publisher.publish_event(&event_config.topic, &endpoint_url, alarm.key(), &alarm) {
+                println!("Event \"{}\", failed to send to Kafka topic {}: {}", event_config.name, event_config.topic, err);
+            }
+        }
+    }))?;
+    let results = session.create_monitored_items(subscription_id, TimestampsToReturn::Neither, &[request])?;
+    match results.first() {
+        Some(result) if result.status_code.is_bad() => {
+            let _ = session.delete_subscription(subscription_id);
+            Err(result.status_code)
+        }
+        _ => Ok(subscription_id),
+    }
+}
+
+/// Prints a startup error and exits, so configuration problems are reported without a panic.
+fn exit_on_error<T>(result: Result<T, String>) -> T {
+    result.unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    })
 }
 
 fn print_value(item: &MonitoredItem) {
@@ -74,15 +431,3 @@ fn print_value(item: &MonitoredItem) {
 mod test {
     use super::*;
 }
-pub fn send_kafka(broker: String, item: &MonitoredItem) {
-    let mut producer = 
-        Producer::from_hosts(vec!(broker.to_owned()))
-            .with_ack_timeout(Duration::from_secs(1))
-            .with_required_acks(RequiredAcks::One)
-            .create()
-            .unwrap();
-    let mut buf = String::with_capacity(2);
-    let _ = write!(&mut buf, "{:?}", item.last_value().value);
-    producer.send(&Record { key: ("start_kanban"), value: (buf.as_bytes()), topic: ("pss"), partition: (1) }).unwrap();
-    buf.clear();
-}
\ No newline at end of file
@@ -0,0 +1,315 @@
+//! MQTT output for monitored item changes, alongside or instead of Kafka.
+//!
+//! | Variable                  | Default      | Meaning                                              |
+//! |---------------------------|--------------|------------------------------------------------------|
+//! | `MQTT_BROKER`             |              | `host:port` of the broker, enables the MQTT output   |
+//! | `MQTT_CLIENT_ID`          | `dcs-<instance>` | MQTT client id                                   |
+//! | `MQTT_USERNAME`           |              | user name, when the broker requires one              |
+//! | `MQTT_PASSWORD`           |              | password of `MQTT_USERNAME`                          |
+//! | `MQTT_QOS`                | `1`          | QoS of published messages, 0 or 1                    |
+//! | `MQTT_KEEP_ALIVE_SECS`    | `30`         | keep alive interval                                  |
+//! | `MQTT_QUEUE_CAPACITY`     | `1000`       | messages waiting for the broker at most              |
+//! | `MQTT_MODE`               | `json`       | `json` or `sparkplug`                                |
+//! | `MQTT_TOPIC_PREFIX`       | `dcs`        | `json`: samples go to `<prefix>/<alias or tag>`      |
+//! | `MQTT_RETAIN`             | `false`      | `json`: retain the last sample of every tag          |
+//! | `SPARKPLUG_GROUP_ID`      | `dcs`        | `sparkplug`: group id                                |
+//! | `SPARKPLUG_EDGE_NODE_ID`  | instance id  | `sparkplug`: edge node id, `DCS_INSTANCE_ID` or the host name by default |
+//! | `SPARKPLUG_DEVICE_ID`     | `opcua`      | `sparkplug`: device id of the OPC UA server          |
+//!
+//! In `json` mode every sample is published as the JSON envelope of [`crate::sample`], and
+//! connection events go to `<prefix>/status`. In `sparkplug` mode samples are published as
+//! Sparkplug B metrics, see [`crate::sparkplug`].
+//!
+//! Publishing never blocks the subscription callback: when `MQTT_QUEUE_CAPACITY` messages are
+//! waiting, new ones are dropped. The client reconnects by itself after the connection is lost.
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use dotenvy::var;
+use opcua::sync::Mutex;
+use rumqttc::{Client, Connection, Event, LastWill, MqttOptions, Packet, QoS};
+
+use crate::client_config::{parse_bool, parse_u64, Secret};
+use crate::connection::ConnectionEvent;
+use crate::sample::TagSample;
+use crate::sparkplug::{EdgeNode, Message};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub enum MqttMode {
+    /// One JSON message per sample on `<prefix>/<alias or tag>`.
+    Json { topic_prefix: String, retain: bool },
+    Sparkplug { group_id: String, edge_node_id: String, device_id: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub credentials: Option<(String, Secret)>,
+    pub qos: QoS,
+    pub keep_alive: Duration,
+    pub queue_capacity: usize,
+    pub mode: MqttMode,
+}
+
+impl MqttConfig {
+    /// The MQTT settings, `None` when `MQTT_BROKER` is not set.
+    pub fn from_env(instance_id: &str) -> Result<Option<MqttConfig>, String> {
+        let Ok(broker) = var("MQTT_BROKER") else {
+            return Ok(None);
+        };
+        let (host, port) = match broker.trim().rsplit_once(':') {
+            Some((host, port)) => (host.to_string(), port.parse::<u16>()
+                .map_err(|_| format!("MQTT_BROKER \"{}\" has an invalid port", broker))?),
+            None => (broker.trim().to_string(), 1883),
+        };
+        if host.is_empty() {
+            return Err(format!("MQTT_BROKER \"{}\" has no host", broker));
+        }
+        let credentials = match var("MQTT_USERNAME") {
+            Ok(user) => Some((user, Secret::new(var("MQTT_PASSWORD").unwrap_or_default()))),
+            Err(_) => None,
+        };
+        let qos = match parse_u64("MQTT_QOS", 1)? {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            other => return Err(format!("MQTT_QOS {} must be 0 or 1", other)),
+        };
+        let mode = match var("MQTT_MODE").as_deref().map(str::trim) {
+            Ok("json") | Err(_) => MqttMode::Json {
+                topic_prefix: var("MQTT_TOPIC_PREFIX").unwrap_or_else(|_| "dcs".to_string()).trim_end_matches('/').to_string(),
+                retain: parse_bool("MQTT_RETAIN", false)?,
+            },
+            Ok("sparkplug") => MqttMode::Sparkplug {
+                group_id: sparkplug_id("SPARKPLUG_GROUP_ID", "dcs")?,
+                edge_node_id: sparkplug_id("SPARKPLUG_EDGE_NODE_ID", instance_id)?,
+                device_id: sparkplug_id("SPARKPLUG_DEVICE_ID", "opcua")?,
+            },
+            Ok(other) => return Err(format!("MQTT_MODE \"{}\" must be json or sparkplug", other)),
+        };
+        Ok(Some(MqttConfig {
+            host,
+            port,
+            client_id: var("MQTT_CLIENT_ID").unwrap_or_else(|_| format!("dcs-{}", instance_id)),
+            credentials,
+            qos,
+            keep_alive: Duration::from_secs(parse_u64("MQTT_KEEP_ALIVE_SECS", 30)?.max(5)),
+            queue_capacity: parse_u64("MQTT_QUEUE_CAPACITY", 1000)?.max(1) as usize,
+            mode,
+        }))
+    }
+}
+
+/// A Sparkplug id, which must not contain the topic separators and wildcards.
+fn sparkplug_id(name: &str, default: &str) -> Result<String, String> {
+    let id = var(name).unwrap_or_else(|_| default.to_string());
+    if id.is_empty() || id.contains(['/', '+', '#']) {
+        return Err(format!("{} \"{}\" must not be empty or contain /, + or #", name, id));
+    }
+    Ok(id)
+}
+
+/// The `json` mode message of a sample, on `<prefix>/<alias or tag>` with the MQTT wildcards
+/// of the name replaced.
+fn sample_message(topic_prefix: &str, sample: &TagSample) -> Result<Message, String> {
+    let name = sample.alias.as_deref().unwrap_or(&sample.tag).replace(['+', '#'], "_");
+    let payload = sample.to_json().map_err(|err| format!("cannot serialize sample: {}", err))?;
+    Ok(Message { topic: format!("{}/{}", topic_prefix, name), payload })
+}
+
+/// The `json` mode message of a connection event, published retained on `<prefix>/status`.
+fn status_message(topic_prefix: &str, event: &ConnectionEvent) -> Result<Message, String> {
+    let payload = serde_json::to_vec(event).map_err(|err| format!("cannot serialize event: {}", err))?;
+    Ok(Message { topic: format!("{}/status", topic_prefix), payload })
+}
+
+/// The sending side of the MQTT output, cheap to clone into callbacks.
+#[derive(Clone)]
+pub struct MqttHandle {
+    client: Client,
+    config: Arc<MqttConfig>,
+    /// The Sparkplug state in `sparkplug` mode.
+    node: Option<Arc<Mutex<EdgeNode>>>,
+    online: Arc<AtomicBool>,
+}
+
+/// Connects to the broker and starts the thread driving the MQTT connection.
+pub fn spawn_mqtt(config: MqttConfig) -> MqttHandle {
+    let mut options = MqttOptions::new(config.client_id.clone(), config.host.clone(), config.port);
+    options.set_keep_alive(config.keep_alive);
+    if let Some((ref user, ref password)) = config.credentials {
+        options.set_credentials(user.clone(), password.expose().to_string());
+    }
+    let node = match config.mode {
+        MqttMode::Sparkplug { ref group_id, ref edge_node_id, ref device_id } => {
+            let node = EdgeNode::new(group_id, edge_node_id, device_id);
+            options.set_last_will(last_will(node.death_certificate()));
+            Some(Arc::new(Mutex::new(node)))
+        }
+        MqttMode::Json { .. } => None,
+    };
+    let (client, connection) = Client::new(options, config.queue_capacity);
+    let handle = MqttHandle { client, config: Arc::new(config), node, online: Arc::new(AtomicBool::new(false)) };
+    let driver = handle.clone();
+    thread::spawn(move || driver.run(connection));
+    handle
+}
+
+fn last_will(death: Message) -> LastWill {
+    LastWill::new(death.topic, death.payload, QoS::AtLeastOnce, false)
+}
+
+impl MqttHandle {
+    /// Publishes the samples of one data change.
+    pub fn publish_samples(&self, samples: &[TagSample]) -> Result<(), String> {
+        match (&self.config.mode, &self.node) {
+            (_, Some(node)) => {
+                let mut node = node.lock();
+                let messages = node.data(samples);
+                self.publish_all(messages, false)
+            }
+            (MqttMode::Json { topic_prefix, retain }, None) => {
+                for sample in samples {
+                    let message = sample_message(topic_prefix, sample)?;
+                    self.publish(message.topic, message.payload, *retain)?;
+                }
+                Ok(())
+            }
+            (MqttMode::Sparkplug { .. }, None) => Ok(()),
+        }
+    }
+
+    /// Publishes a connection event to `<prefix>/status`, or in `sparkplug` mode births or
+    /// kills the device.
+    pub fn connection_event(&self, event: &ConnectionEvent) -> Result<(), String> {
+        match (&self.config.mode, &self.node) {
+            (_, Some(node)) => {
+                let mut node = node.lock();
+                if event.event == "disconnected" {
+                    let messages = node.device_offline();
+                    self.publish_all(messages, false)
+                } else {
+                    node.device_online();
+                    Ok(())
+                }
+            }
+            (MqttMode::Json { topic_prefix, .. }, None) => {
+                let message = status_message(topic_prefix, event)?;
+                self.publish(message.topic, message.payload, true)
+            }
+            (MqttMode::Sparkplug { .. }, None) => Ok(()),
+        }
+    }
+
+    fn publish_all(&self, messages: Vec<Message>, force: bool) -> Result<(), String> {
+        // Sparkplug messages are only valid in the session of their NBIRTH
+        if !force && !self.online.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let mut result = Ok(());
+        for message in messages {
+            result = result.and(self.publish(message.topic, message.payload, false));
+        }
+        result
+    }
+
+    fn publish(&self, topic: String, payload: Vec<u8>, retain: bool) -> Result<(), String> {
+        self.client.try_publish(topic.as_str(), self.config.qos, retain, payload)
+            .map_err(|err| format!("cannot publish to MQTT topic {}: {}", topic, err))
+    }
+
+    /// Drives the connection, sending the births after every connect and registering the
+    /// death certificate of the next session after every disconnect.
+    fn run(self, mut connection: Connection) {
+        let broker = format!("{}:{}", self.config.host, self.config.port);
+        loop {
+            match connection.recv() {
+                Ok(Ok(Event::Incoming(Packet::ConnAck(_)))) => {
+                    println!("MQTT connected to {}", broker);
+                    self.online.store(true, Ordering::Relaxed);
+                    if let Some(ref node) = self.node {
+                        let mut node = node.lock();
+                        if let Err(err) = self.client.try_subscribe(node.command_topic(), QoS::AtLeastOnce) {
+                            println!("Cannot subscribe to Sparkplug commands: {}", err);
+                        }
+                        let births = node.connected();
+                        if let Err(err) = self.publish_all(births, true) {
+                            println!("{}", err);
+                        }
+                    }
+                }
+                Ok(Ok(Event::Incoming(Packet::Publish(publish)))) => {
+                    if let Some(ref node) = self.node {
+                        let mut node = node.lock();
+                        if node.is_rebirth_request(&publish.topic, &publish.payload) {
+                            println!("Sparkplug rebirth requested");
+                            let births = node.connected();
+                            if let Err(err) = self.publish_all(births, true) {
+                                println!("{}", err);
+                            }
+                        }
+                    }
+                }
+                Ok(Ok(_)) => {}
+                Ok(Err(err)) => {
+                    println!("MQTT connection to {} failed, reconnecting in {:?}: {}", broker, RECONNECT_DELAY, err);
+                    self.online.store(false, Ordering::Relaxed);
+                    if let Some(ref node) = self.node {
+                        let mut node = node.lock();
+                        node.disconnected();
+                        connection.eventloop.mqtt_options.set_last_will(last_will(node.death_certificate()));
+                    }
+                    thread::sleep(RECONNECT_DELAY);
+                }
+                // The request channel is closed
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn sample(tag: &str, alias: Option<&str>) -> TagSample {
+        serde_json::from_value(json!({
+            "schema_version": 1,
+            "endpoint": "opc.tcp://plc1:4840",
+            "node_id": format!("ns=2;s={}", tag),
+            "tag": tag,
+            "alias": alias,
+            "value_type": "Double",
+            "value": 3.2,
+            "status": { "code": 0, "name": "Good" },
+            "source_timestamp": null,
+            "server_timestamp": null,
+        })).unwrap()
+    }
+
+    #[test]
+    fn publishes_samples_by_alias_or_tag() {
+        let message = sample_message("plant/dcs", &sample("Line1.Temp", Some("line1_temperature"))).unwrap();
+        assert_eq!(message.topic, "plant/dcs/line1_temperature");
+        let payload: Value = serde_json::from_slice(&message.payload).unwrap();
+        assert_eq!(payload["tag"], "Line1.Temp");
+        assert_eq!(payload["value"], 3.2);
+
+        assert_eq!(sample_message("dcs", &sample("Line1.Temp", None)).unwrap().topic, "dcs/Line1.Temp");
+        assert_eq!(sample_message("dcs", &sample("Tank#1+Level", None)).unwrap().topic, "dcs/Tank_1_Level");
+    }
+
+    #[test]
+    fn publishes_connection_events_to_the_status_topic() {
+        let message = status_message("dcs", &ConnectionEvent::new("disconnected", "opc.tcp://plc1:4840", 3)).unwrap();
+        assert_eq!(message.topic, "dcs/status");
+        let payload: Value = serde_json::from_slice(&message.payload).unwrap();
+        assert_eq!(payload["event"], "disconnected");
+        assert_eq!(payload["endpoint"], "opc.tcp://plc1:4840");
+    }
+}
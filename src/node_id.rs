@@ -0,0 +1,193 @@
+//! Node ids in the standard string syntax, with namespaces given by index or URI.
+//!
+//! ```text
+//! ns=3;i=1001
+//! ns=2;s=Line1.Temp
+//! nsu=urn:plant:line1;s=Line1.Temp
+//! ns=4;g=72962b91-fa75-4ae6-8d28-b404dc7daf63
+//! ns=5;b=M/RbKBsRVkePCePcx24oRA==
+//! i=2258
+//! ```
+//!
+//! Without `ns=` or `nsu=` the node is in namespace 0. Namespace URIs are only known to the
+//! server, so a parsed node id is resolved against the server's NamespaceArray after
+//! connecting.
+use std::fmt;
+use std::str::FromStr;
+use opcua::client::prelude::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Namespace {
+    Index(u16),
+    Uri(String),
+}
+
+/// A node id whose namespace may still be a URI.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeIdSpec {
+    pub namespace: Namespace,
+    pub identifier: Identifier,
+}
+
+impl NodeIdSpec {
+    /// Whether `value` is written in node id syntax rather than being a plain tag name.
+    pub fn is_node_id_syntax(value: &str) -> bool {
+        ["ns=", "nsu=", "i=", "s=", "g=", "b="].iter().any(|prefix| value.starts_with(prefix))
+    }
+
+    pub fn parse(value: &str) -> Result<NodeIdSpec, String> {
+        let value = value.trim();
+        let (namespace, identifier) = if let Some(rest) = value.strip_prefix("nsu=") {
+            let (uri, identifier) = rest.split_once(';')
+                .ok_or_else(|| format!("node id \"{}\" has no identifier after the namespace URI", value))?;
+            if uri.is_empty() {
+                return Err(format!("node id \"{}\" has an empty namespace URI", value));
+            }
+            (Namespace::Uri(uri.to_string()), identifier)
+        } else if let Some(rest) = value.strip_prefix("ns=") {
+            let (index, identifier) = rest.split_once(';')
+                .ok_or_else(|| format!("node id \"{}\" has no identifier after the namespace index", value))?;
+            let index = index.parse::<u16>()
+                .map_err(|_| format!("node id \"{}\" has an invalid namespace index \"{}\"", value, index))?;
+            (Namespace::Index(index), identifier)
+        } else {
+            (Namespace::Index(0), value)
+        };
+        let identifier = parse_identifier(identifier)
+            .map_err(|err| format!("node id \"{}\" {}", value, err))?;
+        Ok(NodeIdSpec { namespace, identifier })
+    }
+
+    pub fn has_namespace_uri(&self) -> bool {
+        matches!(self.namespace, Namespace::Uri(_))
+    }
+
+    /// The node id, with a namespace URI looked up in the server's NamespaceArray.
+    pub fn resolve(&self, namespaces: &[String]) -> Result<NodeId, String> {
+        let namespace = match self.namespace {
+            Namespace::Index(index) => index,
+            Namespace::Uri(ref uri) => namespaces.iter().position(|namespace| namespace == uri)
+                .ok_or_else(|| format!("namespace URI {} is not in the server NamespaceArray {:?}", uri, namespaces))?
+                as u16,
+        };
+        Ok(NodeId { namespace, identifier: self.identifier.clone() })
+    }
+}
+
+impl FromStr for NodeIdSpec {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        NodeIdSpec::parse(value)
+    }
+}
+
+impl fmt::Display for NodeIdSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.namespace {
+            Namespace::Index(0) => write!(f, "{}", self.identifier),
+            Namespace::Index(index) => write!(f, "ns={};{}", index, self.identifier),
+            Namespace::Uri(ref uri) => write!(f, "nsu={};{}", uri, self.identifier),
+        }
+    }
+}
+
+/// Parses the `i=`, `s=`, `g=` or `b=` part of a node id.
+pub fn parse_identifier(identifier: &str) -> Result<Identifier, String> {
+    if let Some(i) = identifier.strip_prefix("i=") {
+        i.parse::<u32>().map(Identifier::Numeric)
+            .map_err(|_| format!("has an invalid numeric identifier \"{}\"", i))
+    } else if let Some(s) = identifier.strip_prefix("s=") {
+        if s.is_empty() {
+            Err("has an empty string identifier".to_string())
+        } else {
+            Ok(Identifier::String(UAString::from(s)))
+        }
+    } else if let Some(g) = identifier.strip_prefix("g=") {
+        Guid::from_str(g).map(Identifier::Guid)
+            .map_err(|_| format!("has an invalid GUID \"{}\"", g))
+    } else if let Some(b) = identifier.strip_prefix("b=") {
+        ByteString::from_base64(b).map(Identifier::ByteString)
+            .ok_or_else(|| format!("has an invalid base64 opaque identifier \"{}\"", b))
+    } else {
+        Err(format!("has identifier \"{}\", expected one of i=, s=, g= or b=", identifier))
+    }
+}
+
+/// Reads the server's NamespaceArray, whose positions are the namespace indexes.
+pub fn read_namespace_array(session: &Session) -> Result<Vec<String>, StatusCode> {
+    let namespace_array = ReadValueId {
+        node_id: VariableId::Server_NamespaceArray.into(),
+        attribute_id: AttributeId::Value as u32,
+        index_range: UAString::null(),
+        data_encoding: QualifiedName::null(),
+    };
+    let results = session.read(&[namespace_array], TimestampsToReturn::Neither, 0.0)?;
+    match results.first().and_then(|data_value| data_value.value.as_ref()) {
+        Some(Variant::Array(array)) => Ok(array.values.iter()
+            .map(|value| match value {
+                Variant::String(uri) => uri.as_ref().to_string(),
+                _ => String::new(),
+            })
+            .collect()),
+        _ => Err(results.first().and_then(|data_value| data_value.status).unwrap_or(StatusCode::BadTypeMismatch)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_each_form() {
+        let spec = NodeIdSpec::parse("ns=2;s=Line1.Temp").unwrap();
+        assert_eq!(spec, NodeIdSpec { namespace: Namespace::Index(2), identifier: Identifier::String(UAString::from("Line1.Temp")) });
+        assert_eq!(spec.to_string(), "ns=2;s=Line1.Temp");
+
+        let spec = NodeIdSpec::parse("nsu=urn:plant:line1;i=1001").unwrap();
+        assert_eq!(spec, NodeIdSpec { namespace: Namespace::Uri("urn:plant:line1".to_string()), identifier: Identifier::Numeric(1001) });
+        assert!(spec.has_namespace_uri());
+        assert_eq!(spec.to_string(), "nsu=urn:plant:line1;i=1001");
+
+        let spec = NodeIdSpec::parse("ns=4;g=72962b91-fa75-4ae6-8d28-b404dc7daf63").unwrap();
+        assert_eq!(spec.identifier, Identifier::Guid(Guid::from_str("72962b91-fa75-4ae6-8d28-b404dc7daf63").unwrap()));
+
+        let spec = NodeIdSpec::parse("ns=5;b=M/RbKBsRVkePCePcx24oRA==").unwrap();
+        assert_eq!(spec.identifier, Identifier::ByteString(ByteString::from_base64("M/RbKBsRVkePCePcx24oRA==").unwrap()));
+    }
+
+    #[test]
+    fn identifiers_without_a_namespace_are_in_namespace_0() {
+        let spec: NodeIdSpec = " i=2258 ".parse().unwrap();
+        assert_eq!(spec, NodeIdSpec { namespace: Namespace::Index(0), identifier: Identifier::Numeric(2258) });
+        assert_eq!(spec.to_string(), "i=2258");
+        assert!(NodeIdSpec::is_node_id_syntax("s=Line1.Temp"));
+        assert!(!NodeIdSpec::is_node_id_syntax("Line1.Temp"));
+    }
+
+    #[test]
+    fn rejects_malformed_node_ids() {
+        let err = |value: &str| NodeIdSpec::parse(value).unwrap_err();
+        assert!(err("ns=2;g=not-a-guid").contains("invalid GUID \"not-a-guid\""));
+        assert!(err("ns=2;b=%%%").contains("invalid base64 opaque identifier"));
+        assert!(err("ns=2;i=x").contains("invalid numeric identifier"));
+        assert!(err("ns=2;s=").contains("empty string identifier"));
+        assert!(err("ns=2;Line1.Temp").contains("expected one of i=, s=, g= or b="));
+        assert!(err("Line1.Temp").contains("expected one of i=, s=, g= or b="));
+        assert!(err("ns=2").contains("no identifier after the namespace index"));
+        assert!(err("nsu=urn:plant").contains("no identifier after the namespace URI"));
+        assert!(err("nsu=;i=1").contains("empty namespace URI"));
+        assert!(err("ns=70000;i=1").contains("invalid namespace index \"70000\""));
+    }
+
+    #[test]
+    fn resolves_namespace_uris() {
+        let namespaces = vec!["http://opcfoundation.org/UA/".to_string(), "urn:plant:line1".to_string()];
+        let spec = NodeIdSpec::parse("nsu=urn:plant:line1;s=Line1.Temp").unwrap();
+        assert_eq!(spec.resolve(&namespaces).unwrap(), NodeId::new(1, "Line1.Temp"));
+        assert_eq!(NodeIdSpec::parse("ns=3;i=1").unwrap().resolve(&namespaces).unwrap(), NodeId::new(3, 1u32));
+
+        let err = NodeIdSpec::parse("nsu=urn:plant:line2;s=Line2.Temp").unwrap().resolve(&namespaces).unwrap_err();
+        assert!(err.contains("namespace URI urn:plant:line2 is not in the server NamespaceArray"), "{}", err);
+    }
+}
@@ -0,0 +1,379 @@
+//! Protobuf encoding of tag samples, following `proto/tag_sample.proto`.
+//!
+//! With `KAFKA_FORMAT=protobuf`, or per topic with `KAFKA_TOPIC_FORMATS`, each Kafka record
+//! holds one `dcs.v1.TagSample` message. Unlike the JSON and Avro formats the value keeps its
+//! OPC UA type: it is encoded from the `Variant` into the matching field of the `value` oneof.
+use opcua::types::{DateTime, DiagnosticInfo, ExtensionObjectEncoding, StatusCode, UAString, Variant};
+use prost::Message;
+use prost_types::Timestamp;
+
+use crate::sample::TagSample;
+
+/// The messages of `proto/tag_sample.proto` that dcs writes, in the form `prost-build`
+/// generates.
+pub mod proto {
+    use prost_types::Timestamp;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TagSample {
+        #[prost(uint32, tag = "1")]
+        pub schema_version: u32,
+        #[prost(string, tag = "2")]
+        pub endpoint: String,
+        #[prost(string, tag = "3")]
+        pub node_id: String,
+        #[prost(string, tag = "4")]
+        pub tag: String,
+        #[prost(string, optional, tag = "5")]
+        pub alias: Option<String>,
+        #[prost(string, optional, tag = "6")]
+        pub asset_id: Option<String>,
+        #[prost(string, optional, tag = "7")]
+        pub unit: Option<String>,
+        #[prost(string, tag = "8")]
+        pub value_type: String,
+        #[prost(message, optional, tag = "9")]
+        pub value: Option<Variant>,
+        #[prost(uint32, repeated, tag = "10")]
+        pub array_dimensions: Vec<u32>,
+        #[prost(message, optional, tag = "11")]
+        pub status: Option<Status>,
+        #[prost(message, optional, tag = "12")]
+        pub source_timestamp: Option<Timestamp>,
+        #[prost(message, optional, tag = "13")]
+        pub server_timestamp: Option<Timestamp>,
+        #[prost(bool, tag = "14")]
+        pub backfilled: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Status {
+        #[prost(uint32, tag = "1")]
+        pub code: u32,
+        #[prost(string, tag = "2")]
+        pub name: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Variant {
+        #[prost(oneof = "variant::Value", tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26")]
+        pub value: Option<variant::Value>,
+    }
+
+    pub mod variant {
+        use prost_types::Timestamp;
+
+        #[derive(Clone, PartialEq, prost::Oneof)]
+        pub enum Value {
+            #[prost(bool, tag = "1")]
+            BooleanValue(bool),
+            #[prost(sint32, tag = "2")]
+            SbyteValue(i32),
+            #[prost(uint32, tag = "3")]
+            ByteValue(u32),
+            #[prost(sint32, tag = "4")]
+            Int16Value(i32),
+            #[prost(uint32, tag = "5")]
+            Uint16Value(u32),
+            #[prost(sint32, tag = "6")]
+            Int32Value(i32),
+            #[prost(uint32, tag = "7")]
+            Uint32Value(u32),
+            #[prost(sint64, tag = "8")]
+            Int64Value(i64),
+            #[prost(uint64, tag = "9")]
+            Uint64Value(u64),
+            #[prost(float, tag = "10")]
+            FloatValue(f32),
+            #[prost(double, tag = "11")]
+            DoubleValue(f64),
+            #[prost(string, tag = "12")]
+            StringValue(String),
+            #[prost(message, tag = "13")]
+            DateTimeValue(Timestamp),
+            #[prost(string, tag = "14")]
+            GuidValue(String),
+            #[prost(message, tag = "15")]
+            StatusCodeValue(super::Status),
+            #[prost(bytes, tag = "16")]
+            ByteStringValue(Vec<u8>),
+            #[prost(string, tag = "17")]
+            XmlElementValue(String),
+            #[prost(message, tag = "18")]
+            QualifiedNameValue(super::QualifiedName),
+            #[prost(message, tag = "19")]
+            LocalizedTextValue(super::LocalizedText),
+            #[prost(string, tag = "20")]
+            NodeIdValue(String),
+            #[prost(string, tag = "21")]
+            ExpandedNodeIdValue(String),
+            #[prost(message, tag = "22")]
+            ExtensionObjectValue(super::ExtensionObject),
+            #[prost(message, tag = "23")]
+            VariantValue(Box<super::Variant>),
+            #[prost(message, tag = "24")]
+            DataValueValue(super::DataValue),
+            #[prost(message, tag = "25")]
+            DiagnosticInfoValue(super::DiagnosticInfo),
+            #[prost(message, tag = "26")]
+            ArrayValue(super::Array),
+        }
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Array {
+        #[prost(message, repeated, tag = "1")]
+        pub values: Vec<Variant>,
+        #[prost(uint32, repeated, tag = "2")]
+        pub dimensions: Vec<u32>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct QualifiedName {
+        #[prost(uint32, tag = "1")]
+        pub namespace_index: u32,
+        #[prost(string, tag = "2")]
+        pub name: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct LocalizedText {
+        #[prost(string, tag = "1")]
+        pub locale: String,
+        #[prost(string, tag = "2")]
+        pub text: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ExtensionObject {
+        #[prost(string, tag = "1")]
+        pub type_id: String,
+        #[prost(oneof = "extension_object::Body", tags = "2, 3")]
+        pub body: Option<extension_object::Body>,
+    }
+
+    pub mod extension_object {
+        #[derive(Clone, PartialEq, prost::Oneof)]
+        pub enum Body {
+            #[prost(bytes, tag = "2")]
+            Binary(Vec<u8>),
+            #[prost(string, tag = "3")]
+            Xml(String),
+        }
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct DataValue {
+        #[prost(message, optional, boxed, tag = "1")]
+        pub value: Option<Box<Variant>>,
+        #[prost(message, optional, tag = "2")]
+        pub status: Option<Status>,
+        #[prost(message, optional, tag = "3")]
+        pub source_timestamp: Option<Timestamp>,
+        #[prost(message, optional, tag = "4")]
+        pub server_timestamp: Option<Timestamp>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct DiagnosticInfo {
+        #[prost(sint32, optional, tag = "1")]
+        pub symbolic_id: Option<i32>,
+        #[prost(sint32, optional, tag = "2")]
+        pub namespace_uri: Option<i32>,
+        #[prost(sint32, optional, tag = "3")]
+        pub locale: Option<i32>,
+        #[prost(sint32, optional, tag = "4")]
+        pub localized_text: Option<i32>,
+        #[prost(string, optional, tag = "5")]
+        pub additional_info: Option<String>,
+        #[prost(message, optional, tag = "6")]
+        pub inner_status_code: Option<Status>,
+        #[prost(message, optional, boxed, tag = "7")]
+        pub inner_diagnostic_info: Option<Box<DiagnosticInfo>>,
+    }
+}
+
+use proto::variant::Value;
+
+/// Encodes a sample as a `dcs.v1.TagSample` message.
+pub fn encode_sample(sample: &TagSample) -> Vec<u8> {
+    proto::TagSample {
+        schema_version: sample.schema_version,
+        endpoint: sample.endpoint.clone(),
+        node_id: sample.node_id.clone(),
+        tag: sample.tag.clone(),
+        alias: sample.alias.clone(),
+        asset_id: sample.asset_id.clone(),
+        unit: sample.unit.clone(),
+        value_type: sample.value_type.clone(),
+        value: match sample.variant {
+            Variant::Empty => None,
+            ref variant => Some(variant_to_proto(variant)),
+        },
+        array_dimensions: sample.array_dimensions.clone().unwrap_or_default(),
+        status: Some(proto::Status { code: sample.status.code, name: sample.status.name.clone() }),
+        source_timestamp: sample.source_timestamp.as_deref().and_then(parse_timestamp),
+        server_timestamp: sample.server_timestamp.as_deref().and_then(parse_timestamp),
+        backfilled: sample.backfilled,
+    }
+    .encode_to_vec()
+}
+
+pub fn variant_to_proto(variant: &Variant) -> proto::Variant {
+    let value = match variant {
+        Variant::Empty => None,
+        Variant::Boolean(v) => Some(Value::BooleanValue(*v)),
+        Variant::SByte(v) => Some(Value::SbyteValue(*v as i32)),
+        Variant::Byte(v) => Some(Value::ByteValue(*v as u32)),
+        Variant::Int16(v) => Some(Value::Int16Value(*v as i32)),
+        Variant::UInt16(v) => Some(Value::Uint16Value(*v as u32)),
+        Variant::Int32(v) => Some(Value::Int32Value(*v)),
+        Variant::UInt32(v) => Some(Value::Uint32Value(*v)),
+        Variant::Int64(v) => Some(Value::Int64Value(*v)),
+        Variant::UInt64(v) => Some(Value::Uint64Value(*v)),
+        Variant::Float(v) => Some(Value::FloatValue(*v)),
+        Variant::Double(v) => Some(Value::DoubleValue(*v)),
+        Variant::String(ref v) => ua_string(v).map(Value::StringValue),
+        Variant::DateTime(ref v) => date_time_to_timestamp(v).map(Value::DateTimeValue),
+        Variant::Guid(ref v) => Some(Value::GuidValue(v.to_string())),
+        Variant::StatusCode(v) => Some(Value::StatusCodeValue(status(*v))),
+        Variant::ByteString(ref v) => v.value.clone().map(Value::ByteStringValue),
+        Variant::XmlElement(ref v) => ua_string(v).map(Value::XmlElementValue),
+        Variant::QualifiedName(ref v) => Some(Value::QualifiedNameValue(proto::QualifiedName {
+            namespace_index: v.namespace_index as u32,
+            name: ua_string(&v.name).unwrap_or_default(),
+        })),
+        Variant::LocalizedText(ref v) => Some(Value::LocalizedTextValue(proto::LocalizedText {
+            locale: ua_string(&v.locale).unwrap_or_default(),
+            text: ua_string(&v.text).unwrap_or_default(),
+        })),
+        Variant::NodeId(ref v) => Some(Value::NodeIdValue(v.to_string())),
+        Variant::ExpandedNodeId(ref v) => Some(Value::ExpandedNodeIdValue(v.to_string())),
+        Variant::ExtensionObject(ref v) => Some(Value::ExtensionObjectValue(proto::ExtensionObject {
+            type_id: v.node_id.to_string(),
+            body: match v.body {
+                ExtensionObjectEncoding::None => None,
+                ExtensionObjectEncoding::ByteString(ref body) => body.value.clone().map(proto::extension_object::Body::Binary),
+                ExtensionObjectEncoding::XmlElement(ref body) => ua_string(body).map(proto::extension_object::Body::Xml),
+            },
+        })),
+        Variant::Variant(ref v) => Some(Value::VariantValue(Box::new(variant_to_proto(v)))),
+        Variant::DataValue(ref v) => Some(Value::DataValueValue(proto::DataValue {
+            value: v.value.as_ref().map(|value| Box::new(variant_to_proto(value))),
+            status: Some(status(v.status.unwrap_or(StatusCode::Good))),
+            source_timestamp: v.source_timestamp.as_ref().and_then(date_time_to_timestamp),
+            server_timestamp: v.server_timestamp.as_ref().and_then(date_time_to_timestamp),
+        })),
+        Variant::DiagnosticInfo(ref v) => Some(Value::DiagnosticInfoValue(diagnostic_info_to_proto(v))),
+        Variant::Array(ref array) => Some(Value::ArrayValue(proto::Array {
+            values: array.values.iter().map(variant_to_proto).collect(),
+            dimensions: array.dimensions.clone().filter(|dimensions| dimensions.len() > 1).unwrap_or_default(),
+        })),
+    };
+    proto::Variant { value }
+}
+
+fn status(status: StatusCode) -> proto::Status {
+    proto::Status { code: status.bits(), name: status.name().to_string() }
+}
+
+fn ua_string(v: &UAString) -> Option<String> {
+    v.value().clone()
+}
+
+fn date_time_to_timestamp(v: &DateTime) -> Option<Timestamp> {
+    if v.is_null() {
+        return None;
+    }
+    let time = v.as_chrono();
+    Some(Timestamp { seconds: time.timestamp(), nanos: time.timestamp_subsec_nanos() as i32 })
+}
+
+/// Parses the RFC 3339 timestamps of the envelope.
+fn parse_timestamp(value: &str) -> Option<Timestamp> {
+    let time = chrono::DateTime::parse_from_rfc3339(value).ok()?;
+    Some(Timestamp { seconds: time.timestamp(), nanos: time.timestamp_subsec_nanos() as i32 })
+}
+
+fn diagnostic_info_to_proto(v: &DiagnosticInfo) -> proto::DiagnosticInfo {
+    proto::DiagnosticInfo {
+        symbolic_id: v.symbolic_id,
+        namespace_uri: v.namespace_uri,
+        locale: v.locale,
+        localized_text: v.localized_text,
+        additional_info: v.additional_info.as_ref().and_then(ua_string),
+        inner_status_code: v.inner_status_code.map(status),
+        inner_diagnostic_info: v.inner_diagnostic_info.as_ref().map(|inner| Box::new(diagnostic_info_to_proto(inner))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use opcua::types::{Array, DataValue, LocalizedText, NodeId, VariantTypeId};
+
+    fn sample(value: Variant) -> TagSample {
+        let data_value = DataValue {
+            value: Some(value),
+            status: Some(StatusCode::Good),
+            source_timestamp: Some(DateTime::from(Utc.timestamp_millis_opt(1_714_557_600_123).unwrap())),
+            source_picoseconds: None,
+            server_timestamp: None,
+            server_picoseconds: None,
+        };
+        TagSample::from_data_value("opc.tcp://plc1:4840", &NodeId::new(2, "Line1.Temp"), "Line1.Temp", &data_value)
+    }
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let mut sample = sample(Variant::Double(3.2));
+        sample.unit = Some("degC".to_string());
+        sample.backfilled = true;
+        let decoded = proto::TagSample::decode(encode_sample(&sample).as_slice()).unwrap();
+        assert_eq!(decoded, proto::TagSample {
+            schema_version: 1,
+            endpoint: "opc.tcp://plc1:4840".to_string(),
+            node_id: "ns=2;s=Line1.Temp".to_string(),
+            tag: "Line1.Temp".to_string(),
+            alias: None,
+            asset_id: None,
+            unit: Some("degC".to_string()),
+            value_type: "Double".to_string(),
+            value: Some(proto::Variant { value: Some(Value::DoubleValue(3.2)) }),
+            array_dimensions: Vec::new(),
+            status: Some(proto::Status { code: 0, name: "Good".to_string() }),
+            source_timestamp: Some(Timestamp { seconds: 1_714_557_600, nanos: 123_000_000 }),
+            server_timestamp: None,
+            backfilled: true,
+        });
+    }
+
+    #[test]
+    fn keeps_the_opc_ua_type_of_values() {
+        let matrix = Variant::Array(Box::new(Array {
+            value_type: VariantTypeId::UInt64,
+            values: vec![Variant::UInt64(u64::MAX), Variant::UInt64(0)],
+            dimensions: Some(vec![1, 2]),
+        }));
+        let decoded = proto::TagSample::decode(encode_sample(&sample(matrix)).as_slice()).unwrap();
+        assert_eq!(decoded.array_dimensions, [1, 2]);
+        assert_eq!(decoded.value.unwrap().value, Some(Value::ArrayValue(proto::Array {
+            values: vec![
+                proto::Variant { value: Some(Value::Uint64Value(u64::MAX)) },
+                proto::Variant { value: Some(Value::Uint64Value(0)) },
+            ],
+            dimensions: vec![1, 2],
+        })));
+
+        let text = Variant::from(LocalizedText::new("de", "Temperatur"));
+        let decoded = proto::TagSample::decode(encode_sample(&sample(text)).as_slice()).unwrap();
+        assert_eq!(decoded.value.unwrap().value, Some(Value::LocalizedTextValue(proto::LocalizedText {
+            locale: "de".to_string(),
+            text: "Temperatur".to_string(),
+        })));
+
+        let decoded = proto::TagSample::decode(encode_sample(&sample(Variant::Empty)).as_slice()).unwrap();
+        assert_eq!(decoded.value, None);
+    }
+}
@@ -0,0 +1,200 @@
+//! Routing of samples to Kafka topics, loaded from the file named by `ROUTING_FILE`.
+//!
+//! ```toml
+//! default_topic = "dcs.pv"
+//!
+//! [[route]]
+//! match = "prefix"
+//! pattern = "Alarm."
+//! topic = "dcs.alarms"
+//!
+//! [[route]]
+//! match = "regex"
+//! pattern = ".*\\.Count(er)?"
+//! topic = "dcs.counters"
+//! ```
+//!
+//! A route matches when its pattern matches either the node id in standard syntax
+//! (`ns=2;s=Alarm.Overheat`) or the tag name (`Alarm.Overheat`). A regex must match the whole
+//! node id or tag name, as if it were written `^(?:pattern)$`. Routes are tried in file
+//! order and the first match wins. Unmatched samples go to `default_topic`, which falls
+//! back to `KAFKA_TOPIC` when the file does not set it.
+use std::fs;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::sample::TagSample;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchKind {
+    Exact,
+    Prefix,
+    Regex,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RouteEntry {
+    #[serde(rename = "match")]
+    kind: MatchKind,
+    pattern: String,
+    topic: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RoutingFile {
+    default_topic: Option<String>,
+    #[serde(default)]
+    route: Vec<RouteEntry>,
+}
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    Exact(String),
+    Prefix(String),
+    Regex(Regex),
+}
+
+impl Matcher {
+    fn is_match(&self, value: &str) -> bool {
+        match self {
+            Matcher::Exact(pattern) => value == pattern,
+            Matcher::Prefix(pattern) => value.starts_with(pattern.as_str()),
+            Matcher::Regex(regex) => regex.is_match(value),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Route {
+    matcher: Matcher,
+    topic: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Router {
+    routes: Vec<Route>,
+    default_topic: String,
+}
+
+impl Router {
+    /// A router without routes, sending everything to `default_topic`.
+    pub fn new(default_topic: &str) -> Router {
+        Router { routes: Vec::new(), default_topic: default_topic.to_string() }
+    }
+
+    pub fn load(path: &str, default_topic: &str) -> Result<Router, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("cannot read routing file {}: {}", path, err))?;
+        Self::parse(&contents, path, default_topic)
+    }
+
+    fn parse(contents: &str, path: &str, default_topic: &str) -> Result<Router, String> {
+        let file: RoutingFile = toml::from_str(contents)
+            .map_err(|err| format!("invalid routing file {}: {}", path, err))?;
+        let mut routes = Vec::with_capacity(file.route.len());
+        for (index, entry) in file.route.into_iter().enumerate() {
+            if entry.topic.trim().is_empty() {
+                return Err(format!("routing file {}: route {} has an empty topic", path, index + 1));
+            }
+            let matcher = match entry.kind {
+                MatchKind::Exact => Matcher::Exact(entry.pattern),
+                MatchKind::Prefix => Matcher::Prefix(entry.pattern),
+                MatchKind::Regex => Matcher::Regex(Regex::new(&format!("^(?:{})$", entry.pattern))
+                    .map_err(|err| format!("routing file {}: route {} has an invalid regex: {}", path, index + 1, err))?),
+            };
+            routes.push(Route { matcher, topic: entry.topic });
+        }
+        let default_topic = file.default_topic.unwrap_or_else(|| default_topic.to_string());
+        Ok(Router { routes, default_topic })
+    }
+
+    /// The topic for a sample.
+    pub fn route(&self, sample: &TagSample) -> &str {
+        self.routes.iter()
+            .find(|route| route.matcher.is_match(&sample.node_id) || route.matcher.is_match(&sample.tag))
+            .map(|route| route.topic.as_str())
+            .unwrap_or(self.default_topic.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn sample(node_id: &str, tag: &str) -> TagSample {
+        serde_json::from_value(json!({
+            "schema_version": 1,
+            "endpoint": "opc.tcp://plc1:4840",
+            "node_id": node_id,
+            "tag": tag,
+            "value_type": "Double",
+            "value": 1.0,
+            "status": { "code": 0, "name": "Good" },
+            "source_timestamp": null,
+            "server_timestamp": null,
+        })).unwrap()
+    }
+
+    fn router() -> Router {
+        Router::parse(r#"
+            default_topic = "dcs.pv"
+
+            [[route]]
+            match = "exact"
+            pattern = "ns=2;s=Line1.Temp"
+            topic = "dcs.line1"
+
+            [[route]]
+            match = "prefix"
+            pattern = "Alarm."
+            topic = "dcs.alarms"
+
+            [[route]]
+            match = "regex"
+            pattern = ".*\\.Count(er)?"
+            topic = "dcs.counters"
+        "#, "routes.toml", "dcs.samples").unwrap()
+    }
+
+    #[test]
+    fn routes_by_exact_prefix_and_regex() {
+        let router = router();
+        assert_eq!(router.route(&sample("ns=2;s=Line1.Temp", "Line1.Temp")), "dcs.line1");
+        assert_eq!(router.route(&sample("ns=2;i=7", "Alarm.Overheat")), "dcs.alarms");
+        assert_eq!(router.route(&sample("ns=2;s=Press1.Counter", "Press1.Counter")), "dcs.counters");
+        assert_eq!(router.route(&sample("ns=2;s=Press1.Count", "Press1.Count")), "dcs.counters");
+    }
+
+    #[test]
+    fn anchors_the_regex() {
+        let router = router();
+        assert_eq!(router.route(&sample("ns=2;s=Press1.Counters", "Press1.Counters")), "dcs.pv");
+    }
+
+    #[test]
+    fn first_match_wins() {
+        // Matches the prefix and the regex route
+        let router = router();
+        assert_eq!(router.route(&sample("ns=2;s=Alarm.Count", "Alarm.Count")), "dcs.alarms");
+    }
+
+    #[test]
+    fn falls_back_to_the_default_topic() {
+        assert_eq!(router().route(&sample("ns=2;s=Line2.Temp", "Line2.Temp")), "dcs.pv");
+        let router = Router::parse("", "routes.toml", "dcs.samples").unwrap();
+        assert_eq!(router.route(&sample("ns=2;s=Line2.Temp", "Line2.Temp")), "dcs.samples");
+        assert_eq!(Router::new("dcs.samples").route(&sample("ns=2;s=Line2.Temp", "Line2.Temp")), "dcs.samples");
+    }
+
+    #[test]
+    fn rejects_invalid_routes() {
+        let err = Router::parse("[[route]]\nmatch = \"regex\"\npattern = \"(\"\ntopic = \"t\"\n", "routes.toml", "t").unwrap_err();
+        assert!(err.contains("route 1 has an invalid regex"), "{}", err);
+        let err = Router::parse("[[route]]\nmatch = \"exact\"\npattern = \"a\"\ntopic = \" \"\n", "routes.toml", "t").unwrap_err();
+        assert!(err.contains("route 1 has an empty topic"), "{}", err);
+    }
+}
@@ -0,0 +1,398 @@
+//! The JSON envelope published for every monitored item change.
+//!
+//! Each sample is serialized as one JSON object:
+//!
+//! ```json
+//! {
+//!   "schema_version": 1,
+//!   "endpoint": "opc.tcp://plc1:4840",
+//!   "node_id": "ns=2;s=Line1.Temp",
+//!   "tag": "Line1.Temp",
+//!   "alias": "line1_temperature",
+//!   "asset_id": "L1-TT-101",
+//!   "unit": "degC",
+//!   "value_type": "Double",
+//!   "value": 3.2,
+//!   "status": { "code": 0, "name": "Good" },
+//!   "source_timestamp": "2024-05-01T10:00:00.123Z",
+//!   "server_timestamp": "2024-05-01T10:00:00.125Z"
+//! }
+//! ```
+//!
+//! Samples read from the server history after an outage carry `"backfilled": true`, see
+//! [`crate::backfill`]. The field is left out on live samples.
+//!
+//! `tag` is the tag name from the tag configuration. `alias`, `asset_id` and `unit` come from
+//! the tag configuration as well and are left out when the tag has none.
+//! `value_type` is the OPC UA `Variant` type name, with `[]` appended for arrays.
+//! `value` is mapped from the `Variant` as follows, close to the OPC UA Part 6 JSON encoding:
+//!
+//! | Variant                                           | JSON                                                  |
+//! |---------------------------------------------------|-------------------------------------------------------|
+//! | Empty                                             | `null`                                                |
+//! | Boolean                                           | `true` / `false`                                      |
+//! | SByte, Byte, Int16, UInt16, Int32, UInt32         | number                                                |
+//! | Int64, UInt64                                     | decimal string, so JavaScript consumers keep precision |
+//! | Float, Double                                     | number, or `"NaN"`, `"Infinity"`, `"-Infinity"`       |
+//! | String, XmlElement                                | string, `null` when the string is null                |
+//! | DateTime                                          | RFC 3339 string in UTC, `null` for the null date      |
+//! | Guid                                              | string, e.g. `"72962b91-fa75-4ae6-8d28-b404dc7daf63"` |
+//! | ByteString                                        | base64 string, `null` when the byte string is null    |
+//! | StatusCode                                        | `{ "code": u32, "name": string }`                     |
+//! | QualifiedName                                     | `{ "namespace_index": u16, "name": string }`          |
+//! | LocalizedText                                     | `{ "locale": string, "text": string }`                |
+//! | NodeId, ExpandedNodeId                            | string in standard syntax, e.g. `"ns=3;i=1001"`       |
+//! | ExtensionObject                                   | `{ "type_id": string, "encoding": "binary" \| "xml" \| "none", "body": string }`, binary bodies in base64 |
+//! | Variant                                           | `{ "type": string, "value": ... }`                    |
+//! | DataValue                                         | `{ "value", "status", "source_timestamp", "server_timestamp" }` |
+//! | DiagnosticInfo                                    | object with the fields that are set                   |
+//! | Array                                             | JSON array of the mapped elements; multi dimensional arrays are flattened and `array_dimensions` is added to the envelope |
+//!
+//! `schema_version` is bumped whenever a field is removed or changes meaning.
+use opcua::client::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::tags::TagMapping;
+
+/// Version of the envelope layout written to `schema_version`.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SampleStatus {
+    pub code: u32,
+    pub name: String,
+}
+
+impl From<StatusCode> for SampleStatus {
+    fn from(status: StatusCode) -> Self {
+        SampleStatus { code: status.bits(), name: status.name().to_string() }
+    }
+}
+
+/// One published value of a monitored tag.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TagSample {
+    pub schema_version: u32,
+    pub endpoint: String,
+    pub node_id: String,
+    pub tag: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asset_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    pub value_type: String,
+    pub value: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub array_dimensions: Option<Vec<u32>>,
+    pub status: SampleStatus,
+    pub source_timestamp: Option<String>,
+    pub server_timestamp: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub backfilled: bool,
+    /// The value as received, for formats that keep its OPC UA type.
+    #[serde(skip)]
+    pub variant: Variant,
+}
+
+impl TagSample {
+    pub fn from_monitored_item(endpoint: &str, item: &MonitoredItem, tags: &TagMapping) -> TagSample {
+        Self::from_tag_value(endpoint, &item.item_to_monitor().node_id, tags, item.last_value())
+    }
+
+    /// A sample of a node, with the alias, asset id and unit of its tag.
+    pub fn from_tag_value(endpoint: &str, node_id: &NodeId, tags: &TagMapping, data_value: &DataValue) -> TagSample {
+        match tags.get(node_id) {
+            Some(tag) => {
+                let mut sample = Self::from_data_value(endpoint, node_id, &tag.name, data_value);
+                sample.alias = tag.alias.clone();
+                sample.asset_id = tag.asset_id.clone();
+                sample.unit = tag.unit.clone();
+                sample
+            }
+            None => Self::from_data_value(endpoint, node_id, &tag_name(node_id), data_value),
+        }
+    }
+
+    pub fn from_data_value(endpoint: &str, node_id: &NodeId, tag: &str, data_value: &DataValue) -> TagSample {
+        let (value_type, value, array_dimensions) = match data_value.value {
+            Some(ref variant) => (variant_type_name(variant), variant_to_json(variant), array_dimensions(variant)),
+            None => ("Empty".to_string(), Value::Null, None),
+        };
+        TagSample {
+            schema_version: SCHEMA_VERSION,
+            endpoint: endpoint.to_string(),
+            node_id: node_id.to_string(),
+            tag: tag.to_string(),
+            alias: None,
+            asset_id: None,
+            unit: None,
+            value_type,
+            value,
+            array_dimensions,
+            // A missing status code means Good
+            status: data_value.status.unwrap_or(StatusCode::Good).into(),
+            source_timestamp: data_value.source_timestamp.as_ref().and_then(date_time_to_string),
+            server_timestamp: data_value.server_timestamp.as_ref().and_then(date_time_to_string),
+            backfilled: false,
+            variant: data_value.value.clone().unwrap_or(Variant::Empty),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec(self)
+    }
+}
+
+/// The tag name of a node, which is its string identifier when it has one.
+pub fn tag_name(node_id: &NodeId) -> String {
+    match node_id.identifier {
+        Identifier::String(ref s) => s.as_ref().to_string(),
+        _ => node_id.to_string(),
+    }
+}
+
+pub fn variant_type_name(variant: &Variant) -> String {
+    let name = match variant {
+        Variant::Empty => "Empty",
+        Variant::Boolean(_) => "Boolean",
+        Variant::SByte(_) => "SByte",
+        Variant::Byte(_) => "Byte",
+        Variant::Int16(_) => "Int16",
+        Variant::UInt16(_) => "UInt16",
+        Variant::Int32(_) => "Int32",
+        Variant::UInt32(_) => "UInt32",
+        Variant::Int64(_) => "Int64",
+        Variant::UInt64(_) => "UInt64",
+        Variant::Float(_) => "Float",
+        Variant::Double(_) => "Double",
+        Variant::String(_) => "String",
+        Variant::DateTime(_) => "DateTime",
+        Variant::Guid(_) => "Guid",
+        Variant::StatusCode(_) => "StatusCode",
+        Variant::ByteString(_) => "ByteString",
+        Variant::XmlElement(_) => "XmlElement",
+        Variant::QualifiedName(_) => "QualifiedName",
+        Variant::LocalizedText(_) => "LocalizedText",
+        Variant::NodeId(_) => "NodeId",
+        Variant::ExpandedNodeId(_) => "ExpandedNodeId",
+        Variant::ExtensionObject(_) => "ExtensionObject",
+        Variant::Variant(_) => "Variant",
+        Variant::DataValue(_) => "DataValue",
+        Variant::DiagnosticInfo(_) => "DiagnosticInfo",
+        Variant::Array(ref array) => return format!("{:?}[]", array.value_type),
+    };
+    name.to_string()
+}
+
+fn array_dimensions(variant: &Variant) -> Option<Vec<u32>> {
+    match variant {
+        Variant::Array(ref array) => array.dimensions.clone().filter(|dimensions| dimensions.len() > 1),
+        _ => None,
+    }
+}
+
+pub fn variant_to_json(variant: &Variant) -> Value {
+    match variant {
+        Variant::Empty => Value::Null,
+        Variant::Boolean(v) => json!(v),
+        Variant::SByte(v) => json!(v),
+        Variant::Byte(v) => json!(v),
+        Variant::Int16(v) => json!(v),
+        Variant::UInt16(v) => json!(v),
+        Variant::Int32(v) => json!(v),
+        Variant::UInt32(v) => json!(v),
+        Variant::Int64(v) => json!(v.to_string()),
+        Variant::UInt64(v) => json!(v.to_string()),
+        Variant::Float(v) => float_to_json(*v as f64),
+        Variant::Double(v) => float_to_json(*v),
+        Variant::String(ref v) | Variant::XmlElement(ref v) => ua_string_to_json(v),
+        Variant::DateTime(ref v) => date_time_to_string(v).map(Value::String).unwrap_or(Value::Null),
+        Variant::Guid(ref v) => json!(v.to_string()),
+        Variant::StatusCode(v) => json!(SampleStatus::from(*v)),
+        Variant::ByteString(ref v) => byte_string_to_json(v),
+        Variant::QualifiedName(ref v) => json!({
+            "namespace_index": v.namespace_index,
+            "name": ua_string_to_json(&v.name),
+        }),
+        Variant::LocalizedText(ref v) => json!({
+            "locale": ua_string_to_json(&v.locale),
+            "text": ua_string_to_json(&v.text),
+        }),
+        Variant::NodeId(ref v) => json!(v.to_string()),
+        Variant::ExpandedNodeId(ref v) => json!(v.to_string()),
+        Variant::ExtensionObject(ref v) => {
+            let (encoding, body) = match v.body {
+                ExtensionObjectEncoding::None => ("none", Value::Null),
+                ExtensionObjectEncoding::ByteString(ref body) => ("binary", byte_string_to_json(body)),
+                ExtensionObjectEncoding::XmlElement(ref body) => ("xml", ua_string_to_json(body)),
+            };
+            json!({ "type_id": v.node_id.to_string(), "encoding": encoding, "body": body })
+        }
+        Variant::Variant(ref v) => json!({ "type": variant_type_name(v), "value": variant_to_json(v) }),
+        Variant::DataValue(ref v) => {
+            let status: SampleStatus = v.status.unwrap_or(StatusCode::Good).into();
+            json!({
+                "value": v.value.as_ref().map(variant_to_json).unwrap_or(Value::Null),
+                "status": status,
+                "source_timestamp": v.source_timestamp.as_ref().and_then(date_time_to_string),
+                "server_timestamp": v.server_timestamp.as_ref().and_then(date_time_to_string),
+            })
+        }
+        Variant::DiagnosticInfo(ref v) => diagnostic_info_to_json(v),
+        Variant::Array(ref array) => Value::Array(array.values.iter().map(variant_to_json).collect()),
+    }
+}
+
+fn float_to_json(v: f64) -> Value {
+    if v.is_nan() {
+        json!("NaN")
+    } else if v.is_infinite() {
+        json!(if v > 0.0 { "Infinity" } else { "-Infinity" })
+    } else {
+        json!(v)
+    }
+}
+
+fn ua_string_to_json(v: &UAString) -> Value {
+    v.value().as_ref().map(|s| Value::String(s.clone())).unwrap_or(Value::Null)
+}
+
+fn byte_string_to_json(v: &ByteString) -> Value {
+    if v.is_null() { Value::Null } else { Value::String(v.as_base64()) }
+}
+
+pub fn date_time_to_string(v: &DateTime) -> Option<String> {
+    if v.is_null() {
+        None
+    } else {
+        Some(v.as_chrono().to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true))
+    }
+}
+
+fn diagnostic_info_to_json(v: &DiagnosticInfo) -> Value {
+    let mut object = Map::new();
+    if let Some(symbolic_id) = v.symbolic_id {
+        object.insert("symbolic_id".to_string(), json!(symbolic_id));
+    }
+    if let Some(namespace_uri) = v.namespace_uri {
+        object.insert("namespace_uri".to_string(), json!(namespace_uri));
+    }
+    if let Some(locale) = v.locale {
+        object.insert("locale".to_string(), json!(locale));
+    }
+    if let Some(localized_text) = v.localized_text {
+        object.insert("localized_text".to_string(), json!(localized_text));
+    }
+    if let Some(ref additional_info) = v.additional_info {
+        object.insert("additional_info".to_string(), ua_string_to_json(additional_info));
+    }
+    if let Some(inner_status_code) = v.inner_status_code {
+        object.insert("inner_status_code".to_string(), json!(SampleStatus::from(inner_status_code)));
+    }
+    if let Some(ref inner_diagnostic_info) = v.inner_diagnostic_info {
+        object.insert("inner_diagnostic_info".to_string(), diagnostic_info_to_json(inner_diagnostic_info));
+    }
+    Value::Object(object)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn keeps_64_bit_integers_as_strings() {
+        assert_eq!(variant_to_json(&Variant::Int64(i64::MIN)), json!("-9223372036854775808"));
+        assert_eq!(variant_to_json(&Variant::UInt64(u64::MAX)), json!("18446744073709551615"));
+        assert_eq!(variant_to_json(&Variant::Int32(-7)), json!(-7));
+    }
+
+    #[test]
+    fn writes_special_floats_as_strings() {
+        assert_eq!(variant_to_json(&Variant::Double(f64::NAN)), json!("NaN"));
+        assert_eq!(variant_to_json(&Variant::Double(f64::INFINITY)), json!("Infinity"));
+        assert_eq!(variant_to_json(&Variant::Float(f32::NEG_INFINITY)), json!("-Infinity"));
+        assert_eq!(variant_to_json(&Variant::Double(3.25)), json!(3.25));
+    }
+
+    #[test]
+    fn encodes_byte_strings_in_base64() {
+        assert_eq!(variant_to_json(&Variant::ByteString(ByteString::from(vec![0xde, 0xad, 0xbe, 0xef]))), json!("3q2+7w=="));
+        assert_eq!(variant_to_json(&Variant::ByteString(ByteString::null())), Value::Null);
+    }
+
+    #[test]
+    fn flattens_arrays_and_keeps_their_dimensions() {
+        let matrix = Variant::Array(Box::new(Array {
+            value_type: VariantTypeId::Int32,
+            values: vec![Variant::Int32(1), Variant::Int32(2), Variant::Int32(3), Variant::Int32(4)],
+            dimensions: Some(vec![2, 2]),
+        }));
+        assert_eq!(variant_type_name(&matrix), "Int32[]");
+        assert_eq!(variant_to_json(&matrix), json!([1, 2, 3, 4]));
+        assert_eq!(array_dimensions(&matrix), Some(vec![2, 2]));
+
+        let list = Variant::Array(Box::new(Array {
+            value_type: VariantTypeId::Int64,
+            values: vec![Variant::Int64(1), Variant::Int64(2)],
+            dimensions: Some(vec![2]),
+        }));
+        assert_eq!(variant_to_json(&list), json!(["1", "2"]));
+        assert_eq!(array_dimensions(&list), None);
+    }
+
+    #[test]
+    fn writes_date_times_in_utc() {
+        let time = DateTime::from(Utc.timestamp_millis_opt(1_714_557_600_123).unwrap());
+        assert_eq!(variant_to_json(&Variant::from(time)), json!("2024-05-01T10:00:00.123Z"));
+        assert_eq!(variant_to_json(&Variant::from(DateTime::null())), Value::Null);
+    }
+
+    #[test]
+    fn writes_localized_text_as_an_object() {
+        let text = Variant::from(LocalizedText::new("en", "Temperature"));
+        assert_eq!(variant_to_json(&text), json!({ "locale": "en", "text": "Temperature" }));
+    }
+
+    #[test]
+    fn round_trips_the_envelope() {
+        let time = DateTime::from(Utc.timestamp_millis_opt(1_714_557_600_123).unwrap());
+        let data_value = DataValue {
+            value: Some(Variant::Double(3.2)),
+            status: Some(StatusCode::Good),
+            source_timestamp: Some(time),
+            source_picoseconds: None,
+            server_timestamp: None,
+            server_picoseconds: None,
+        };
+        let mut sample = TagSample::from_data_value("opc.tcp://plc1:4840", &NodeId::new(2, "Line1.Temp"), "Line1.Temp", &data_value);
+        sample.unit = Some("degC".to_string());
+
+        let json = sample.to_json().unwrap();
+        let value: Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(value, json!({
+            "schema_version": 1,
+            "endpoint": "opc.tcp://plc1:4840",
+            "node_id": "ns=2;s=Line1.Temp",
+            "tag": "Line1.Temp",
+            "unit": "degC",
+            "value_type": "Double",
+            "value": 3.2,
+            "status": { "code": 0, "name": "Good" },
+            "source_timestamp": "2024-05-01T10:00:00.123Z",
+            "server_timestamp": null,
+        }));
+
+        let decoded: TagSample = serde_json::from_slice(&json).unwrap();
+        assert_eq!(decoded.tag, sample.tag);
+        assert_eq!(decoded.unit, sample.unit);
+        assert_eq!(decoded.value, sample.value);
+        assert_eq!(decoded.status, sample.status);
+        assert_eq!(decoded.source_timestamp, sample.source_timestamp);
+        assert!(!decoded.backfilled);
+        assert_eq!(decoded.to_json().unwrap(), json);
+    }
+}
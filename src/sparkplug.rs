@@ -0,0 +1,410 @@
+//! Sparkplug B messages of the edge node and its OPC UA device.
+//!
+//! The edge node `SPARKPLUG_EDGE_NODE_ID` in group `SPARKPLUG_GROUP_ID` has one device,
+//! `SPARKPLUG_DEVICE_ID`, whose metrics are the monitored tags, named by their alias or tag
+//! name. [`EdgeNode`] turns connection changes and samples into the messages to publish:
+//!
+//! | Message  | Sent when                                                                   |
+//! |----------|-----------------------------------------------------------------------------|
+//! | `NBIRTH` | the MQTT session is established, and on a `Node Control/Rebirth` command    |
+//! | `DBIRTH` | the first samples after the OPC UA subscription is created, and whenever a sample brings a metric or data type the last `DBIRTH` did not have |
+//! | `DDATA`  | samples of metrics that were born                                           |
+//! | `DDEATH` | the OPC UA session is lost                                                  |
+//! | `NDEATH` | registered as the MQTT will, so the broker sends it when dcs goes away      |
+//!
+//! `NBIRTH` and the `NDEATH` will carry the same `bdSeq` metric, which is incremented for every
+//! new MQTT session. `NBIRTH` has `seq` 0 and every later message of the session the next
+//! `seq`, wrapping from 255 to 0.
+//!
+//! Values are mapped to the Sparkplug data type of their OPC UA type. Types without a
+//! Sparkplug counterpart, arrays included, are sent as `String` holding the JSON value.
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use opcua::types::Variant;
+use prost::Message as _;
+
+use crate::sample::TagSample;
+
+/// The messages of the Sparkplug B `sparkplug_b.proto` used here, in the form `prost-build`
+/// generates.
+pub mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Payload {
+        #[prost(uint64, optional, tag = "1")]
+        pub timestamp: Option<u64>,
+        #[prost(message, repeated, tag = "2")]
+        pub metrics: Vec<Metric>,
+        #[prost(uint64, optional, tag = "3")]
+        pub seq: Option<u64>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Metric {
+        #[prost(string, optional, tag = "1")]
+        pub name: Option<String>,
+        #[prost(uint64, optional, tag = "3")]
+        pub timestamp: Option<u64>,
+        #[prost(uint32, optional, tag = "4")]
+        pub datatype: Option<u32>,
+        #[prost(bool, optional, tag = "7")]
+        pub is_null: Option<bool>,
+        #[prost(oneof = "metric::Value", tags = "10, 11, 12, 13, 14, 15, 16")]
+        pub value: Option<metric::Value>,
+    }
+
+    pub mod metric {
+        #[derive(Clone, PartialEq, prost::Oneof)]
+        pub enum Value {
+            #[prost(uint32, tag = "10")]
+            IntValue(u32),
+            #[prost(uint64, tag = "11")]
+            LongValue(u64),
+            #[prost(float, tag = "12")]
+            FloatValue(f32),
+            #[prost(double, tag = "13")]
+            DoubleValue(f64),
+            #[prost(bool, tag = "14")]
+            BooleanValue(bool),
+            #[prost(string, tag = "15")]
+            StringValue(String),
+            #[prost(bytes, tag = "16")]
+            BytesValue(Vec<u8>),
+        }
+    }
+}
+
+use proto::metric::Value;
+
+/// Sparkplug B data types.
+pub mod data_type {
+    pub const INT8: u32 = 1;
+    pub const INT16: u32 = 2;
+    pub const INT32: u32 = 3;
+    pub const INT64: u32 = 4;
+    pub const UINT8: u32 = 5;
+    pub const UINT16: u32 = 6;
+    pub const UINT32: u32 = 7;
+    pub const UINT64: u32 = 8;
+    pub const FLOAT: u32 = 9;
+    pub const DOUBLE: u32 = 10;
+    pub const BOOLEAN: u32 = 11;
+    pub const STRING: u32 = 12;
+    pub const DATE_TIME: u32 = 13;
+    pub const UUID: u32 = 15;
+    pub const BYTES: u32 = 17;
+}
+
+const NAMESPACE: &str = "spBv1.0";
+const BD_SEQ: &str = "bdSeq";
+const REBIRTH: &str = "Node Control/Rebirth";
+
+/// A message to publish.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+/// The Sparkplug state of the edge node and its device.
+pub struct EdgeNode {
+    group_id: String,
+    edge_node_id: String,
+    device_id: String,
+    bd_seq: u64,
+    /// `seq` of the next message, `None` before `NBIRTH`.
+    seq: Option<u8>,
+    /// Whether the OPC UA subscription is running.
+    device_online: bool,
+    /// The last value of every metric, by name.
+    metrics: BTreeMap<String, proto::Metric>,
+    /// The data types in the last `DBIRTH`, empty while the device is not born.
+    born: BTreeMap<String, u32>,
+}
+
+impl EdgeNode {
+    pub fn new(group_id: &str, edge_node_id: &str, device_id: &str) -> EdgeNode {
+        EdgeNode {
+            group_id: group_id.to_string(),
+            edge_node_id: edge_node_id.to_string(),
+            device_id: device_id.to_string(),
+            bd_seq: 0,
+            seq: None,
+            device_online: false,
+            metrics: BTreeMap::new(),
+            born: BTreeMap::new(),
+        }
+    }
+
+    fn node_topic(&self, message_type: &str) -> String {
+        format!("{}/{}/{}/{}", NAMESPACE, self.group_id, message_type, self.edge_node_id)
+    }
+
+    fn device_topic(&self, message_type: &str) -> String {
+        format!("{}/{}/{}/{}/{}", NAMESPACE, self.group_id, message_type, self.edge_node_id, self.device_id)
+    }
+
+    /// The topic of `NCMD` messages to the edge node.
+    pub fn command_topic(&self) -> String {
+        self.node_topic("NCMD")
+    }
+
+    /// The `NDEATH` to register as the will of the next MQTT session.
+    pub fn death_certificate(&self) -> Message {
+        let payload = proto::Payload {
+            timestamp: Some(now_ms()),
+            metrics: vec![bd_seq_metric(self.bd_seq)],
+            seq: None,
+        };
+        Message { topic: self.node_topic("NDEATH"), payload: payload.encode_to_vec() }
+    }
+
+    /// The births of a new MQTT session, or of a rebirth.
+    pub fn connected(&mut self) -> Vec<Message> {
+        self.seq = Some(0);
+        let mut rebirth = metric(REBIRTH, data_type::BOOLEAN, Some(Value::BooleanValue(false)), now_ms());
+        rebirth.timestamp = None;
+        let nbirth = self.payload(vec![bd_seq_metric(self.bd_seq), rebirth]);
+        let mut messages = vec![Message { topic: self.node_topic("NBIRTH"), payload: nbirth }];
+        self.born.clear();
+        if self.device_online && !self.metrics.is_empty() {
+            messages.push(self.device_birth());
+        }
+        messages
+    }
+
+    /// The MQTT session is lost. The next session has a new `bdSeq`, so its will must be
+    /// registered again from [`EdgeNode::death_certificate`].
+    pub fn disconnected(&mut self) {
+        if self.seq.take().is_some() {
+            self.bd_seq = self.bd_seq.wrapping_add(1);
+        }
+        self.born.clear();
+    }
+
+    /// Whether `payload` on the command topic asks for a rebirth.
+    pub fn is_rebirth_request(&self, topic: &str, payload: &[u8]) -> bool {
+        topic == self.command_topic() && proto::Payload::decode(payload).is_ok_and(|payload| payload.metrics.iter()
+            .any(|metric| metric.name.as_deref() == Some(REBIRTH) && metric.value == Some(Value::BooleanValue(true))))
+    }
+
+    /// The OPC UA subscription is created. The device is born with the first samples.
+    pub fn device_online(&mut self) {
+        self.device_online = true;
+    }
+
+    /// The OPC UA session is lost.
+    pub fn device_offline(&mut self) -> Vec<Message> {
+        self.device_online = false;
+        if self.born.is_empty() || self.seq.is_none() {
+            self.born.clear();
+            return Vec::new();
+        }
+        self.born.clear();
+        let payload = self.payload(Vec::new());
+        vec![Message { topic: self.device_topic("DDEATH"), payload }]
+    }
+
+    /// The `DDATA`, or `DBIRTH` when a metric is new or changed its data type, for samples.
+    pub fn data(&mut self, samples: &[TagSample]) -> Vec<Message> {
+        let mut changed = Vec::with_capacity(samples.len());
+        for sample in samples {
+            let name = sample.alias.clone().unwrap_or_else(|| sample.tag.clone());
+            let timestamp = sample.source_timestamp.as_deref()
+                .and_then(|timestamp| chrono::DateTime::parse_from_rfc3339(timestamp).ok())
+                .map(|timestamp| timestamp.timestamp_millis() as u64)
+                .unwrap_or_else(now_ms);
+            let mut metric = match metric_value(&sample.variant) {
+                Some((datatype, value)) => metric(&name, datatype, value, timestamp),
+                // An empty value keeps the data type the metric had
+                None => {
+                    let datatype = self.metrics.get(&name).and_then(|metric| metric.datatype).unwrap_or(data_type::STRING);
+                    metric(&name, datatype, None, timestamp)
+                }
+            };
+            if sample.status.code & 0x8000_0000 != 0 {
+                // A Bad status has no usable value
+                metric.value = None;
+                metric.is_null = Some(true);
+            }
+            self.metrics.insert(name, metric.clone());
+            changed.push(metric);
+        }
+        if !self.device_online || self.seq.is_none() {
+            return Vec::new();
+        }
+        let reborn = changed.iter().any(|metric| {
+            self.born.get(metric.name.as_deref().unwrap_or_default()) != metric.datatype.as_ref()
+        });
+        if reborn {
+            return vec![self.device_birth()];
+        }
+        let payload = self.payload(changed);
+        vec![Message { topic: self.device_topic("DDATA"), payload }]
+    }
+
+    fn device_birth(&mut self) -> Message {
+        self.born = self.metrics.iter()
+            .map(|(name, metric)| (name.clone(), metric.datatype.unwrap_or(data_type::STRING)))
+            .collect();
+        let metrics = self.metrics.values().cloned().collect();
+        let payload = self.payload(metrics);
+        Message { topic: self.device_topic("DBIRTH"), payload }
+    }
+
+    fn payload(&mut self, metrics: Vec<proto::Metric>) -> Vec<u8> {
+        let seq = self.seq.unwrap_or(0);
+        self.seq = Some(seq.wrapping_add(1));
+        proto::Payload { timestamp: Some(now_ms()), metrics, seq: Some(seq as u64) }.encode_to_vec()
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_millis() as u64).unwrap_or(0)
+}
+
+fn metric(name: &str, datatype: u32, value: Option<Value>, timestamp: u64) -> proto::Metric {
+    proto::Metric {
+        name: Some(name.to_string()),
+        timestamp: Some(timestamp),
+        datatype: Some(datatype),
+        is_null: value.is_none().then_some(true),
+        value,
+    }
+}
+
+fn bd_seq_metric(bd_seq: u64) -> proto::Metric {
+    metric(BD_SEQ, data_type::UINT64, Some(Value::LongValue(bd_seq)), now_ms())
+}
+
+/// The Sparkplug data type and value of a variant, `None` for an empty variant. Signed
+/// integers are stored in two's complement as the specification requires.
+fn metric_value(variant: &Variant) -> Option<(u32, Option<Value>)> {
+    let value = match variant {
+        Variant::Empty => return None,
+        Variant::Boolean(v) => (data_type::BOOLEAN, Some(Value::BooleanValue(*v))),
+        Variant::SByte(v) => (data_type::INT8, Some(Value::IntValue(*v as i32 as u32))),
+        Variant::Byte(v) => (data_type::UINT8, Some(Value::IntValue(*v as u32))),
+        Variant::Int16(v) => (data_type::INT16, Some(Value::IntValue(*v as i32 as u32))),
+        Variant::UInt16(v) => (data_type::UINT16, Some(Value::IntValue(*v as u32))),
+        Variant::Int32(v) => (data_type::INT32, Some(Value::IntValue(*v as u32))),
+        Variant::UInt32(v) => (data_type::UINT32, Some(Value::LongValue(*v as u64))),
+        Variant::Int64(v) => (data_type::INT64, Some(Value::LongValue(*v as u64))),
+        Variant::UInt64(v) => (data_type::UINT64, Some(Value::LongValue(*v))),
+        Variant::Float(v) => (data_type::FLOAT, Some(Value::FloatValue(*v))),
+        Variant::Double(v) => (data_type::DOUBLE, Some(Value::DoubleValue(*v))),
+        Variant::String(v) => (data_type::STRING, v.value().clone().map(Value::StringValue)),
+        Variant::DateTime(v) => (data_type::DATE_TIME, (!v.is_null()).then(|| Value::LongValue(v.as_chrono().timestamp_millis() as u64))),
+        Variant::Guid(v) => (data_type::UUID, Some(Value::StringValue(v.to_string()))),
+        Variant::ByteString(v) => (data_type::BYTES, v.value.clone().map(Value::BytesValue)),
+        other => (data_type::STRING, Some(Value::StringValue(crate::sample::variant_to_json(other).to_string()))),
+    };
+    Some(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use prost::Message as _;
+    use crate::sample::{SampleStatus, SCHEMA_VERSION};
+
+    fn sample(tag: &str, variant: Variant) -> TagSample {
+        TagSample {
+            schema_version: SCHEMA_VERSION,
+            endpoint: "opc.tcp://plc1:4840".to_string(),
+            node_id: format!("ns=2;s={}", tag),
+            tag: tag.to_string(),
+            alias: None,
+            asset_id: None,
+            unit: None,
+            value_type: crate::sample::variant_type_name(&variant),
+            value: crate::sample::variant_to_json(&variant),
+            array_dimensions: None,
+            status: SampleStatus { code: 0, name: "Good".to_string() },
+            source_timestamp: None,
+            server_timestamp: None,
+            backfilled: false,
+            variant,
+        }
+    }
+
+    fn decode(message: &Message) -> proto::Payload {
+        proto::Payload::decode(message.payload.as_slice()).unwrap()
+    }
+
+    fn bd_seq(payload: &proto::Payload) -> Option<u64> {
+        payload.metrics.iter().find(|metric| metric.name.as_deref() == Some(BD_SEQ))
+            .and_then(|metric| match metric.value {
+                Some(Value::LongValue(bd_seq)) => Some(bd_seq),
+                _ => None,
+            })
+    }
+
+    #[test]
+    fn birth_and_death_sequence() {
+        let mut node = EdgeNode::new("plant", "dcs1", "plc1");
+        assert_eq!(bd_seq(&decode(&node.death_certificate())), Some(0));
+        let births = node.connected();
+        assert_eq!(births.len(), 1);
+        assert_eq!(births[0].topic, "spBv1.0/plant/NBIRTH/dcs1");
+        assert_eq!(decode(&births[0]).seq, Some(0));
+        assert_eq!(bd_seq(&decode(&births[0])), Some(0));
+
+        // Samples before the subscription is up are only remembered
+        assert!(node.data(&[sample("Temp", Variant::Double(21.5))]).is_empty());
+        node.device_online();
+        let messages = node.data(&[sample("Temp", Variant::Double(21.6))]);
+        assert_eq!(messages[0].topic, "spBv1.0/plant/DBIRTH/dcs1/plc1");
+        assert_eq!(decode(&messages[0]).seq, Some(1));
+        let messages = node.data(&[sample("Temp", Variant::Double(21.7))]);
+        assert_eq!(messages[0].topic, "spBv1.0/plant/DDATA/dcs1/plc1");
+        assert_eq!(decode(&messages[0]).seq, Some(2));
+        // A new metric needs a new birth
+        let messages = node.data(&[sample("Count", Variant::Int32(-1))]);
+        assert_eq!(messages[0].topic, "spBv1.0/plant/DBIRTH/dcs1/plc1");
+        let payload = decode(&messages[0]);
+        assert_eq!(payload.metrics.len(), 2);
+        let count = payload.metrics.iter().find(|metric| metric.name.as_deref() == Some("Count")).unwrap();
+        assert_eq!(count.datatype, Some(data_type::INT32));
+        assert_eq!(count.value, Some(Value::IntValue(u32::MAX)));
+
+        let messages = node.device_offline();
+        assert_eq!(messages[0].topic, "spBv1.0/plant/DDEATH/dcs1/plc1");
+        assert_eq!(decode(&messages[0]).seq, Some(4));
+
+        // A new MQTT session has a new bdSeq and starts over at seq 0
+        node.disconnected();
+        assert_eq!(bd_seq(&decode(&node.death_certificate())), Some(1));
+        node.device_online();
+        let births = node.connected();
+        assert_eq!(births.len(), 2);
+        assert_eq!(bd_seq(&decode(&births[0])), Some(1));
+        assert_eq!(decode(&births[0]).seq, Some(0));
+        assert_eq!(births[1].topic, "spBv1.0/plant/DBIRTH/dcs1/plc1");
+        assert_eq!(decode(&births[1]).seq, Some(1));
+    }
+
+    #[test]
+    fn seq_wraps_after_255() {
+        let mut node = EdgeNode::new("plant", "dcs1", "plc1");
+        node.connected();
+        node.device_online();
+        let mut last = 0;
+        for value in 0..300 {
+            let messages = node.data(&[sample("Temp", Variant::Double(value as f64))]);
+            last = decode(&messages[0]).seq.unwrap();
+        }
+        assert_eq!(last, 300 % 256);
+    }
+
+    #[test]
+    fn rebirth_request() {
+        let node = EdgeNode::new("plant", "dcs1", "plc1");
+        let command = proto::Payload {
+            timestamp: Some(0),
+            metrics: vec![metric(REBIRTH, data_type::BOOLEAN, Some(Value::BooleanValue(true)), 0)],
+            seq: None,
+        };
+        assert!(node.is_rebirth_request("spBv1.0/plant/NCMD/dcs1", &command.encode_to_vec()));
+        assert!(!node.is_rebirth_request("spBv1.0/plant/NCMD/other", &command.encode_to_vec()));
+    }
+}
@@ -0,0 +1,653 @@
+//! A durable on-disk queue of Kafka records, used while Kafka cannot be reached.
+//!
+//! Records are appended to segment files `<sequence>.seg` in `SPOOL_DIR`. Each record is
+//!
+//! ```text
+//! u32 length | u32 crc32 | u64 spooled at (ms since epoch) | payload
+//! ```
+//!
+//! in little endian, where the CRC covers the timestamp and the payload and the payload is
+//!
+//! ```text
+//! u16 topic length | topic | u32 key length | key | i32 partition
+//!     | u16 header count | (u16 name length | name | u32 value length | value)* | value
+//! ```
+//!
+//! Records are read back in the order they were appended. The read position is kept in the
+//! `cursor` file, which is written every [`CURSOR_SYNC_INTERVAL`] records and whenever a
+//! segment is finished, so after a crash a few records may be replayed twice. A record with
+//! a bad checksum, such as a torn write at the end of a segment, ends that segment.
+//!
+//! | Variable              | Default        | Meaning                                               |
+//! |-----------------------|----------------|-------------------------------------------------------|
+//! | `SPOOL_DIR`           | `spool`        | directory of the segment files                        |
+//! | `SPOOL_SEGMENT_BYTES` | `16777216`     | size at which a new segment is started                |
+//! | `SPOOL_MAX_BYTES`     | `1073741824`   | size limit of the whole spool                         |
+//! | `SPOOL_MAX_AGE_SECS`  | `604800`       | records older than this are dropped, 0 for no limit   |
+//! | `SPOOL_EVICTION`      | `drop-oldest`  | at the size limit, `drop-oldest` deletes the oldest segment, `drop-newest` refuses new records |
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use dotenvy::var;
+
+use crate::client_config::parse_u64;
+
+const SEGMENT_EXTENSION: &str = "seg";
+const HEADER_BYTES: u64 = 16;
+/// Larger lengths can only come from a corrupt header.
+const MAX_RECORD_BYTES: usize = 256 * 1024 * 1024;
+/// Records read between two writes of the cursor file.
+pub const CURSOR_SYNC_INTERVAL: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Eviction {
+    DropOldest,
+    DropNewest,
+}
+
+#[derive(Debug, Clone)]
+pub struct SpoolConfig {
+    pub dir: PathBuf,
+    pub segment_bytes: u64,
+    pub max_bytes: u64,
+    pub max_age: Option<Duration>,
+    pub eviction: Eviction,
+}
+
+impl SpoolConfig {
+    pub fn from_env() -> Result<SpoolConfig, String> {
+        let segment_bytes = parse_u64("SPOOL_SEGMENT_BYTES", 16 * 1024 * 1024)?.max(HEADER_BYTES);
+        let max_bytes = parse_u64("SPOOL_MAX_BYTES", 1024 * 1024 * 1024)?;
+        if max_bytes < segment_bytes {
+            return Err(format!("SPOOL_MAX_BYTES {} must not be smaller than SPOOL_SEGMENT_BYTES {}", max_bytes, segment_bytes));
+        }
+        let max_age = match parse_u64("SPOOL_MAX_AGE_SECS", 7 * 24 * 3600)? {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        let eviction = match var("SPOOL_EVICTION").as_deref().map(str::trim) {
+            Ok("drop-oldest") | Err(_) => Eviction::DropOldest,
+            Ok("drop-newest") => Eviction::DropNewest,
+            Ok(other) => return Err(format!("SPOOL_EVICTION \"{}\" must be drop-oldest or drop-newest", other)),
+        };
+        Ok(SpoolConfig {
+            dir: PathBuf::from(var("SPOOL_DIR").unwrap_or_else(|_| "spool".to_string())),
+            segment_bytes,
+            max_bytes,
+            max_age,
+            eviction,
+        })
+    }
+}
+
+/// A Kafka record waiting in the spool.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpooledRecord {
+    pub spooled_at_ms: u64,
+    pub topic: String,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    pub partition: i32,
+    /// Record headers as name and value.
+    pub headers: Vec<(String, Vec<u8>)>,
+}
+
+impl SpooledRecord {
+    pub fn new(topic: &str, key: &[u8], value: &[u8], partition: i32) -> SpooledRecord {
+        SpooledRecord {
+            spooled_at_ms: now_ms(),
+            topic: topic.to_string(),
+            key: key.to_vec(),
+            value: value.to_vec(),
+            partition,
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn with_headers(mut self, headers: Vec<(String, Vec<u8>)>) -> SpooledRecord {
+        self.headers = headers;
+        self
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(10 + self.topic.len() + self.key.len() + self.value.len());
+        payload.extend_from_slice(&(self.topic.len() as u16).to_le_bytes());
+        payload.extend_from_slice(self.topic.as_bytes());
+        payload.extend_from_slice(&(self.key.len() as u32).to_le_bytes());
+        payload.extend_from_slice(&self.key);
+        payload.extend_from_slice(&self.partition.to_le_bytes());
+        payload.extend_from_slice(&(self.headers.len() as u16).to_le_bytes());
+        for (name, value) in &self.headers {
+            payload.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            payload.extend_from_slice(name.as_bytes());
+            payload.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            payload.extend_from_slice(value);
+        }
+        payload.extend_from_slice(&self.value);
+        let mut checksum = crc32fast::Hasher::new();
+        checksum.update(&self.spooled_at_ms.to_le_bytes());
+        checksum.update(&payload);
+        let mut record = Vec::with_capacity(HEADER_BYTES as usize + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&checksum.finalize().to_le_bytes());
+        record.extend_from_slice(&self.spooled_at_ms.to_le_bytes());
+        record.extend_from_slice(&payload);
+        record
+    }
+
+    fn decode(spooled_at_ms: u64, payload: &[u8]) -> Option<SpooledRecord> {
+        let topic_len = u16::from_le_bytes(payload.get(0..2)?.try_into().ok()?) as usize;
+        let topic = String::from_utf8(payload.get(2..2 + topic_len)?.to_vec()).ok()?;
+        let mut offset = 2 + topic_len;
+        let key_len = u32::from_le_bytes(payload.get(offset..offset + 4)?.try_into().ok()?) as usize;
+        offset += 4;
+        let key = payload.get(offset..offset + key_len)?.to_vec();
+        offset += key_len;
+        let partition = i32::from_le_bytes(payload.get(offset..offset + 4)?.try_into().ok()?);
+        offset += 4;
+        let header_count = u16::from_le_bytes(payload.get(offset..offset + 2)?.try_into().ok()?) as usize;
+        offset += 2;
+        let mut headers = Vec::with_capacity(header_count);
+        for _ in 0..header_count {
+            let name_len = u16::from_le_bytes(payload.get(offset..offset + 2)?.try_into().ok()?) as usize;
+            offset += 2;
+            let name = String::from_utf8(payload.get(offset..offset + name_len)?.to_vec()).ok()?;
+            offset += name_len;
+            let value_len = u32::from_le_bytes(payload.get(offset..offset + 4)?.try_into().ok()?) as usize;
+            offset += 4;
+            headers.push((name, payload.get(offset..offset + value_len)?.to_vec()));
+            offset += value_len;
+        }
+        Some(SpooledRecord { spooled_at_ms, topic, key, value: payload[offset..].to_vec(), partition, headers })
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_millis() as u64).unwrap_or(0)
+}
+
+/// Reads the record at the current position of `file`. `Ok(None)` means the end of the
+/// segment, or a torn or corrupt record that ends it.
+fn read_record(file: &mut File) -> io::Result<Option<(SpooledRecord, u64)>> {
+    let mut header = [0u8; HEADER_BYTES as usize];
+    match file.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let length = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let spooled_at_ms = u64::from_le_bytes(header[8..16].try_into().unwrap());
+    if length > MAX_RECORD_BYTES {
+        return Ok(None);
+    }
+    let mut payload = vec![0u8; length];
+    match file.read_exact(&mut payload) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let mut checksum = crc32fast::Hasher::new();
+    checksum.update(&header[8..16]);
+    checksum.update(&payload);
+    if checksum.finalize() != crc {
+        return Ok(None);
+    }
+    Ok(SpooledRecord::decode(spooled_at_ms, &payload).map(|record| (record, HEADER_BYTES + length as u64)))
+}
+
+struct Segment {
+    sequence: u64,
+    /// Bytes of valid records in the file.
+    bytes: u64,
+    records: u64,
+}
+
+/// Backlog metrics of the spool.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SpoolStats {
+    /// Records waiting to be replayed.
+    pub records: u64,
+    /// Bytes of the waiting records.
+    pub bytes: u64,
+    pub segments: usize,
+    /// When the oldest waiting record was spooled, in ms since the epoch.
+    pub oldest_ms: Option<u64>,
+    /// Records deleted by the size limit with `drop-oldest` or by the age limit.
+    pub evicted: u64,
+    /// Records refused by the size limit with `drop-newest`.
+    pub refused: u64,
+}
+
+pub struct Spool {
+    config: SpoolConfig,
+    /// Oldest first, the last one is appended to.
+    segments: VecDeque<Segment>,
+    next_sequence: u64,
+    writer: Option<File>,
+    reader: Option<File>,
+    /// Read position in the first segment.
+    read_offset: u64,
+    read_records: u64,
+    /// Sizes of the records returned by the last [`Spool::front_batch`] that are not popped yet.
+    front_sizes: VecDeque<u64>,
+    unsynced_reads: u64,
+    oldest_ms: Option<u64>,
+    evicted: u64,
+    refused: u64,
+}
+
+impl Spool {
+    /// Opens the spool, checking every segment and cutting off torn records at their end.
+    pub fn open(config: SpoolConfig) -> io::Result<Spool> {
+        fs::create_dir_all(&config.dir)?;
+        let mut sequences: Vec<u64> = fs::read_dir(&config.dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let path = entry.path();
+                if path.extension().and_then(|extension| extension.to_str()) != Some(SEGMENT_EXTENSION) {
+                    return None;
+                }
+                path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse::<u64>().ok())
+            })
+            .collect();
+        sequences.sort_unstable();
+        let mut spool = Spool {
+            config,
+            segments: VecDeque::new(),
+            next_sequence: 0,
+            writer: None,
+            reader: None,
+            read_offset: 0,
+            read_records: 0,
+            front_sizes: VecDeque::new(),
+            unsynced_reads: 0,
+            oldest_ms: None,
+            evicted: 0,
+            refused: 0,
+        };
+        let (cursor_sequence, cursor_offset) = spool.read_cursor();
+        spool.next_sequence = cursor_sequence;
+        for sequence in sequences {
+            spool.next_sequence = spool.next_sequence.max(sequence + 1);
+            if sequence < cursor_sequence {
+                // Fully replayed before the cursor was last written
+                fs::remove_file(spool.segment_path(sequence))?;
+                continue;
+            }
+            let mut file = OpenOptions::new().read(true).write(true).open(spool.segment_path(sequence))?;
+            let mut segment = Segment { sequence, bytes: 0, records: 0 };
+            while let Some((_, size)) = read_record(&mut file)? {
+                if sequence == cursor_sequence && segment.bytes < cursor_offset {
+                    spool.read_records += 1;
+                }
+                segment.bytes += size;
+                segment.records += 1;
+            }
+            if file.metadata()?.len() > segment.bytes {
+                println!("Spool segment {} has a torn or corrupt record at byte {}, dropping the rest of it", sequence, segment.bytes);
+                file.set_len(segment.bytes)?;
+            }
+            if sequence == cursor_sequence {
+                spool.read_offset = cursor_offset.min(segment.bytes);
+            }
+            spool.segments.push_back(segment);
+        }
+        if spool.segments.front().is_some_and(|segment| segment.sequence != cursor_sequence) {
+            spool.read_offset = 0;
+            spool.read_records = 0;
+        }
+        Ok(spool)
+    }
+
+    fn segment_path(&self, sequence: u64) -> PathBuf {
+        self.config.dir.join(format!("{:020}.{}", sequence, SEGMENT_EXTENSION))
+    }
+
+    fn cursor_path(&self) -> PathBuf {
+        self.config.dir.join("cursor")
+    }
+
+    fn read_cursor(&self) -> (u64, u64) {
+        fs::read_to_string(self.cursor_path()).ok()
+            .and_then(|cursor| {
+                let (sequence, offset) = cursor.trim().split_once(' ')?;
+                Some((sequence.parse().ok()?, offset.parse().ok()?))
+            })
+            .unwrap_or((0, 0))
+    }
+
+    fn write_cursor(&mut self) -> io::Result<()> {
+        let sequence = self.segments.front().map(|segment| segment.sequence).unwrap_or(self.next_sequence);
+        let temporary = self.config.dir.join("cursor.tmp");
+        fs::write(&temporary, format!("{} {}\n", sequence, self.read_offset))?;
+        fs::rename(temporary, self.cursor_path())?;
+        self.unsynced_reads = 0;
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stats().records == 0
+    }
+
+    pub fn stats(&self) -> SpoolStats {
+        let records = self.segments.iter().map(|segment| segment.records).sum::<u64>() - self.read_records;
+        let bytes = self.segments.iter().map(|segment| segment.bytes).sum::<u64>() - self.read_offset;
+        SpoolStats {
+            records,
+            bytes,
+            segments: self.segments.len(),
+            oldest_ms: if records > 0 { self.oldest_ms } else { None },
+            evicted: self.evicted,
+            refused: self.refused,
+        }
+    }
+
+    /// Appends a record. Returns `false` when the record was refused by the size limit.
+    pub fn push(&mut self, record: &SpooledRecord) -> io::Result<bool> {
+        let encoded = record.encode();
+        let was_empty = self.is_empty();
+        while self.stats().bytes + encoded.len() as u64 > self.config.max_bytes {
+            if self.config.eviction == Eviction::DropNewest || self.segments.is_empty() {
+                self.refused += 1;
+                return Ok(false);
+            }
+            self.evict_oldest_segment()?;
+        }
+        let needs_segment = match self.segments.back() {
+            Some(segment) => self.writer.is_none() || segment.bytes >= self.config.segment_bytes,
+            None => true,
+        };
+        if needs_segment {
+            if let Some(writer) = self.writer.take() {
+                writer.sync_all()?;
+            }
+            let sequence = self.next_sequence;
+            self.next_sequence += 1;
+            let writer = OpenOptions::new().create(true).write(true).truncate(true).open(self.segment_path(sequence))?;
+            self.segments.push_back(Segment { sequence, bytes: 0, records: 0 });
+            self.writer = Some(writer);
+        }
+        self.writer.as_mut().unwrap().write_all(&encoded)?;
+        let segment = self.segments.back_mut().unwrap();
+        segment.bytes += encoded.len() as u64;
+        segment.records += 1;
+        if was_empty {
+            self.oldest_ms = Some(record.spooled_at_ms);
+        }
+        Ok(true)
+    }
+
+    fn evict_oldest_segment(&mut self) -> io::Result<()> {
+        if let Some(segment) = self.segments.front() {
+            let evicted = segment.records - self.read_records;
+            println!("Spool is full, evicting segment {} with {} records", segment.sequence, evicted);
+            self.evicted += evicted;
+            self.oldest_ms = None;
+            self.finish_segment()?;
+        }
+        Ok(())
+    }
+
+    /// Up to `max_records` of the oldest waiting records, all from one segment, without
+    /// removing them. Records older than the age limit are dropped on the way.
+    pub fn front_batch(&mut self, max_records: usize) -> io::Result<Vec<SpooledRecord>> {
+        self.front_sizes.clear();
+        let Some((first, size)) = self.read_front()? else {
+            return Ok(Vec::new());
+        };
+        let bytes = self.segments.front().map(|segment| segment.bytes).unwrap_or(0);
+        let mut offset = self.read_offset + size;
+        let mut records = vec![first];
+        self.front_sizes.push_back(size);
+        // The reader is positioned right after the first record
+        let reader = self.reader.as_mut().unwrap();
+        while records.len() < max_records && offset < bytes {
+            match read_record(reader)? {
+                Some((record, size)) => {
+                    offset += size;
+                    records.push(record);
+                    self.front_sizes.push_back(size);
+                }
+                None => break,
+            }
+        }
+        Ok(records)
+    }
+
+    fn read_front(&mut self) -> io::Result<Option<(SpooledRecord, u64)>> {
+        loop {
+            let Some(&Segment { sequence, bytes, records }) = self.segments.front() else {
+                return Ok(None);
+            };
+            if self.read_offset >= bytes {
+                if self.segments.len() == 1 {
+                    // Nothing is waiting, the writer starts a new segment
+                    return Ok(None);
+                }
+                self.finish_segment()?;
+                continue;
+            }
+            if self.reader.is_none() {
+                self.reader = Some(File::open(self.segment_path(sequence))?);
+            }
+            let reader = self.reader.as_mut().unwrap();
+            reader.seek(SeekFrom::Start(self.read_offset))?;
+            match read_record(reader)? {
+                Some((record, size)) => {
+                    let expired = self.config.max_age
+                        .is_some_and(|max_age| now_ms().saturating_sub(record.spooled_at_ms) > max_age.as_millis() as u64);
+                    if expired {
+                        self.evicted += 1;
+                        self.advance(size)?;
+                        continue;
+                    }
+                    self.oldest_ms = Some(record.spooled_at_ms);
+                    return Ok(Some((record, size)));
+                }
+                None => {
+                    // The segment was cut short after it was opened, skip what is left of it
+                    println!("Spool segment {} cannot be read at byte {}, skipping the rest of it", sequence, self.read_offset);
+                    self.evicted += records - self.read_records;
+                    self.read_offset = bytes;
+                    self.read_records = records;
+                }
+            }
+        }
+    }
+
+    /// Removes the first `count` records returned by the last [`Spool::front_batch`].
+    pub fn pop_batch(&mut self, count: usize) -> io::Result<()> {
+        for _ in 0..count {
+            let Some(size) = self.front_sizes.pop_front() else {
+                break;
+            };
+            self.oldest_ms = None;
+            self.advance(size)?;
+        }
+        Ok(())
+    }
+
+    fn advance(&mut self, size: u64) -> io::Result<()> {
+        self.read_offset += size;
+        self.read_records += 1;
+        self.unsynced_reads += 1;
+        if self.segments.front().is_some_and(|segment| self.read_offset >= segment.bytes) {
+            // A finished segment is deleted, also the one being written to
+            self.finish_segment()
+        } else if self.unsynced_reads >= CURSOR_SYNC_INTERVAL {
+            self.write_cursor()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn finish_segment(&mut self) -> io::Result<()> {
+        if let Some(segment) = self.segments.pop_front() {
+            if self.segments.is_empty() {
+                self.writer = None;
+            }
+            self.reader = None;
+            self.front_sizes.clear();
+            self.read_offset = 0;
+            self.read_records = 0;
+            fs::remove_file(self.segment_path(segment.sequence))?;
+        }
+        self.write_cursor()
+    }
+
+    /// Writes the read position and flushes appended records, so both survive a restart.
+    pub fn sync(&mut self) -> io::Result<()> {
+        if let Some(writer) = self.writer.as_ref() {
+            writer.sync_data()?;
+        }
+        self.write_cursor()
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        let _ = self.sync();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(dir: &tempfile::TempDir, segment_bytes: u64, max_bytes: u64, eviction: Eviction) -> SpoolConfig {
+        SpoolConfig { dir: dir.path().to_path_buf(), segment_bytes, max_bytes, max_age: None, eviction }
+    }
+
+    fn record(n: u32) -> SpooledRecord {
+        SpooledRecord::new("dcs.pv", format!("k{}", n).as_bytes(), format!("value {}", n).as_bytes(), -1)
+            .with_headers(vec![("dcs-sequence".to_string(), n.to_string().into_bytes())])
+    }
+
+    /// Reads and pops every waiting record.
+    fn drain(spool: &mut Spool) -> Vec<SpooledRecord> {
+        let mut drained = Vec::new();
+        loop {
+            let batch = spool.front_batch(3).unwrap();
+            if batch.is_empty() {
+                return drained;
+            }
+            spool.pop_batch(batch.len()).unwrap();
+            drained.extend(batch);
+        }
+    }
+
+    fn keys(records: &[SpooledRecord]) -> Vec<String> {
+        records.iter().map(|record| String::from_utf8(record.key.clone()).unwrap()).collect()
+    }
+
+    #[test]
+    fn replays_in_order_across_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        // Two records per segment
+        let segment_bytes = 2 * record(0).encode().len() as u64;
+        let mut spool = Spool::open(config(&dir, segment_bytes, 1 << 20, Eviction::DropOldest)).unwrap();
+        let records: Vec<SpooledRecord> = (0..9).map(record).collect();
+        for record in &records {
+            assert!(spool.push(record).unwrap());
+        }
+        let stats = spool.stats();
+        assert_eq!((stats.records, stats.segments), (9, 5));
+        assert_eq!(stats.oldest_ms, Some(records[0].spooled_at_ms));
+
+        // A batch never spans segments
+        assert_eq!(spool.front_batch(10).unwrap(), records[0..2]);
+        assert_eq!(drain(&mut spool), records);
+        assert!(spool.is_empty());
+    }
+
+    #[test]
+    fn resumes_at_the_cursor_after_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut records: Vec<SpooledRecord> = (0..5).map(record).collect();
+        {
+            let mut spool = Spool::open(config(&dir, 1 << 20, 1 << 20, Eviction::DropOldest)).unwrap();
+            for record in &records {
+                spool.push(record).unwrap();
+            }
+            assert_eq!(spool.front_batch(3).unwrap().len(), 3);
+            // Only the first two were sent
+            spool.pop_batch(2).unwrap();
+        }
+        let mut spool = Spool::open(config(&dir, 1 << 20, 1 << 20, Eviction::DropOldest)).unwrap();
+        assert_eq!(spool.stats().records, 3);
+        records.push(record(5));
+        spool.push(&records[5]).unwrap();
+        assert_eq!(drain(&mut spool), records[2..]);
+    }
+
+    #[test]
+    fn drops_a_torn_last_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut records: Vec<SpooledRecord> = (0..3).map(record).collect();
+        {
+            let mut spool = Spool::open(config(&dir, 1 << 20, 1 << 20, Eviction::DropOldest)).unwrap();
+            for record in &records {
+                spool.push(record).unwrap();
+            }
+        }
+        let segment = dir.path().join(format!("{:020}.{}", 0, SEGMENT_EXTENSION));
+        let length = fs::metadata(&segment).unwrap().len();
+        OpenOptions::new().write(true).open(&segment).unwrap().set_len(length - 3).unwrap();
+
+        let mut spool = Spool::open(config(&dir, 1 << 20, 1 << 20, Eviction::DropOldest)).unwrap();
+        assert_eq!(spool.stats().records, 2);
+        assert_eq!(fs::metadata(&segment).unwrap().len(), length - records[2].encode().len() as u64);
+        records[2] = record(3);
+        spool.push(&records[2]).unwrap();
+        assert_eq!(drain(&mut spool), records);
+    }
+
+    #[test]
+    fn drop_oldest_evicts_the_oldest_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let size = record(0).encode().len() as u64;
+        let mut spool = Spool::open(config(&dir, size, 3 * size, Eviction::DropOldest)).unwrap();
+        for n in 0..5 {
+            assert!(spool.push(&record(n)).unwrap());
+        }
+        let stats = spool.stats();
+        assert_eq!((stats.records, stats.evicted, stats.refused), (3, 2, 0));
+        assert_eq!(keys(&drain(&mut spool)), ["k2", "k3", "k4"]);
+    }
+
+    #[test]
+    fn drop_newest_refuses_new_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let size = record(0).encode().len() as u64;
+        let mut spool = Spool::open(config(&dir, size, 3 * size, Eviction::DropNewest)).unwrap();
+        for n in 0..3 {
+            assert!(spool.push(&record(n)).unwrap());
+        }
+        assert!(!spool.push(&record(3)).unwrap());
+        assert!(!spool.push(&record(4)).unwrap());
+        let stats = spool.stats();
+        assert_eq!((stats.records, stats.evicted, stats.refused), (3, 0, 2));
+        assert_eq!(keys(&drain(&mut spool)), ["k0", "k1", "k2"]);
+    }
+
+    #[test]
+    fn expires_records_by_age() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = config(&dir, 1 << 20, 1 << 20, Eviction::DropOldest);
+        config.max_age = Some(Duration::from_secs(60));
+        let mut spool = Spool::open(config).unwrap();
+        for n in 0..4 {
+            let mut record = record(n);
+            if n < 2 {
+                record.spooled_at_ms -= 61_000;
+            }
+            spool.push(&record).unwrap();
+        }
+        assert_eq!(keys(&spool.front_batch(10).unwrap()), ["k2", "k3"]);
+        assert_eq!(spool.stats().evicted, 2);
+        assert_eq!(spool.stats().records, 2);
+    }
+}
@@ -0,0 +1,487 @@
+//! The monitored tags, loaded from the TOML file named by `TAGS_FILE`.
+//!
+//! ```toml
+//! # Optional values used by every tag that does not set its own
+//! [defaults]
+//! sampling_interval = 1000.0
+//! queue_size = 1
+//! measurement = "plant"
+//! labels = { site = "north" }
+//!
+//! [[tag]]
+//! name = "Line1.Temp"
+//! namespace = 2
+//! s = "Line1.Temp"
+//! alias = "line1_temperature"
+//! asset_id = "L1-TT-101"
+//! unit = "degC"
+//! sampling_interval = 500.0
+//! deadband = 0.5
+//! queue_size = 10
+//! topic = "dcs.pv"
+//! measurement = "temperature"
+//! field = "celsius"
+//! labels = { line = "1" }
+//!
+//! [[tag]]
+//! name = "Line1.Count"
+//! namespace_uri = "urn:plant:line1"
+//! i = 1001
+//!
+//! [[tag]]
+//! name = "Line2.Level"
+//! node_id = "nsu=urn:plant:line2;s=Level"
+//! ```
+//!
+//! The node of a tag is either `node_id` in standard node id syntax (see [`crate::node_id`]),
+//! or a namespace, either `namespace` (index, default 2) or `namespace_uri`, and exactly one
+//! identifier: `s` (string), `i` (numeric), `g` (GUID) or `b` (opaque, base64). A tag without
+//! an identifier uses its `name` as string identifier, so `[[tag]] name = "Line1.Temp"` is the
+//! same as `Line1.Temp` in `MONITORED_TAGS`.
+//!
+//! Optional settings per tag:
+//!
+//! * `alias` and `asset_id`, published with each sample and usable as Kafka key.
+//! * `unit`, the engineering unit published with each sample.
+//! * `sampling_interval` in milliseconds, -1 (the default) uses the publishing interval.
+//! * `deadband` with `deadband_type` `"absolute"` (the default) or `"percent"`.
+//! * `queue_size`, the server side queue size, 1 by default.
+//! * `topic`, the Kafka topic of the tag, which takes precedence over `ROUTING_FILE`.
+//! * `measurement`, `field` and `labels`, where the time-series database sinks write the
+//!   tag, see [`crate::timeseries`]. Labels are merged with the default labels.
+//!
+//! Namespace URIs are resolved to indexes through the server's NamespaceArray after
+//! connecting. When `TAGS_FILE` is not set, the comma separated `MONITORED_TAGS` are
+//! monitored. Entries in node id syntax (`ns=3;i=1001`) are used as they are, plain names
+//! are string identifiers in namespace 2.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use opcua::client::prelude::*;
+use serde::Deserialize;
+
+use crate::node_id::{parse_identifier, Namespace, NodeIdSpec};
+
+/// The namespace index used when a tag does not set one.
+const DEFAULT_NAMESPACE: u16 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeadbandKind {
+    Absolute,
+    Percent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Deadband {
+    pub kind: DeadbandKind,
+    pub value: f64,
+}
+
+/// A validated tag from the tag file, before its namespace is resolved.
+#[derive(Debug, Clone)]
+pub struct TagConfig {
+    pub name: String,
+    pub node: NodeIdSpec,
+    pub alias: Option<String>,
+    pub asset_id: Option<String>,
+    pub unit: Option<String>,
+    pub sampling_interval: f64,
+    pub deadband: Option<Deadband>,
+    pub queue_size: u32,
+    pub topic: Option<String>,
+    pub measurement: Option<String>,
+    pub field: Option<String>,
+    pub labels: BTreeMap<String, String>,
+}
+
+impl TagConfig {
+    /// A tag with the default settings.
+    pub fn new(name: String, node: NodeIdSpec) -> TagConfig {
+        TagConfig {
+            name,
+            node,
+            alias: None,
+            asset_id: None,
+            unit: None,
+            sampling_interval: -1.0,
+            deadband: None,
+            queue_size: 1,
+            topic: None,
+            measurement: None,
+            field: None,
+            labels: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TagDefaults {
+    sampling_interval: Option<f64>,
+    queue_size: Option<u32>,
+    deadband: Option<f64>,
+    deadband_type: Option<DeadbandKind>,
+    measurement: Option<String>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TagEntry {
+    name: Option<String>,
+    node_id: Option<String>,
+    namespace: Option<u16>,
+    namespace_uri: Option<String>,
+    s: Option<String>,
+    i: Option<u32>,
+    g: Option<String>,
+    b: Option<String>,
+    alias: Option<String>,
+    asset_id: Option<String>,
+    unit: Option<String>,
+    sampling_interval: Option<f64>,
+    deadband: Option<f64>,
+    deadband_type: Option<DeadbandKind>,
+    queue_size: Option<u32>,
+    topic: Option<String>,
+    measurement: Option<String>,
+    field: Option<String>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TagFile {
+    #[serde(default)]
+    defaults: TagDefaults,
+    #[serde(default)]
+    tag: Vec<TagEntry>,
+}
+
+impl TagEntry {
+    fn validate(self, defaults: &TagDefaults) -> Result<TagConfig, String> {
+        let identifiers = [self.s.is_some(), self.i.is_some(), self.g.is_some(), self.b.is_some()];
+        let identifier_count = identifiers.iter().filter(|set| **set).count();
+        let node = if let Some(node_id) = self.node_id {
+            if self.namespace.is_some() || self.namespace_uri.is_some() || identifier_count > 0 {
+                return Err("sets node_id together with namespace, namespace_uri, s, i, g or b".to_string());
+            }
+            NodeIdSpec::parse(&node_id)?
+        } else {
+            let namespace = match (self.namespace, self.namespace_uri) {
+                (Some(_), Some(_)) => return Err("sets both namespace and namespace_uri".to_string()),
+                (Some(index), None) => Namespace::Index(index),
+                (None, Some(uri)) if uri.trim().is_empty() => return Err("has an empty namespace_uri".to_string()),
+                (None, Some(uri)) => Namespace::Uri(uri),
+                (None, None) => Namespace::Index(DEFAULT_NAMESPACE),
+            };
+            if identifier_count > 1 {
+                return Err("sets more than one of s, i, g and b".to_string());
+            }
+            let identifier = if let Some(s) = self.s {
+                parse_identifier(&format!("s={}", s))?
+            } else if let Some(i) = self.i {
+                Identifier::Numeric(i)
+            } else if let Some(g) = self.g {
+                parse_identifier(&format!("g={}", g))?
+            } else if let Some(b) = self.b {
+                parse_identifier(&format!("b={}", b))?
+            } else if let Some(ref name) = self.name {
+                Identifier::String(UAString::from(name.as_str()))
+            } else {
+                return Err("has neither a name, a node_id nor one of s, i, g and b".to_string());
+            };
+            NodeIdSpec { namespace, identifier }
+        };
+        let name = match self.name {
+            Some(name) if name.trim().is_empty() => return Err("has an empty name".to_string()),
+            Some(name) => name,
+            None => node.to_string(),
+        };
+        let sampling_interval = self.sampling_interval.or(defaults.sampling_interval).unwrap_or(-1.0);
+        if !sampling_interval.is_finite() || (sampling_interval < 0.0 && sampling_interval != -1.0) {
+            return Err(format!("has an invalid sampling_interval {}, expected milliseconds or -1", sampling_interval));
+        }
+        let deadband = match self.deadband.or(defaults.deadband) {
+            Some(value) => {
+                let kind = self.deadband_type.or(defaults.deadband_type).unwrap_or(DeadbandKind::Absolute);
+                if !value.is_finite() || value < 0.0 || (kind == DeadbandKind::Percent && value > 100.0) {
+                    return Err(format!("has an invalid deadband {}", value));
+                }
+                Some(Deadband { kind, value })
+            }
+            None if self.deadband_type.is_some() => return Err("sets deadband_type without a deadband".to_string()),
+            None => None,
+        };
+        let queue_size = self.queue_size.or(defaults.queue_size).unwrap_or(1);
+        if queue_size == 0 {
+            return Err("has a queue_size of 0".to_string());
+        }
+        if self.topic.as_ref().is_some_and(|topic| topic.trim().is_empty()) {
+            return Err("has an empty topic".to_string());
+        }
+        let measurement = self.measurement.or_else(|| defaults.measurement.clone());
+        if measurement.as_ref().is_some_and(|measurement| measurement.trim().is_empty()) {
+            return Err("has an empty measurement".to_string());
+        }
+        if self.field.as_ref().is_some_and(|field| field.trim().is_empty()) {
+            return Err("has an empty field".to_string());
+        }
+        let mut labels = defaults.labels.clone();
+        labels.extend(self.labels);
+        if labels.keys().any(|label| label.trim().is_empty()) {
+            return Err("has a label with an empty name".to_string());
+        }
+        Ok(TagConfig {
+            name,
+            node,
+            alias: self.alias,
+            asset_id: self.asset_id,
+            unit: self.unit,
+            sampling_interval,
+            deadband,
+            queue_size,
+            topic: self.topic,
+            measurement,
+            field: self.field,
+            labels,
+        })
+    }
+}
+
+/// The configured tags, before namespace URIs are resolved.
+#[derive(Debug, Clone)]
+pub struct TagList {
+    pub tags: Vec<TagConfig>,
+}
+
+impl TagList {
+    pub fn load(path: &str) -> Result<TagList, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("cannot read tags file {}: {}", path, err))?;
+        Self::parse(&contents, path)
+    }
+
+    fn parse(contents: &str, path: &str) -> Result<TagList, String> {
+        let file: TagFile = toml::from_str(contents)
+            .map_err(|err| format!("invalid tags file {}: {}", path, err))?;
+        if file.tag.is_empty() {
+            return Err(format!("tags file {} does not define any [[tag]]", path));
+        }
+        let mut tags = Vec::with_capacity(file.tag.len());
+        for (index, entry) in file.tag.into_iter().enumerate() {
+            let label = entry.name.clone().map(|name| format!(" (\"{}\")", name)).unwrap_or_default();
+            let tag = entry.validate(&file.defaults)
+                .map_err(|err| format!("tags file {}: tag {}{} {}", path, index + 1, label, err))?;
+            tags.push(tag);
+        }
+        let list = TagList { tags };
+        list.check_unique(path)?;
+        Ok(list)
+    }
+
+    /// The tags of the comma separated `MONITORED_TAGS`, either in node id syntax or plain
+    /// names that are string identifiers in namespace 2.
+    pub fn from_monitored_tags(monitored_tags: &str) -> Result<TagList, String> {
+        let tags = monitored_tags.split(',')
+            .map(|tag| tag.trim())
+            .filter(|tag| !tag.is_empty())
+            .map(|tag| Ok(TagConfig::new(tag.to_string(), if NodeIdSpec::is_node_id_syntax(tag) {
+                NodeIdSpec::parse(tag).map_err(|err| format!("MONITORED_TAGS: {}", err))?
+            } else {
+                NodeIdSpec { namespace: Namespace::Index(DEFAULT_NAMESPACE), identifier: Identifier::String(UAString::from(tag)) }
+            })))
+            .collect::<Result<Vec<TagConfig>, String>>()?;
+        if tags.is_empty() {
+            return Err("MONITORED_TAGS does not contain any tag".to_string());
+        }
+        let list = TagList { tags };
+        list.check_unique("MONITORED_TAGS")?;
+        Ok(list)
+    }
+
+    fn check_unique(&self, source: &str) -> Result<(), String> {
+        let mut names = HashSet::new();
+        let mut aliases = HashSet::new();
+        for tag in &self.tags {
+            if !names.insert(tag.name.as_str()) {
+                return Err(format!("{}: tag name \"{}\" is used more than once", source, tag.name));
+            }
+            if let Some(ref alias) = tag.alias {
+                if !aliases.insert(alias.as_str()) {
+                    return Err(format!("{}: alias \"{}\" is used more than once", source, alias));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn has_namespace_uris(&self) -> bool {
+        self.tags.iter().any(|tag| tag.node.has_namespace_uri())
+    }
+
+    /// Resolves every tag to its node id, looking namespace URIs up in the server's
+    /// NamespaceArray.
+    pub fn resolve(&self, namespaces: &[String]) -> Result<TagMapping, String> {
+        let mut tags = HashMap::new();
+        for tag in &self.tags {
+            let node_id = tag.node.resolve(namespaces)
+                .map_err(|err| format!("tag \"{}\": {}", tag.name, err))?;
+            if let Some(other) = tags.get(&node_id).map(|other: &TagConfig| other.name.clone()) {
+                return Err(format!("tags \"{}\" and \"{}\" both resolve to node {}", other, tag.name, node_id));
+            }
+            tags.insert(node_id, tag.clone());
+        }
+        Ok(TagMapping { tags })
+    }
+}
+
+/// The resolved tags by node id.
+#[derive(Debug, Clone, Default)]
+pub struct TagMapping {
+    tags: HashMap<NodeId, TagConfig>,
+}
+
+impl TagMapping {
+    pub fn get(&self, node_id: &NodeId) -> Option<&TagConfig> {
+        self.tags.get(node_id)
+    }
+
+    /// The tag with the given name or alias.
+    pub fn find(&self, name: &str) -> Option<(&NodeId, &TagConfig)> {
+        self.tags.iter().find(|(_, tag)| tag.name == name || tag.alias.as_deref() == Some(name))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&NodeId, &TagConfig)> {
+        self.tags.iter()
+    }
+
+    /// Adds a discovered node with default settings, unless a configured tag already
+    /// monitors it. Returns whether it was added.
+    pub fn add_discovered(&mut self, node_id: NodeId, name: String) -> bool {
+        if self.tags.contains_key(&node_id) {
+            return false;
+        }
+        let node = NodeIdSpec { namespace: Namespace::Index(node_id.namespace), identifier: node_id.identifier.clone() };
+        self.tags.insert(node_id, TagConfig::new(name, node));
+        true
+    }
+
+    /// The monitored item requests for every tag, with the tag's sampling interval,
+    /// deadband and queue size.
+    pub fn create_requests(&self) -> Vec<MonitoredItemCreateRequest> {
+        self.tags.iter().map(|(node_id, tag)| {
+            let filter = match tag.deadband {
+                Some(deadband) => {
+                    let deadband_type = match deadband.kind {
+                        DeadbandKind::Absolute => DeadbandType::Absolute,
+                        DeadbandKind::Percent => DeadbandType::Percent,
+                    };
+                    let filter = DataChangeFilter {
+                        trigger: DataChangeTrigger::StatusValue,
+                        deadband_type: deadband_type as u32,
+                        deadband_value: deadband.value,
+                    };
+                    ExtensionObject::from_encodable(ObjectId::DataChangeFilter_Encoding_DefaultBinary, &filter)
+                }
+                None => ExtensionObject::null(),
+            };
+            MonitoredItemCreateRequest {
+                item_to_monitor: ReadValueId {
+                    node_id: node_id.clone(),
+                    attribute_id: AttributeId::Value as u32,
+                    index_range: UAString::null(),
+                    data_encoding: QualifiedName::null(),
+                },
+                monitoring_mode: MonitoringMode::Reporting,
+                requested_parameters: MonitoringParameters {
+                    client_handle: 0,
+                    sampling_interval: tag.sampling_interval,
+                    filter,
+                    queue_size: tag.queue_size,
+                    discard_oldest: true,
+                },
+            }
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn loads_tags_with_defaults() {
+        let list = TagList::parse(r#"
+            [defaults]
+            sampling_interval = 1000.0
+            measurement = "plant"
+            labels = { site = "north" }
+
+            [[tag]]
+            name = "Line1.Temp"
+            alias = "line1_temperature"
+            deadband = 5.0
+            deadband_type = "percent"
+            labels = { line = "1" }
+
+            [[tag]]
+            name = "Line1.Count"
+            namespace_uri = "urn:plant:line1"
+            i = 1001
+            sampling_interval = 250.0
+
+            [[tag]]
+            node_id = "ns=3;s=Level"
+        "#, "tags.toml").unwrap();
+        let [temp, count, level] = &list.tags[..] else { panic!("expected 3 tags") };
+        assert_eq!(temp.node, NodeIdSpec::parse("ns=2;s=Line1.Temp").unwrap());
+        assert_eq!(temp.sampling_interval, 1000.0);
+        assert_eq!(temp.deadband, Some(Deadband { kind: DeadbandKind::Percent, value: 5.0 }));
+        assert_eq!(temp.measurement.as_deref(), Some("plant"));
+        assert_eq!(temp.labels.len(), 2);
+        assert_eq!(count.node, NodeIdSpec::parse("nsu=urn:plant:line1;i=1001").unwrap());
+        assert_eq!(count.sampling_interval, 250.0);
+        assert_eq!(level.name, "ns=3;s=Level");
+        assert_eq!(level.queue_size, 1);
+    }
+
+    #[test]
+    fn rejects_duplicate_names_and_aliases() {
+        let err = TagList::parse("[[tag]]\nname = \"A\"\n[[tag]]\nname = \"A\"\ns = \"B\"\n", "tags.toml").unwrap_err();
+        assert_eq!(err, "tags.toml: tag name \"A\" is used more than once");
+        let err = TagList::parse("[[tag]]\nname = \"A\"\nalias = \"x\"\n[[tag]]\nname = \"B\"\nalias = \"x\"\n", "tags.toml").unwrap_err();
+        assert_eq!(err, "tags.toml: alias \"x\" is used more than once");
+        let err = TagList::from_monitored_tags("A, B, A").unwrap_err();
+        assert_eq!(err, "MONITORED_TAGS: tag name \"A\" is used more than once");
+    }
+
+    #[test]
+    fn rejects_a_file_without_tags() {
+        let err = TagList::parse("[defaults]\nqueue_size = 5\n", "tags.toml").unwrap_err();
+        assert_eq!(err, "tags file tags.toml does not define any [[tag]]");
+        assert!(TagList::parse("", "tags.toml").is_err());
+    }
+
+    #[test]
+    fn names_the_file_and_the_entry_in_errors() {
+        let err = |entry: &str| TagList::parse(&format!("[[tag]]\nname = \"A\"\n[[tag]]\n{}\n", entry), "tags.toml").unwrap_err();
+        assert_eq!(err("name = \"B\"\nnode_id = \"ns=2;x=1\""),
+            "tags file tags.toml: tag 2 (\"B\") node id \"ns=2;x=1\" has identifier \"x=1\", expected one of i=, s=, g= or b=");
+        assert_eq!(err("name = \"B\"\ndeadband = -1.0"), "tags file tags.toml: tag 2 (\"B\") has an invalid deadband -1");
+        assert_eq!(err("name = \"B\"\ndeadband = 120.0\ndeadband_type = \"percent\""),
+            "tags file tags.toml: tag 2 (\"B\") has an invalid deadband 120");
+        assert_eq!(err("name = \"B\"\nsampling_interval = -5.0"),
+            "tags file tags.toml: tag 2 (\"B\") has an invalid sampling_interval -5, expected milliseconds or -1");
+        assert_eq!(err("i = 7\ns = \"B\""), "tags file tags.toml: tag 2 sets more than one of s, i, g and b");
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let err = TagList::parse("[[tag]]\nname = \"A\"\ninterval = 5\n", "tags.toml").unwrap_err();
+        assert!(err.starts_with("invalid tags file tags.toml:"), "{}", err);
+        assert!(err.contains("unknown field `interval`"), "{}", err);
+    }
+}
@@ -0,0 +1,201 @@
+//! PostgreSQL / TimescaleDB output, inserting samples into a hypertable.
+//!
+//! | Variable                  | Default       | Meaning                                             |
+//! |---------------------------|---------------|-----------------------------------------------------|
+//! | `TIMESCALE_URL`           |               | connection string, e.g. `postgresql://dcs:secret@db/plant`, enables the sink |
+//! | `TIMESCALE_TABLE`         | `tag_samples` | table the points are inserted into, optionally `schema.table` |
+//! | `TIMESCALE_CREATE_TABLE`  | `true`        | create the table and make it a hypertable on `time` |
+//!
+//! Batching and retries are set with the `TIMESCALE_` variables of [`crate::timeseries`].
+//! Every point is one row, a batch is inserted with a single statement:
+//!
+//! ```sql
+//! CREATE TABLE tag_samples (
+//!     time TIMESTAMPTZ NOT NULL,
+//!     measurement TEXT NOT NULL,
+//!     field TEXT NOT NULL,
+//!     tags JSONB NOT NULL,
+//!     value DOUBLE PRECISION,
+//!     value_bool BOOLEAN,
+//!     value_text TEXT,
+//!     status BIGINT NOT NULL
+//! );
+//! ```
+//!
+//! Only one of the value columns is set per row. TLS is used when the server offers it, or
+//! required with `sslmode=require` in the connection string.
+use chrono::{DateTime, Utc};
+use dotenvy::var;
+use openssl::ssl::{SslConnector, SslMethod};
+use postgres::Client;
+use postgres_openssl::MakeTlsConnector;
+use regex::Regex;
+use serde_json::Value;
+
+use crate::client_config::{parse_bool, Secret};
+use crate::timeseries::{BatchConfig, FieldValue, Point, PointWriter};
+
+#[derive(Debug, Clone)]
+pub struct TimescaleConfig {
+    pub url: Secret,
+    pub table: String,
+    pub create_table: bool,
+    pub batch: BatchConfig,
+}
+
+impl TimescaleConfig {
+    /// The TimescaleDB settings, `None` when `TIMESCALE_URL` is not set.
+    pub fn from_env() -> Result<Option<TimescaleConfig>, String> {
+        let Ok(url) = var("TIMESCALE_URL") else {
+            return Ok(None);
+        };
+        let table = var("TIMESCALE_TABLE").unwrap_or_else(|_| "tag_samples".to_string());
+        // The table name is part of the statements, so only plain identifiers are accepted
+        let identifier = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*(\.[A-Za-z_][A-Za-z0-9_]*)?$").unwrap();
+        if !identifier.is_match(&table) {
+            return Err(format!("TIMESCALE_TABLE \"{}\" must be a table name, optionally with a schema", table));
+        }
+        Ok(Some(TimescaleConfig {
+            url: Secret::new(url),
+            table,
+            create_table: parse_bool("TIMESCALE_CREATE_TABLE", true)?,
+            batch: BatchConfig::from_env("TIMESCALE")?,
+        }))
+    }
+}
+
+pub struct TimescaleWriter {
+    config: TimescaleConfig,
+    /// Connected on the first write and again after a failed one.
+    client: Option<Client>,
+}
+
+impl TimescaleWriter {
+    pub fn new(config: TimescaleConfig) -> TimescaleWriter {
+        TimescaleWriter { config, client: None }
+    }
+
+    fn connect(&self) -> Result<Client, String> {
+        let connector = SslConnector::builder(SslMethod::tls())
+            .map_err(|err| format!("cannot set up TLS: {}", err))?
+            .build();
+        let mut client = Client::connect(self.config.url.expose(), MakeTlsConnector::new(connector))
+            .map_err(|err| format!("cannot connect to PostgreSQL: {}", err))?;
+        if self.config.create_table {
+            client.batch_execute(&format!("CREATE TABLE IF NOT EXISTS {} (
+                    time TIMESTAMPTZ NOT NULL,
+                    measurement TEXT NOT NULL,
+                    field TEXT NOT NULL,
+                    tags JSONB NOT NULL,
+                    value DOUBLE PRECISION,
+                    value_bool BOOLEAN,
+                    value_text TEXT,
+                    status BIGINT NOT NULL
+                )", self.config.table))
+                .map_err(|err| format!("cannot create table {}: {}", self.config.table, err))?;
+            client.execute("SELECT create_hypertable($1::text::regclass, 'time', if_not_exists => TRUE)", &[&self.config.table])
+                .map_err(|err| format!("cannot make {} a hypertable, is the timescaledb extension installed? {}", self.config.table, err))?;
+        }
+        println!("TimescaleDB connected, writing to table {}", self.config.table);
+        Ok(client)
+    }
+
+    fn insert(client: &mut Client, table: &str, points: &[Point]) -> Result<u64, postgres::Error> {
+        let columns = Columns::new(points);
+        client.execute(&format!("INSERT INTO {} (time, measurement, field, tags, value, value_bool, value_text, status)
+                SELECT * FROM unnest($1::timestamptz[], $2::text[], $3::text[], $4::jsonb[], $5::float8[], $6::bool[], $7::text[], $8::int8[])",
+                table),
+            &[&columns.times, &columns.measurements, &columns.fields, &columns.tags,
+                &columns.values, &columns.bools, &columns.texts, &columns.statuses])
+    }
+}
+
+impl PointWriter for TimescaleWriter {
+    fn write(&mut self, points: &[Point]) -> Result<(), String> {
+        let mut client = match self.client.take() {
+            Some(client) => client,
+            None => self.connect()?,
+        };
+        Self::insert(&mut client, &self.config.table, points)
+            .map_err(|err| format!("cannot insert into {}: {}", self.config.table, err))?;
+        // A client whose insert failed is dropped, so the next write reconnects
+        self.client = Some(client);
+        Ok(())
+    }
+}
+
+/// The rows of a batch as one array per column, for a single `unnest` insert.
+#[derive(Debug, Default, PartialEq)]
+struct Columns<'a> {
+    times: Vec<DateTime<Utc>>,
+    measurements: Vec<&'a str>,
+    fields: Vec<&'a str>,
+    tags: Vec<Value>,
+    values: Vec<Option<f64>>,
+    bools: Vec<Option<bool>>,
+    texts: Vec<Option<&'a str>>,
+    statuses: Vec<i64>,
+}
+
+impl<'a> Columns<'a> {
+    fn new(points: &'a [Point]) -> Columns<'a> {
+        let mut columns = Columns::default();
+        for point in points {
+            columns.times.push(point.time);
+            columns.measurements.push(point.measurement.as_str());
+            columns.fields.push(point.field.as_str());
+            columns.tags.push(Value::from(point.tags.iter()
+                .map(|(name, value)| (name.clone(), Value::from(value.as_str())))
+                .collect::<serde_json::Map<String, Value>>()));
+            // Only one of the value columns is set
+            let (value, value_bool, value_text) = match point.value {
+                Some(FieldValue::Float(value)) => (Some(value), None, None),
+                Some(FieldValue::Boolean(value)) => (None, Some(value), None),
+                Some(FieldValue::Text(ref value)) => (None, None, Some(value.as_str())),
+                None => (None, None, None),
+            };
+            columns.values.push(value);
+            columns.bools.push(value_bool);
+            columns.texts.push(value_text);
+            columns.statuses.push(point.status as i64);
+        }
+        columns
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+    use serde_json::json;
+
+    fn point(field: &str, value: Option<FieldValue>, status: u32) -> Point {
+        Point {
+            measurement: "plant".to_string(),
+            tags: BTreeMap::from([("tag".to_string(), "line1_temperature".to_string()), ("line".to_string(), "1".to_string())]),
+            field: field.to_string(),
+            value,
+            status,
+            time: "2024-05-01T10:00:00Z".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn sets_one_value_column_per_row() {
+        let points = [
+            point("temperature", Some(FieldValue::Float(21.5)), 0),
+            point("running", Some(FieldValue::Boolean(true)), 0),
+            point("state", Some(FieldValue::Text("idle".to_string())), 0),
+            point("temperature", None, 0x80320000),
+        ];
+        let columns = Columns::new(&points);
+        assert_eq!(columns.times, vec!["2024-05-01T10:00:00Z".parse::<DateTime<Utc>>().unwrap(); 4]);
+        assert_eq!(columns.measurements, ["plant"; 4]);
+        assert_eq!(columns.fields, ["temperature", "running", "state", "temperature"]);
+        assert_eq!(columns.tags[0], json!({"line": "1", "tag": "line1_temperature"}));
+        assert_eq!(columns.values, [Some(21.5), None, None, None]);
+        assert_eq!(columns.bools, [None, Some(true), None, None]);
+        assert_eq!(columns.texts, [None, None, Some("idle"), None]);
+        assert_eq!(columns.statuses, [0, 0, 0, 0x80320000]);
+    }
+}
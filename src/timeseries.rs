@@ -0,0 +1,453 @@
+//! Batching of samples into time-series database points, shared by the InfluxDB
+//! ([`crate::influx_sink`]) and TimescaleDB ([`crate::timescale_sink`]) sinks.
+//!
+//! Every sample becomes one point:
+//!
+//! | Part          | Value                                                                    |
+//! |---------------|--------------------------------------------------------------------------|
+//! | measurement   | `measurement` of the tag, `[defaults] measurement` or `opcua`            |
+//! | tags          | `tag` (alias or tag name), `asset_id` and `unit` when set, then `labels` |
+//! | field         | `field` of the tag or `value`, holding the value                         |
+//! | `status`      | the OPC UA status code of the value                                      |
+//! | time          | the source timestamp, else the server timestamp, else the time received  |
+//!
+//! Booleans stay booleans and every numeric type is written as a float, so a series keeps one
+//! type when the server changes the data type of a node. Int64 and UInt64 values beyond 2^53
+//! lose precision. Strings are written as text, and other types as their JSON text (see
+//! [`crate::sample`]). Empty values and NaN or infinite floats are written without a value.
+//!
+//! Each sink has its own thread and bounded queue, settings are read with the sink's prefix:
+//!
+//! | Variable                  | Default | Meaning                                            |
+//! |---------------------------|---------|----------------------------------------------------|
+//! | `<PREFIX>_BATCH_SIZE`     | `1000`  | points written in one request at most              |
+//! | `<PREFIX>_FLUSH_MS`       | `1000`  | how long a point waits for a batch to fill at most |
+//! | `<PREFIX>_RETRIES`        | `3`     | retries of a failed write before its points are dropped |
+//! | `<PREFIX>_QUEUE_CAPACITY` | `10000` | points waiting for the writer at most, newer ones are dropped |
+//!
+//! Retries wait 1s, doubling up to 30s, while new points wait in the queue.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use opcua::types::Variant;
+use serde_json::Value;
+
+use crate::client_config::parse_u64;
+use crate::sample::TagSample;
+use crate::tags::TagList;
+
+const DEFAULT_MEASUREMENT: &str = "opcua";
+const DEFAULT_FIELD: &str = "value";
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// How often the writer thread wakes up while no points arrive.
+const IDLE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    Float(f64),
+    Boolean(bool),
+    Text(String),
+}
+
+/// One sample mapped to a measurement, tags and a field.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point {
+    pub measurement: String,
+    pub tags: BTreeMap<String, String>,
+    pub field: String,
+    pub value: Option<FieldValue>,
+    pub status: u32,
+    pub time: DateTime<Utc>,
+}
+
+/// Where the samples of one tag are written.
+#[derive(Debug, Clone)]
+struct Series {
+    measurement: String,
+    field: String,
+    labels: BTreeMap<String, String>,
+}
+
+/// The series of every configured tag, by tag name.
+#[derive(Debug, Clone)]
+pub struct SeriesMapping {
+    series: HashMap<String, Series>,
+}
+
+impl SeriesMapping {
+    pub fn new(tag_list: &TagList) -> SeriesMapping {
+        let series = tag_list.tags.iter().map(|tag| (tag.name.clone(), Series {
+            measurement: tag.measurement.clone().unwrap_or_else(|| DEFAULT_MEASUREMENT.to_string()),
+            field: tag.field.clone().unwrap_or_else(|| DEFAULT_FIELD.to_string()),
+            labels: tag.labels.clone(),
+        })).collect();
+        SeriesMapping { series }
+    }
+
+    pub fn point(&self, sample: &TagSample) -> Point {
+        let series = self.series.get(&sample.tag);
+        let mut tags = BTreeMap::new();
+        tags.insert("tag".to_string(), sample.alias.clone().unwrap_or_else(|| sample.tag.clone()));
+        if let Some(ref asset_id) = sample.asset_id {
+            tags.insert("asset_id".to_string(), asset_id.clone());
+        }
+        if let Some(ref unit) = sample.unit {
+            tags.insert("unit".to_string(), unit.clone());
+        }
+        if let Some(series) = series {
+            tags.extend(series.labels.clone());
+        }
+        let time = sample.source_timestamp.as_deref()
+            .or(sample.server_timestamp.as_deref())
+            .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+            .map(|time| time.with_timezone(&Utc))
+            .unwrap_or_else(Utc::now);
+        Point {
+            measurement: series.map_or(DEFAULT_MEASUREMENT, |series| series.measurement.as_str()).to_string(),
+            tags,
+            field: series.map_or(DEFAULT_FIELD, |series| series.field.as_str()).to_string(),
+            value: field_value(&sample.variant, &sample.value),
+            status: sample.status.code,
+            time,
+        }
+    }
+}
+
+fn field_value(variant: &Variant, value: &Value) -> Option<FieldValue> {
+    let float = match *variant {
+        Variant::Empty => return None,
+        Variant::Boolean(value) => return Some(FieldValue::Boolean(value)),
+        Variant::SByte(value) => value as f64,
+        Variant::Byte(value) => value as f64,
+        Variant::Int16(value) => value as f64,
+        Variant::UInt16(value) => value as f64,
+        Variant::Int32(value) => value as f64,
+        Variant::UInt32(value) => value as f64,
+        Variant::Int64(value) => value as f64,
+        Variant::UInt64(value) => value as f64,
+        Variant::Float(value) => value as f64,
+        Variant::Double(value) => value,
+        _ => return match value {
+            Value::Null => None,
+            Value::String(text) => Some(FieldValue::Text(text.clone())),
+            other => Some(FieldValue::Text(other.to_string())),
+        },
+    };
+    float.is_finite().then_some(FieldValue::Float(float))
+}
+
+/// Writes batches of points to one database.
+pub trait PointWriter: Send + 'static {
+    fn write(&mut self, points: &[Point]) -> Result<(), String>;
+}
+
+#[derive(Debug, Clone)]
+pub struct BatchConfig {
+    pub batch_size: usize,
+    pub flush_interval: Duration,
+    pub retries: u32,
+    pub queue_capacity: usize,
+}
+
+impl BatchConfig {
+    /// The batch settings of the sink whose variables start with `prefix`.
+    pub fn from_env(prefix: &str) -> Result<BatchConfig, String> {
+        Ok(BatchConfig {
+            batch_size: parse_u64(&format!("{}_BATCH_SIZE", prefix), 1000)?.max(1) as usize,
+            flush_interval: Duration::from_millis(parse_u64(&format!("{}_FLUSH_MS", prefix), 1000)?),
+            retries: u32::try_from(parse_u64(&format!("{}_RETRIES", prefix), 3)?).unwrap_or(u32::MAX),
+            queue_capacity: parse_u64(&format!("{}_QUEUE_CAPACITY", prefix), 10_000)?.max(1) as usize,
+        })
+    }
+}
+
+/// The sending side of a time-series sink, cheap to clone into callbacks.
+#[derive(Clone)]
+pub struct TimeSeriesHandle {
+    name: &'static str,
+    sender: SyncSender<Point>,
+    mapping: Arc<SeriesMapping>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl TimeSeriesHandle {
+    /// Queues the points of the samples without waiting for the database.
+    pub fn publish(&self, samples: &[TagSample]) -> Result<(), String> {
+        let mut dropped = 0;
+        for sample in samples {
+            match self.sender.try_send(self.mapping.point(sample)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => dropped += 1,
+                Err(TrySendError::Disconnected(_)) => return Err(format!("the {} writer has stopped", self.name)),
+            }
+        }
+        if dropped > 0 {
+            self.dropped.fetch_add(dropped, Ordering::Relaxed);
+            return Err(format!("the {} queue is full, dropped {} points", self.name, dropped));
+        }
+        Ok(())
+    }
+
+    /// Queues the points of the samples, waiting while the queue is full.
+    pub fn publish_waiting(&self, samples: &[TagSample]) -> Result<(), String> {
+        for sample in samples {
+            self.sender.send(self.mapping.point(sample))
+                .map_err(|_| format!("the {} writer has stopped", self.name))?;
+        }
+        Ok(())
+    }
+}
+
+/// Starts the thread writing the points queued on the returned handle with `writer`.
+pub fn spawn_writer<W: PointWriter>(name: &'static str, config: BatchConfig, mapping: Arc<SeriesMapping>, writer: W) -> TimeSeriesHandle {
+    let (sender, receiver) = mpsc::sync_channel(config.queue_capacity);
+    let dropped = Arc::new(AtomicU64::new(0));
+    let handle = TimeSeriesHandle { name, sender, mapping, dropped: dropped.clone() };
+    let batch_writer = BatchWriter { name, config, writer, retry_delay: RETRY_DELAY };
+    thread::spawn(move || batch_writer.run(receiver, dropped));
+    handle
+}
+
+struct BatchWriter<W> {
+    name: &'static str,
+    config: BatchConfig,
+    writer: W,
+    /// The delay before the first retry of a write.
+    retry_delay: Duration,
+}
+
+impl<W: PointWriter> BatchWriter<W> {
+    /// Collects queued points into batches until every handle is dropped.
+    fn run(mut self, receiver: Receiver<Point>, dropped: Arc<AtomicU64>) {
+        let mut batch = Vec::with_capacity(self.config.batch_size);
+        let mut batch_started = Instant::now();
+        let mut last_dropped = 0;
+        loop {
+            let timeout = if batch.is_empty() {
+                IDLE_INTERVAL
+            } else {
+                self.config.flush_interval.saturating_sub(batch_started.elapsed())
+            };
+            match receiver.recv_timeout(timeout) {
+                Ok(point) => {
+                    if batch.is_empty() {
+                        batch_started = Instant::now();
+                    }
+                    batch.push(point);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.flush(&mut batch);
+                    return;
+                }
+            }
+            if batch.len() >= self.config.batch_size || (!batch.is_empty() && batch_started.elapsed() >= self.config.flush_interval) {
+                self.flush(&mut batch);
+                let dropped = dropped.load(Ordering::Relaxed);
+                if dropped > last_dropped {
+                    println!("{}: {} points dropped while the queue was full", self.name, dropped - last_dropped);
+                    last_dropped = dropped;
+                }
+            }
+        }
+    }
+
+    /// Writes the batch, retrying with a growing delay, and drops it when every retry failed.
+    fn flush(&mut self, batch: &mut Vec<Point>) {
+        if batch.is_empty() {
+            return;
+        }
+        let mut delay = self.retry_delay;
+        let mut retries = 0;
+        loop {
+            match self.writer.write(batch) {
+                Ok(()) => break,
+                Err(err) if retries < self.config.retries => {
+                    println!("{}: writing {} points failed, retrying in {:?}: {}", self.name, batch.len(), delay, err);
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                    retries += 1;
+                }
+                Err(err) => {
+                    println!("{}: dropping {} points after {} retries: {}", self.name, batch.len(), retries, err);
+                    break;
+                }
+            }
+        }
+        batch.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use opcua::sync::Mutex;
+    use opcua::types::StatusCode;
+    use serde_json::json;
+
+    use crate::node_id::NodeIdSpec;
+    use crate::tags::TagConfig;
+
+    fn sample(tag: &str, variant: Variant, source_timestamp: Option<&str>, server_timestamp: Option<&str>) -> TagSample {
+        let mut sample: TagSample = serde_json::from_value(json!({
+            "schema_version": 1,
+            "endpoint": "opc.tcp://plc1:4840",
+            "node_id": format!("ns=2;s={}", tag),
+            "tag": tag,
+            "value_type": "Double",
+            "value": null,
+            "status": { "code": 0, "name": "Good" },
+            "source_timestamp": source_timestamp,
+            "server_timestamp": server_timestamp,
+        })).unwrap();
+        sample.value = crate::sample::variant_to_json(&variant);
+        sample.variant = variant;
+        sample
+    }
+
+    fn mapping() -> SeriesMapping {
+        let mut tag = TagConfig::new("Line1.Temp".to_string(), NodeIdSpec::parse("ns=2;s=Line1.Temp").unwrap());
+        tag.measurement = Some("plant".to_string());
+        tag.field = Some("temperature".to_string());
+        tag.labels = BTreeMap::from([("line".to_string(), "1".to_string())]);
+        SeriesMapping::new(&TagList { tags: vec![tag] })
+    }
+
+    #[test]
+    fn maps_samples_by_their_tag_config() {
+        let mut tagged = sample("Line1.Temp", Variant::Double(21.5), Some("2024-05-01T10:00:00Z"), Some("2024-05-01T10:00:01Z"));
+        tagged.alias = Some("line1_temperature".to_string());
+        tagged.asset_id = Some("L1-TT-101".to_string());
+        tagged.unit = Some("°C".to_string());
+        let point = mapping().point(&tagged);
+        assert_eq!(point.measurement, "plant");
+        assert_eq!(point.field, "temperature");
+        assert_eq!(point.value, Some(FieldValue::Float(21.5)));
+        assert_eq!(point.time, "2024-05-01T10:00:00Z".parse::<DateTime<Utc>>().unwrap());
+        let tags: Vec<(&str, &str)> = point.tags.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect();
+        assert_eq!(tags, [("asset_id", "L1-TT-101"), ("line", "1"), ("tag", "line1_temperature"), ("unit", "°C")]);
+
+        let point = mapping().point(&sample("Line2.Temp", Variant::Int32(3), None, Some("2024-05-01T10:00:01Z")));
+        assert_eq!(point.measurement, "opcua");
+        assert_eq!(point.field, "value");
+        assert_eq!(point.tags, BTreeMap::from([("tag".to_string(), "Line2.Temp".to_string())]));
+        assert_eq!(point.time, "2024-05-01T10:00:01Z".parse::<DateTime<Utc>>().unwrap());
+
+        let before = Utc::now();
+        let point = mapping().point(&sample("Line2.Temp", Variant::Int32(3), None, None));
+        assert!(point.time >= before && point.time <= Utc::now());
+    }
+
+    #[test]
+    fn writes_numbers_as_floats() {
+        let float = |variant: Variant| field_value(&variant, &crate::sample::variant_to_json(&variant));
+        assert_eq!(float(Variant::Byte(7)), Some(FieldValue::Float(7.0)));
+        assert_eq!(float(Variant::Int64(-3)), Some(FieldValue::Float(-3.0)));
+        assert_eq!(float(Variant::UInt64(5)), Some(FieldValue::Float(5.0)));
+        assert_eq!(float(Variant::Float(1.5)), Some(FieldValue::Float(1.5)));
+        assert_eq!(float(Variant::Double(f64::NAN)), None);
+        assert_eq!(float(Variant::Double(f64::INFINITY)), None);
+        assert_eq!(float(Variant::Empty), None);
+        assert_eq!(float(Variant::Boolean(true)), Some(FieldValue::Boolean(true)));
+        assert_eq!(float(Variant::from("running")), Some(FieldValue::Text("running".to_string())));
+        let status = Variant::StatusCode(StatusCode::BadTimeout);
+        assert_eq!(field_value(&status, &json!({"code": 2148139008u32})), Some(FieldValue::Text(r#"{"code":2148139008}"#.to_string())));
+    }
+
+    /// Records every write and fails the first `failures` of them.
+    #[derive(Clone, Default)]
+    struct FakeWriter {
+        writes: Arc<Mutex<Vec<(Instant, usize)>>>,
+        failures: u32,
+    }
+
+    impl PointWriter for FakeWriter {
+        fn write(&mut self, points: &[Point]) -> Result<(), String> {
+            self.writes.lock().push((Instant::now(), points.len()));
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err("database unavailable".to_string());
+            }
+            Ok(())
+        }
+    }
+
+    fn batch_writer(writer: FakeWriter, batch_size: usize, flush_interval: Duration, retries: u32) -> BatchWriter<FakeWriter> {
+        let config = BatchConfig { batch_size, flush_interval, retries, queue_capacity: 100 };
+        BatchWriter { name: "test", config, writer, retry_delay: Duration::from_millis(20) }
+    }
+
+    fn point() -> Point {
+        mapping().point(&sample("Line1.Temp", Variant::Double(1.0), None, None))
+    }
+
+    /// Waits until `writes` holds `count` writes, at most 5s.
+    fn wait_for(writes: &Arc<Mutex<Vec<(Instant, usize)>>>, count: usize) -> Vec<usize> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while writes.lock().len() < count && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        writes.lock().iter().map(|(_, points)| *points).collect()
+    }
+
+    #[test]
+    fn flushes_full_batches_at_once() {
+        let writer = FakeWriter::default();
+        let writes = writer.writes.clone();
+        let (sender, receiver) = mpsc::sync_channel(10);
+        let thread = thread::spawn(move || batch_writer(writer, 2, Duration::from_secs(60), 0).run(receiver, Arc::default()));
+        for _ in 0..5 {
+            sender.send(point()).unwrap();
+        }
+        assert_eq!(wait_for(&writes, 2), [2, 2]);
+        // The last point is written when the handles are gone
+        drop(sender);
+        thread.join().unwrap();
+        assert_eq!(wait_for(&writes, 3), [2, 2, 1]);
+    }
+
+    #[test]
+    fn flushes_after_the_interval() {
+        let writer = FakeWriter::default();
+        let writes = writer.writes.clone();
+        let (sender, receiver) = mpsc::sync_channel(10);
+        let thread = thread::spawn(move || batch_writer(writer, 100, Duration::from_millis(50), 0).run(receiver, Arc::default()));
+        let sent = Instant::now();
+        sender.send(point()).unwrap();
+        sender.send(point()).unwrap();
+        assert_eq!(wait_for(&writes, 1), [2]);
+        assert!(writes.lock()[0].0 - sent >= Duration::from_millis(50));
+        drop(sender);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn retries_with_a_growing_delay() {
+        let writer = FakeWriter { failures: 2, ..FakeWriter::default() };
+        let writes = writer.writes.clone();
+        let mut batch_writer = batch_writer(writer, 10, Duration::from_secs(1), 3);
+        let mut batch = vec![point(), point()];
+        batch_writer.flush(&mut batch);
+        assert!(batch.is_empty());
+        let writes = writes.lock();
+        assert_eq!(writes.iter().map(|(_, points)| *points).collect::<Vec<_>>(), [2, 2, 2]);
+        assert!(writes[1].0 - writes[0].0 >= Duration::from_millis(20));
+        assert!(writes[2].0 - writes[1].0 >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn drops_the_batch_after_its_retries() {
+        let writer = FakeWriter { failures: u32::MAX, ..FakeWriter::default() };
+        let writes = writer.writes.clone();
+        let mut batch_writer = batch_writer(writer, 10, Duration::from_secs(1), 2);
+        let mut batch = vec![point()];
+        batch_writer.flush(&mut batch);
+        assert!(batch.is_empty());
+        assert_eq!(writes.lock().len(), 3);
+    }
+}